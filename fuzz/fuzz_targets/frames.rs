@@ -0,0 +1,22 @@
+#![no_main]
+
+//! Feeds arbitrary bytes to everything that decodes what a peer sends: frames,
+//! envelopes, welcomes, commands and responses. Any panic is a bug.
+
+use libfuzzer_sys::fuzz_target;
+use oxidoc_cli::commands::commands::CliCommand;
+use oxidoc_cli::network::protocol::{read_envelope, Envelope, Welcome};
+use oxidoc_core::response::Response;
+
+fuzz_target!(|data: &[u8]| {
+    let mut stream = data;
+    while let Ok(envelope) = read_envelope(&mut stream) {
+        let _ = Welcome::decode(&envelope.payload);
+        let _ = CliCommand::from_bytes(&envelope.payload);
+        let _ = Response::from_bytes(&envelope.payload);
+    }
+    if let Ok(envelope) = Envelope::decode(data) {
+        let _ = CliCommand::from_bytes(&envelope.payload);
+        let _ = Response::from_bytes(&envelope.payload);
+    }
+});
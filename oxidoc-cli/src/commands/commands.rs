@@ -1,17 +1,47 @@
 use clap::error::Error;
 use clap::error::ErrorKind::InvalidValue;
-use clap::{Args as ClapArgs, Parser};
+use clap::{Args as ClapArgs, Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
 
 /// CLI Commands
 #[derive(Parser, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub enum CliCommand {
     Status,
+    /// Stores a new document; fails if the key is taken
     Insert(InsertCommandArgs),
+    /// Overwrites an existing document; fails if the key is free
+    Replace(InsertCommandArgs),
+    /// Stores the document whether or not the key is taken
+    Upsert(InsertCommandArgs),
     Get(GetCommandArgs),
     Delete(DeleteCommandArgs),
     CreateCollection(CreateCollectionCommandArgs),
     DeleteCollection(CreateCollectionCommandArgs),
+    Scan(ScanCommandArgs),
+    Find(FindCommandArgs),
+    CreateIndex(CreateIndexCommandArgs),
+    DropIndex(DropIndexCommandArgs),
+    Update(UpdateCommandArgs),
+    /// Buffers the connection's following writes until commit or abort
+    Begin,
+    /// Applies every write since begin, all or none
+    Commit,
+    /// Discards every write since begin
+    Abort,
+    /// Makes the connection's following reads see the database as of now
+    OpenSnapshot,
+    /// Returns the connection's reads to the latest data
+    ReleaseSnapshot,
+    /// Runs many writes in one round trip
+    Batch(BatchCommandArgs),
+    /// Upserts a chunk of exported records
+    Import(ImportCommandArgs),
+    /// Returns a page of a collection as records to export
+    Export(ExportCommandArgs),
+    /// Copies the database, as of now, into a directory on the server
+    Backup(BackupCommandArgs),
+    /// Persists the database and truncates its write-ahead log
+    Checkpoint,
 }
 
 impl CliCommand {
@@ -21,6 +51,35 @@ impl CliCommand {
         args.insert(0, "prog".to_string());
         CliCommand::try_parse_from(args)
     }
+
+    /// The name the command is typed with, e.g. `create-index`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CliCommand::Status => "status",
+            CliCommand::Insert(_) => "insert",
+            CliCommand::Replace(_) => "replace",
+            CliCommand::Upsert(_) => "upsert",
+            CliCommand::Get(_) => "get",
+            CliCommand::Delete(_) => "delete",
+            CliCommand::CreateCollection(_) => "create-collection",
+            CliCommand::DeleteCollection(_) => "delete-collection",
+            CliCommand::Scan(_) => "scan",
+            CliCommand::Find(_) => "find",
+            CliCommand::CreateIndex(_) => "create-index",
+            CliCommand::DropIndex(_) => "drop-index",
+            CliCommand::Update(_) => "update",
+            CliCommand::Begin => "begin",
+            CliCommand::Commit => "commit",
+            CliCommand::Abort => "abort",
+            CliCommand::OpenSnapshot => "open-snapshot",
+            CliCommand::ReleaseSnapshot => "release-snapshot",
+            CliCommand::Batch(_) => "batch",
+            CliCommand::Import(_) => "import",
+            CliCommand::Export(_) => "export",
+            CliCommand::Backup(_) => "backup",
+            CliCommand::Checkpoint => "checkpoint",
+        }
+    }
 }
 
 #[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
@@ -45,6 +104,14 @@ pub struct InsertCommandArgs {
     
     #[arg(short, long)]
     pub value: String,
+
+    /// Only write if the document is still at this version (0: only if it does not exist)
+    #[arg(long)]
+    pub if_version: Option<u64>,
+
+    /// Seconds after which the document expires
+    #[arg(long)]
+    pub ttl: Option<u64>,
 }
 
 #[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
@@ -65,14 +132,221 @@ pub struct DeleteCommandArgs {
     pub key: String,
 }
 
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct ScanCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    /// First key to return (inclusive)
+    #[arg(short, long, conflicts_with = "after")]
+    pub start: Option<String>,
+
+    /// Resume after this key (exclusive), e.g. the last key of the previous page
+    #[arg(short, long)]
+    pub after: Option<String>,
+
+    /// Stop before this key (exclusive); pages backwards when combined with --reverse
+    #[arg(short, long)]
+    pub end: Option<String>,
+
+    /// Only keys starting with this prefix
+    #[arg(short, long)]
+    pub prefix: Option<String>,
+
+    #[arg(short, long, default_value_t = 100)]
+    pub limit: usize,
+
+    /// Return keys in descending order
+    #[arg(short, long)]
+    pub reverse: bool,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct FindCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    /// MongoDB-style filter document, e.g. '{"age": {"$gt": 30}}'
+    #[arg(short, long, default_value = "{}")]
+    pub filter: String,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct CreateIndexCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    /// Dotted field paths, comma separated; more than one makes a compound index
+    #[arg(short, long, required = true, value_delimiter = ',')]
+    pub fields: Vec<String>,
+
+    /// Index name; defaults to the fields joined with '_'
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Reject writes that would give two documents the same indexed value
+    #[arg(short, long, conflicts_with = "ttl")]
+    pub unique: bool,
+
+    /// Make a TTL index: documents expire this many seconds after the Unix time in the (single) field
+    #[arg(long)]
+    pub ttl: Option<u64>,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct DropIndexCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    #[arg(short, long)]
+    pub name: String,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct UpdateCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    #[arg(short, long)]
+    pub key: String,
+
+    /// JSON Patch array, Merge Patch object or '{"$set": {...}}'-style operators
+    #[arg(short, long)]
+    pub patch: String,
+
+    /// How to read --patch; guessed from its shape when omitted
+    #[arg(short, long, value_enum)]
+    pub format: Option<PatchFormat>,
+
+    /// Only update if the document is still at this version
+    #[arg(long)]
+    pub if_version: Option<u64>,
+}
+
+#[derive(ValueEnum, Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum PatchFormat {
+    /// RFC 6902
+    JsonPatch,
+    /// RFC 7386
+    MergePatch,
+    /// $set, $unset, $inc, $push, $pull
+    Operators,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct BatchCommandArgs {
+    /// JSON array of operations, e.g. '[{"op": "insert", "collection": "c", "key": "k", "value": {}}, {"op": "delete", "collection": "c", "key": "old"}]'
+    #[arg(short, long)]
+    pub ops: String,
+
+    /// Apply every operation or none; otherwise each one succeeds or fails on its own
+    #[arg(short, long)]
+    pub atomic: bool,
+}
+
+/// One entry of a batch's `--ops`, tagged by `"op"`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum BatchOperation {
+    Insert(BatchWrite),
+    Replace(BatchWrite),
+    Upsert(BatchWrite),
+    Delete(DeleteCommandArgs),
+}
+
+/// A document written by a batch; `value` is the document itself rather than its JSON text.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct BatchWrite {
+    pub collection: String,
+    pub key: String,
+    pub value: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub if_version: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+impl BatchOperation {
+    /// The command that runs this operation on its own.
+    pub fn into_command(self) -> CliCommand {
+        match self {
+            BatchOperation::Insert(write) => CliCommand::Insert(write.into()),
+            BatchOperation::Replace(write) => CliCommand::Replace(write.into()),
+            BatchOperation::Upsert(write) => CliCommand::Upsert(write.into()),
+            BatchOperation::Delete(args) => CliCommand::Delete(args),
+        }
+    }
+}
+
+impl From<BatchWrite> for InsertCommandArgs {
+    fn from(write: BatchWrite) -> InsertCommandArgs {
+        InsertCommandArgs {
+            collection: write.collection,
+            key: write.key,
+            value: write.value.to_string(),
+            if_version: write.if_version,
+            ttl: write.ttl,
+        }
+    }
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct ImportCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    /// The records, e.g. '{"_key": "k", "doc": {...}}' per line
+    #[arg(short, long)]
+    pub data: String,
+
+    #[arg(short, long, value_enum, default_value_t = DataFormat::Ndjson)]
+    pub format: DataFormat,
+
+    /// Take each key from this field of the record instead of "_key"
+    #[arg(short, long)]
+    pub key_field: Option<String>,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct ExportCommandArgs {
+    #[arg(short, long)]
+    pub collection: String,
+
+    /// Resume after this key (exclusive), i.e. the last key of the previous page
+    #[arg(short, long)]
+    pub after: Option<String>,
+
+    #[arg(short, long, default_value_t = 1000)]
+    pub limit: usize,
+
+    /// Put each key in this field of the document instead of wrapping it as {"_key", "doc"}
+    #[arg(short, long)]
+    pub key_field: Option<String>,
+}
+
+#[derive(ClapArgs, Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
+pub struct BackupCommandArgs {
+    /// Directory to write to, relative to the server's backup directory; must not exist yet or be empty
+    #[arg(short, long)]
+    pub target: String,
+}
+
+#[derive(ValueEnum, Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum DataFormat {
+    /// One JSON record per line
+    Ndjson,
+    /// A single JSON array of records
+    Json,
+}
 
 impl CliCommand {
     pub fn as_bytes(&self) -> Vec<u8> {
         serde_json::to_vec(self).expect("Serialization failed")
     }
     
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        serde_json::from_slice(bytes).expect("Deserialization failed")
+    /// Bytes that are not a serialized command are an `InvalidData` error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, std::io::Error> {
+        Ok(serde_json::from_slice(bytes)?)
     }
 }
 
@@ -91,9 +365,34 @@ mod tests {
             collection: "mycoll".to_string(),
             key: "mykey".to_string(),
             value: "myval".to_string(),
+            if_version: None,
+            ttl: None,
         }));
     }
 
+    #[test]
+    fn parses_insert_command_with_expected_version() {
+        let command = CliCommand::parse_command(r#"insert -c c -k k -v '{"a": 1}' --if-version 7"#).unwrap();
+        assert!(matches!(command, CliCommand::Insert(args) if args.if_version == Some(7)));
+        let command = CliCommand::parse_command(r#"insert -c c -k k -v '{}' --ttl 3600"#).unwrap();
+        assert!(matches!(command, CliCommand::Insert(args) if args.ttl == Some(3600)));
+    }
+
+    #[test]
+    fn parses_replace_and_upsert_like_insert() {
+        let args = InsertCommandArgs {
+            collection: "c".to_string(),
+            key: "k".to_string(),
+            value: "{}".to_string(),
+            if_version: None,
+            ttl: None,
+        };
+        let command = CliCommand::parse_command("replace -c c -k k -v {}").unwrap();
+        assert_eq!(command, CliCommand::Replace(args.clone()));
+        let command = CliCommand::parse_command("upsert -c c -k k -v {}").unwrap();
+        assert_eq!(command, CliCommand::Upsert(args));
+    }
+
     #[test]
     fn parses_get_command_with_args() {
         let command = CliCommand::parse_from([
@@ -116,22 +415,155 @@ mod tests {
         }));
     }
 
+    #[test]
+    fn parses_scan_command_with_defaults() {
+        let command = CliCommand::parse_command("scan -c mycoll --prefix user: --reverse").unwrap();
+        assert_eq!(command, CliCommand::Scan(ScanCommandArgs {
+            collection: "mycoll".to_string(),
+            start: None,
+            after: None,
+            end: None,
+            prefix: Some("user:".to_string()),
+            limit: 100,
+            reverse: true,
+        }));
+    }
+
+    #[test]
+    fn scan_start_conflicts_with_after() {
+        assert!(CliCommand::parse_command("scan -c mycoll --start a --after b").is_err());
+    }
+
+    #[test]
+    fn parses_find_command_with_quoted_filter() {
+        let command = CliCommand::parse_command(r#"find -c people -f '{"address.city": "Rome"}'"#).unwrap();
+        assert_eq!(command, CliCommand::Find(FindCommandArgs {
+            collection: "people".to_string(),
+            filter: r#"{"address.city": "Rome"}"#.to_string(),
+        }));
+    }
+
+    #[test]
+    fn parses_compound_create_index_command() {
+        let command = CliCommand::parse_command("create-index -c people -f address.city,age").unwrap();
+        assert_eq!(command, CliCommand::CreateIndex(CreateIndexCommandArgs {
+            collection: "people".to_string(),
+            fields: vec!["address.city".to_string(), "age".to_string()],
+            name: None,
+            unique: false,
+            ttl: None,
+        }));
+        let command = CliCommand::parse_command("create-index -c users -f email --unique").unwrap();
+        assert!(matches!(command, CliCommand::CreateIndex(args) if args.unique));
+        assert!(CliCommand::parse_command("create-index -c people").is_err());
+        let command = CliCommand::parse_command("create-index -c sessions -f created --ttl 60").unwrap();
+        assert!(matches!(command, CliCommand::CreateIndex(args) if args.ttl == Some(60)));
+        assert!(CliCommand::parse_command("create-index -c sessions -f created --ttl 60 --unique").is_err());
+    }
+
+    #[test]
+    fn parses_update_command_with_format() {
+        let command = CliCommand::parse_command(r#"update -c c -k k -p '{"a": null}' -f merge-patch"#).unwrap();
+        assert_eq!(command, CliCommand::Update(UpdateCommandArgs {
+            collection: "c".to_string(),
+            key: "k".to_string(),
+            patch: r#"{"a": null}"#.to_string(),
+            format: Some(PatchFormat::MergePatch),
+            if_version: None,
+        }));
+    }
+
     #[test]
     fn parses_status_command() {
         let command = CliCommand::parse_from(["prog", "status"]);
         assert_eq!(command, CliCommand::Status);
     }
 
+    #[test]
+    fn parses_transaction_commands() {
+        assert_eq!(CliCommand::parse_command("begin").unwrap(), CliCommand::Begin);
+        assert_eq!(CliCommand::parse_command("commit").unwrap(), CliCommand::Commit);
+        assert_eq!(CliCommand::parse_command("abort").unwrap(), CliCommand::Abort);
+    }
+
+    #[test]
+    fn parses_snapshot_commands() {
+        assert_eq!(CliCommand::parse_command("open-snapshot").unwrap(), CliCommand::OpenSnapshot);
+        assert_eq!(CliCommand::parse_command("release-snapshot").unwrap(), CliCommand::ReleaseSnapshot);
+    }
+
+    #[test]
+    fn parses_batch_operations() {
+        let command = CliCommand::parse_command(
+            r#"batch --atomic --ops '[{"op": "upsert", "collection": "c", "key": "a", "value": {"n": 1}, "ttl": 60}, {"op": "delete", "collection": "c", "key": "b"}]'"#,
+        )
+        .unwrap();
+        let CliCommand::Batch(args) = command else { panic!("not a batch: {:?}", command) };
+        assert!(args.atomic);
+        let operations: Vec<BatchOperation> = serde_json::from_str(&args.ops).unwrap();
+        assert_eq!(operations[0].clone().into_command(), CliCommand::Upsert(InsertCommandArgs {
+            collection: "c".to_string(),
+            key: "a".to_string(),
+            value: r#"{"n":1}"#.to_string(),
+            if_version: None,
+            ttl: Some(60),
+        }));
+        assert_eq!(operations[1], BatchOperation::Delete(DeleteCommandArgs {
+            collection: "c".to_string(),
+            key: "b".to_string(),
+        }));
+        assert!(serde_json::from_str::<Vec<BatchOperation>>(r#"[{"op": "get", "collection": "c", "key": "a"}]"#).is_err());
+    }
+
+    #[test]
+    fn parses_import_and_export_commands() {
+        let command = CliCommand::parse_command(r#"import -c c -d '[{"id": 1}]' -f json -k id"#).unwrap();
+        assert_eq!(command, CliCommand::Import(ImportCommandArgs {
+            collection: "c".to_string(),
+            data: r#"[{"id": 1}]"#.to_string(),
+            format: DataFormat::Json,
+            key_field: Some("id".to_string()),
+        }));
+        let command = CliCommand::parse_command("export -c c --after k").unwrap();
+        assert_eq!(command, CliCommand::Export(ExportCommandArgs {
+            collection: "c".to_string(),
+            after: Some("k".to_string()),
+            limit: 1000,
+            key_field: None,
+        }));
+    }
+
+    #[test]
+    fn parses_backup_and_checkpoint_commands() {
+        let command = CliCommand::parse_command("backup --target nightly/monday").unwrap();
+        assert_eq!(command, CliCommand::Backup(BackupCommandArgs { target: "nightly/monday".to_string() }));
+        assert!(CliCommand::parse_command("backup").is_err());
+        assert_eq!(CliCommand::parse_command("checkpoint").unwrap(), CliCommand::Checkpoint);
+    }
+
+    #[test]
+    fn names_are_the_words_commands_are_typed_with() {
+        for line in ["status", "create-index -c c -f a", "open-snapshot", "drop-index -c c -n i", "checkpoint"] {
+            let command = CliCommand::parse_command(line).unwrap();
+            assert_eq!(command.name(), line.split(' ').next().unwrap());
+        }
+    }
+
     #[test]
     fn serializes_and_deserializes_cli_command() {
         let original = CliCommand::Insert(InsertCommandArgs {
             collection: "test_collection".to_string(),
             key: "test_key".to_string(),
             value: "test_value".to_string(),
+            if_version: None,
+            ttl: None,
         });
         let bytes = original.as_bytes();
-        let deserialized = CliCommand::from_bytes(&bytes);
+        let deserialized = CliCommand::from_bytes(&bytes).unwrap();
         assert_eq!(original, deserialized);
+        for garbage in [&b"hello\n"[..], b"", b"{\"Insert\":{}}", b"\xff\xfe", &bytes[..bytes.len() - 1]] {
+            assert!(CliCommand::from_bytes(garbage).is_err());
+        }
     }
 
 }
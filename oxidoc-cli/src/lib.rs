@@ -1,6 +1,9 @@
 pub mod commands {
+    #[allow(clippy::module_inception)]
     pub mod commands;
 }
 pub mod network {
+    #[allow(clippy::module_inception)]
     pub mod network;
+    pub mod protocol;
 }
\ No newline at end of file
@@ -1,15 +1,49 @@
-use std::io::{Error, Read, Write};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// Largest message `read_message` accepts.
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A message whose length prefix is over the reader's maximum. It is returned as
+/// an `ErrorKind::FileTooLarge` error, with nothing past the prefix read, so the
+/// stream cannot be read further.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub size: u64,
+    pub max: u64,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Message of {} bytes exceeds the maximum of {} bytes", self.size, self.max)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
 
 pub fn write_message<W: Write>(mut stream: W, message: &[u8]) -> Result<(), Error> {
     let message_length = message.len() as u64;
     stream.write_all(&message_length.to_be_bytes())?;
     stream.write_all(message)
 }
-pub fn read_message<R: Read>(mut stream: R) -> Result<Vec<u8>, Error> {
+pub fn read_message<R: Read>(stream: R) -> Result<Vec<u8>, Error> {
+    read_message_with_limit(stream, DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+/// Reads a message of at most `max` bytes; a longer one is a [`MessageTooLarge`] error.
+pub fn read_message_with_limit<R: Read>(mut stream: R, max: u64) -> Result<Vec<u8>, Error> {
     let mut len_buffer = [0u8; 8]; 
     stream.read_exact(&mut len_buffer)?;
-    let mut buffer = vec![0u8; u64::from_be_bytes(len_buffer) as usize];
-    stream.read_exact(&mut buffer)?;
+    let len = u64::from_be_bytes(len_buffer);
+    if len > max {
+        return Err(Error::new(ErrorKind::FileTooLarge, MessageTooLarge { size: len, max }));
+    }
+    // Grown as bytes arrive rather than allocated up front: the length is the peer's word.
+    let mut buffer = Vec::new();
+    stream.take(len).read_to_end(&mut buffer)?;
+    if (buffer.len() as u64) < len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, format!("Message ended after {} of {} bytes", buffer.len(), len)));
+    }
     Ok(buffer)
 }
 
@@ -48,13 +82,33 @@ mod tests {
     fn read_message_returns_error_on_short_payload() {
         // Lunghezza dichiarata 10, ma solo 5 byte disponibili
         let mut buffer = Vec::new();
-        buffer.extend_from_slice(&10u32.to_be_bytes());
+        buffer.extend_from_slice(&10u64.to_be_bytes());
         buffer.extend_from_slice(b"12345");
         let mut cursor = Cursor::new(&buffer);
         let err = read_message(&mut cursor).unwrap_err();
         assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
     }
 
+    #[test]
+    fn read_message_does_not_trust_the_declared_length() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&u64::MAX.to_be_bytes());
+        buffer.extend_from_slice(b"12345");
+        let err = read_message_with_limit(Cursor::new(&buffer), u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_message_rejects_messages_over_the_limit() {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, b"12345").unwrap();
+        assert_eq!(read_message_with_limit(Cursor::new(&buffer), 5).unwrap(), b"12345");
+        let err = read_message_with_limit(Cursor::new(&buffer), 4).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<MessageTooLarge>(), Some(&MessageTooLarge { size: 5, max: 4 }));
+        assert_eq!(read_message(Cursor::new(&u64::MAX.to_be_bytes())).unwrap_err().kind(), ErrorKind::FileTooLarge);
+    }
+
     #[test]
     fn write_message_writes_length_and_payload() {
         let message = b"abc";
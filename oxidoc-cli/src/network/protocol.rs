@@ -0,0 +1,226 @@
+use crate::network::network::{read_message, read_message_with_limit, write_message};
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// The newest protocol version this build speaks.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The oldest protocol version this build still speaks.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Message type (u8), protocol version (u16), flags (u16) and request id (u64).
+const HEADER_LEN: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    /// Server to client on connect: the versions the server speaks and a greeting.
+    Welcome,
+    /// Client to server: the version chosen from the welcome, in the envelope header.
+    Hello,
+    /// Client to server: a command.
+    Request,
+    /// Server to client: the response to the message with the same request id.
+    Response,
+    /// Client to server: the client is closing the connection.
+    Goodbye,
+}
+
+impl MessageType {
+    fn code(self) -> u8 {
+        match self {
+            MessageType::Welcome => 1,
+            MessageType::Hello => 2,
+            MessageType::Request => 3,
+            MessageType::Response => 4,
+            MessageType::Goodbye => 5,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<MessageType> {
+        match code {
+            1 => Some(MessageType::Welcome),
+            2 => Some(MessageType::Hello),
+            3 => Some(MessageType::Request),
+            4 => Some(MessageType::Response),
+            5 => Some(MessageType::Goodbye),
+            _ => None,
+        }
+    }
+}
+
+/// Every message exchanged over a connection, carried in one length-prefixed frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub kind: MessageType,
+    /// The version the payload is encoded with; after the handshake, the negotiated one.
+    pub version: u16,
+    /// No flags are defined yet; receivers ignore the ones they do not know.
+    pub flags: u16,
+    /// Chosen by the client for each request, from 1, and echoed in its response. A
+    /// response with id 0 is about the connection, e.g. a message the server could not read.
+    pub request_id: u64,
+    pub payload: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(kind: MessageType, version: u16, request_id: u64, payload: Vec<u8>) -> Envelope {
+        Envelope { kind, version, flags: 0, request_id, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push(self.kind.code());
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&self.flags.to_be_bytes());
+        bytes.extend_from_slice(&self.request_id.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Envelope, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Message of {} bytes has no complete header", bytes.len())));
+        }
+        let kind = MessageType::from_code(bytes[0])
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Unknown message type {}", bytes[0])))?;
+        Ok(Envelope {
+            kind,
+            version: u16::from_be_bytes([bytes[1], bytes[2]]),
+            flags: u16::from_be_bytes([bytes[3], bytes[4]]),
+            request_id: u64::from_be_bytes(bytes[5..HEADER_LEN].try_into().unwrap()),
+            payload: bytes[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+pub fn write_envelope<W: Write>(stream: W, envelope: &Envelope) -> Result<(), Error> {
+    write_message(stream, &envelope.encode())
+}
+
+pub fn read_envelope<R: Read>(stream: R) -> Result<Envelope, Error> {
+    Envelope::decode(&read_message(stream)?)
+}
+
+/// Reads an envelope of at most `max` bytes, see [`read_message_with_limit`].
+pub fn read_envelope_with_limit<R: Read>(stream: R, max: u64) -> Result<Envelope, Error> {
+    Envelope::decode(&read_message_with_limit(stream, max)?)
+}
+
+/// Whether this build speaks `version`.
+pub fn supports(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+/// Payload of a `Welcome`: its layout never changes, so clients of any version can
+/// read it and pick a version both sides speak.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Welcome {
+    pub min_version: u16,
+    pub max_version: u16,
+    pub greeting: String,
+}
+
+impl Welcome {
+    /// The welcome of this build, offering every version it speaks.
+    pub fn new(greeting: &str) -> Welcome {
+        Welcome { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, greeting: greeting.to_string() }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.greeting.len());
+        bytes.extend_from_slice(&self.min_version.to_be_bytes());
+        bytes.extend_from_slice(&self.max_version.to_be_bytes());
+        bytes.extend_from_slice(self.greeting.as_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Welcome, Error> {
+        if bytes.len() < 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "Truncated welcome"));
+        }
+        Ok(Welcome {
+            min_version: u16::from_be_bytes([bytes[0], bytes[1]]),
+            max_version: u16::from_be_bytes([bytes[2], bytes[3]]),
+            greeting: String::from_utf8(bytes[4..].to_vec()).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        })
+    }
+
+    /// The newest version spoken by both this build and the server that sent the welcome.
+    pub fn negotiate(&self) -> Result<u16, Error> {
+        let version = self.max_version.min(PROTOCOL_VERSION);
+        if version < self.min_version.max(MIN_PROTOCOL_VERSION) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "Server speaks protocol versions {}-{}, this client {}-{}",
+                    self.min_version, self.max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                ),
+            ));
+        }
+        Ok(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn envelopes_roundtrip_through_frames() {
+        let envelope = Envelope::new(MessageType::Request, PROTOCOL_VERSION, 42, b"{\"Begin\":null}".to_vec());
+        let mut buffer = Vec::new();
+        write_envelope(&mut buffer, &envelope).unwrap();
+        assert_eq!(read_envelope(Cursor::new(&buffer)).unwrap(), envelope);
+
+        let empty = Envelope::new(MessageType::Goodbye, PROTOCOL_VERSION, 0, Vec::new());
+        assert_eq!(Envelope::decode(&empty.encode()).unwrap(), empty);
+        assert_eq!(Envelope::decode(&[4, 0, 1]).unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut unknown = empty.encode();
+        unknown[0] = 99;
+        assert!(Envelope::decode(&unknown).unwrap_err().to_string().contains("Unknown message type"));
+    }
+
+    #[test]
+    fn negotiation_picks_the_newest_common_version() {
+        let welcome = Welcome::new("hi");
+        assert_eq!(Welcome::decode(&welcome.encode()).unwrap(), welcome);
+        assert_eq!(welcome.negotiate().unwrap(), PROTOCOL_VERSION);
+
+        let newer = Welcome { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION + 5, greeting: String::new() };
+        assert_eq!(newer.negotiate().unwrap(), PROTOCOL_VERSION);
+        let too_new = Welcome { min_version: PROTOCOL_VERSION + 1, max_version: PROTOCOL_VERSION + 5, greeting: String::new() };
+        assert_eq!(too_new.negotiate().unwrap_err().kind(), ErrorKind::Unsupported);
+        assert!(Welcome::decode(&[0, 1]).is_err());
+        assert!(supports(PROTOCOL_VERSION) && !supports(PROTOCOL_VERSION + 1) && !supports(0));
+    }
+
+    #[test]
+    fn decoders_reject_mutated_frames_without_panicking() {
+        use crate::commands::commands::CliCommand;
+        let mut valid = Vec::new();
+        let welcome = Envelope::new(MessageType::Welcome, PROTOCOL_VERSION, 0, Welcome::new("hi").encode());
+        write_envelope(&mut valid, &welcome).unwrap();
+        write_envelope(&mut valid, &Envelope::new(MessageType::Request, PROTOCOL_VERSION, 1, CliCommand::Begin.as_bytes())).unwrap();
+
+        // xorshift, so every run mutates the same bytes.
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let mut frame = valid.clone();
+            for _ in 0..=next() % 4 {
+                let at = next() as usize % frame.len();
+                frame[at] = next() as u8;
+            }
+            frame.truncate(next() as usize % (frame.len() + 1));
+            let mut reader = Cursor::new(&frame);
+            while let Ok(envelope) = read_envelope(&mut reader) {
+                let _ = Welcome::decode(&envelope.payload);
+                let _ = CliCommand::from_bytes(&envelope.payload);
+            }
+        }
+    }
+}
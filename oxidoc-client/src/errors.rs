@@ -1,9 +1,38 @@
+use oxidoc_core::error::{ErrorCategory, Failure};
 use std::fmt;
+use std::io;
 
+/// Everything a client call can fail with. Failures reported by the server come
+/// as one variant per category, carrying the code, message and details.
 #[derive(Debug)]
 pub enum ClientError {
     ArgParse(String),
     Connection(String),
+    /// Reading or writing the connection or a local file failed.
+    Io(io::Error),
+    NotFound(Failure),
+    Conflict(Failure),
+    InvalidArgument(Failure),
+    Parse(Failure),
+    ConstraintViolation(Failure),
+    Overloaded(Failure),
+    Internal(Failure),
+}
+
+impl ClientError {
+    /// The failure reported by the server, if the error is one.
+    pub fn failure(&self) -> Option<&Failure> {
+        match self {
+            ClientError::NotFound(failure)
+            | ClientError::Conflict(failure)
+            | ClientError::InvalidArgument(failure)
+            | ClientError::Parse(failure)
+            | ClientError::ConstraintViolation(failure)
+            | ClientError::Overloaded(failure)
+            | ClientError::Internal(failure) => Some(failure),
+            ClientError::ArgParse(_) | ClientError::Connection(_) | ClientError::Io(_) => None,
+        }
+    }
 }
 
 impl fmt::Display for ClientError {
@@ -11,6 +40,45 @@ impl fmt::Display for ClientError {
         match self {
             ClientError::ArgParse(e) => write!(f, "Argument parsing error: {}", e),
             ClientError::Connection(e) => write!(f, "Connection error: {}", e),
+            ClientError::Io(e) => write!(f, "I/O error: {}", e),
+            _ => write!(f, "Error {}", self.failure().expect("a server failure")),
         }
     }
 }
+
+impl std::error::Error for ClientError {}
+
+impl From<Failure> for ClientError {
+    fn from(failure: Failure) -> ClientError {
+        match failure.category {
+            ErrorCategory::NotFound => ClientError::NotFound(failure),
+            ErrorCategory::Conflict => ClientError::Conflict(failure),
+            ErrorCategory::InvalidArgument => ClientError::InvalidArgument(failure),
+            ErrorCategory::Parse => ClientError::Parse(failure),
+            ErrorCategory::ConstraintViolation => ClientError::ConstraintViolation(failure),
+            ErrorCategory::Overloaded => ClientError::Overloaded(failure),
+            ErrorCategory::Internal => ClientError::Internal(failure),
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> ClientError {
+        ClientError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxidoc_core::error::ErrorCode;
+
+    #[test]
+    fn server_failures_map_to_their_category() {
+        let error = ClientError::from(Failure::new(ErrorCode::DuplicateKey, "taken"));
+        assert!(matches!(&error, ClientError::Conflict(failure) if failure.code == ErrorCode::DuplicateKey));
+        assert_eq!(error.to_string(), "Error DUPLICATE_KEY (13) [conflict]: taken");
+        assert!(matches!(ClientError::from(Failure::new(ErrorCode::ServerBusy, "busy")), ClientError::Overloaded(_)));
+        assert!(ClientError::from(io::Error::other("closed")).failure().is_none());
+    }
+}
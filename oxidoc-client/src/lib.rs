@@ -4,3 +4,4 @@ pub mod network {
     pub mod handler;
 }
 pub mod errors;
+pub mod transfer;
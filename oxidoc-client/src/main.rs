@@ -1,36 +1,110 @@
-use clap::Parser;
-use oxidoc_cli::network::network::read_message;
-use oxidoc_client::network::client::connect_to_server;
+use clap::{Parser, Subcommand};
+use oxidoc_cli::commands::commands::DataFormat;
+use oxidoc_client::errors::ClientError;
+use oxidoc_client::network::client::{connect_to_server, Connection, DEFAULT_MAX_RESPONSE_SIZE};
 use oxidoc_client::network::handler::{handle};
+use oxidoc_client::transfer::{export, import};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
 
 const PORT: u16 = 7878;
 const ADDRESS: &str = "127.0.0.1";
 
 #[derive(Parser, Debug)]
 struct ApplicationArgs {
-    #[arg[short, long]]
+    #[arg(short, long, default_value_t = format!("{ADDRESS}:{PORT}"))]
     server: String,
+
+    /// Largest response, in bytes, accepted from the server
+    #[arg(long, default_value_t = DEFAULT_MAX_RESPONSE_SIZE)]
+    max_response_size: u64,
+
+    /// Runs one transfer instead of the interactive prompt
+    #[command(subcommand)]
+    command: Option<ClientCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ClientCommand {
+    /// Upserts every record of a file into a collection
+    Import {
+        #[arg(short, long)]
+        collection: String,
+
+        #[arg(short, long)]
+        file: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = DataFormat::Ndjson)]
+        format: DataFormat,
+
+        /// Take each key from this field of the record instead of "_key"
+        #[arg(short, long)]
+        key_field: Option<String>,
+
+        /// Records sent per request
+        #[arg(long, default_value_t = 1000)]
+        chunk: usize,
+    },
+    /// Writes every document of a collection to a file
+    Export {
+        #[arg(short, long)]
+        collection: String,
+
+        #[arg(short, long)]
+        file: PathBuf,
+
+        #[arg(long, value_enum, default_value_t = DataFormat::Ndjson)]
+        format: DataFormat,
+
+        /// Put each key in this field of the document instead of wrapping it as {"_key", "doc"}
+        #[arg(short, long)]
+        key_field: Option<String>,
+
+        /// Documents fetched per request
+        #[arg(long, default_value_t = 1000)]
+        page: usize,
+    },
 }
 
 fn main() {
-    println!("Welcome to Oxidoc CLI. Type your command or 'exit' to quit.");
-    let args: Vec<String> = std::env::args().collect();
-    let cli_args = ApplicationArgs::try_parse_from(args);
-    let server = match cli_args {
-        Err(_) => {
-            format!("{ADDRESS}:{PORT}")
+    let args = ApplicationArgs::parse();
+    if args.command.is_none() {
+        println!("Welcome to Oxidoc CLI. Type your command or 'exit' to quit.");
+    }
+    match connect_to_server(args.server) {
+        Err(ClientError::Overloaded(failure)) => println!("Server refused connection: {}", failure.message),
+        Err(e) => println!("Failed to connect to server: {}", e),
+        Ok(mut connection) => {
+            connection.set_max_response_size(args.max_response_size);
+            match args.command {
+                None => {
+                    println!("Server: {} (protocol version {})", connection.greeting(), connection.version());
+                    handle(connection)
+                }
+                Some(command) => run(connection, command),
+            }
         }
-        Ok(a) => a.server
+    }
+}
+
+fn run(mut connection: Connection, command: ClientCommand) {
+    let result = match command {
+        ClientCommand::Import { collection, file, format, key_field, chunk } => File::open(&file)
+            .map_err(ClientError::from)
+            .and_then(|f| import(&mut connection, BufReader::new(f), format, &collection, key_field.as_deref(), chunk))
+            .map(|n| format!("Imported {} document(s) from {} into {:?}.", n, file.display(), collection)),
+        ClientCommand::Export { collection, file, format, key_field, page } => File::create(&file)
+            .map_err(ClientError::from)
+            .and_then(|f| export(&mut connection, BufWriter::new(f), format, &collection, key_field.as_deref(), page))
+            .map(|n| format!("Exported {} document(s) from {:?} to {}.", n, collection, file.display())),
     };
-    let stream = connect_to_server(server);
-    match stream {
+    let _ = connection.close();
+    match result {
+        Ok(summary) => println!("{}", summary),
         Err(e) => {
-            println!("Failed to connect to server: {}", e);
-        }
-        Ok(s) => {
-            let welcome_message = read_message(&s).expect("Failed to read handshake message");
-            println!("Server: {}", String::from_utf8_lossy(&welcome_message));
-            handle(s)
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     }
 }
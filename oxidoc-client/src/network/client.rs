@@ -1,6 +1,284 @@
 use crate::errors::ClientError;
+use oxidoc_cli::commands::commands::CliCommand;
+use oxidoc_cli::network::protocol::{read_envelope, read_envelope_with_limit, write_envelope, Envelope, MessageType, Welcome};
+use oxidoc_core::response::Response;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind};
 use std::net::TcpStream;
 
-pub fn connect_to_server(server: String) -> Result<TcpStream, ClientError> {
-    TcpStream::connect(server).map_err(|e| ClientError::Connection(e.to_string()))
+pub fn connect_to_server(server: String) -> Result<Connection, ClientError> {
+    let stream = TcpStream::connect(server).map_err(|e| ClientError::Connection(e.to_string()))?;
+    Connection::open(stream)
+}
+
+/// Largest response a connection accepts unless told otherwise. Unlike requests,
+/// which the server caps, responses are as large as the documents asked for.
+pub const DEFAULT_MAX_RESPONSE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Requests `Connection::pipeline` keeps in flight.
+pub const PIPELINE_DEPTH: usize = 64;
+
+/// Bytes of requests `Connection::pipeline` keeps in flight. The server stops
+/// reading while it waits for the client to read a response, so the requests
+/// sent meanwhile must fit in the socket buffers, or both sides block writing to
+/// each other; a larger request is only sent once every response is in.
+pub const PIPELINE_BYTES: usize = 32 * 1024;
+
+/// A connection past the handshake, sending every request with the protocol
+/// version agreed on and its own request id. Requests can be pipelined: sent
+/// without waiting, their responses matched by id in whatever order they come.
+pub struct Connection {
+    stream: TcpStream,
+    version: u16,
+    greeting: String,
+    next_request_id: u64,
+    /// Requests sent and not yet received, and responses read while waiting for another.
+    pending: HashSet<u64>,
+    arrived: HashMap<u64, Response>,
+    max_response_size: u64,
+}
+
+impl Connection {
+    /// Reads the server's welcome and answers with the newest version both sides
+    /// speak. A server turning the client away, e.g. when busy, answers with a failure.
+    pub fn open(stream: TcpStream) -> Result<Connection, ClientError> {
+        // A message is written as its length, then its bytes: without this the
+        // bytes wait for the length to be acknowledged whenever nothing else is in flight.
+        stream.set_nodelay(true)?;
+        let first = read_envelope(&stream)?;
+        if first.kind != MessageType::Welcome {
+            return Err(refused(first));
+        }
+        let welcome = Welcome::decode(&first.payload)?;
+        let mut connection = Connection {
+            stream,
+            version: welcome.negotiate()?,
+            greeting: welcome.greeting,
+            next_request_id: 0,
+            pending: HashSet::new(),
+            arrived: HashMap::new(),
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+        };
+        let hello = connection.send_message(MessageType::Hello, Vec::new())?;
+        match connection.receive(hello)? {
+            Response::Ack => Ok(connection),
+            Response::Failure(failure) => Err(failure.into()),
+            response => Err(unexpected(format!("Unexpected response to hello: {}", response)).into()),
+        }
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn greeting(&self) -> &str {
+        &self.greeting
+    }
+
+    /// Caps the responses read from now on at `max` bytes; a longer one is a
+    /// `MessageTooLarge` error, after which the connection cannot be used.
+    pub fn set_max_response_size(&mut self, max: u64) {
+        self.max_response_size = max;
+    }
+
+    /// Sends `command` and waits for its response, failures included.
+    pub fn request(&mut self, command: &CliCommand) -> Result<Response, Error> {
+        let request_id = self.send(command)?;
+        self.receive(request_id)
+    }
+
+    /// Sends `command` without waiting for its response; returns the request id
+    /// to [`Connection::receive`] it by.
+    pub fn send(&mut self, command: &CliCommand) -> Result<u64, Error> {
+        self.send_message(MessageType::Request, command.as_bytes())
+    }
+
+    /// Waits for the response to request `request_id`, keeping the responses to
+    /// other pending requests that arrive first. A response with id 0, about the
+    /// whole connection, answers whichever request is waited for.
+    pub fn receive(&mut self, request_id: u64) -> Result<Response, Error> {
+        if !self.pending.remove(&request_id) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Request {} is not pending", request_id)));
+        }
+        loop {
+            if let Some(response) = self.arrived.remove(&request_id) {
+                return Ok(response);
+            }
+            let reply = read_envelope_with_limit(&self.stream, self.max_response_size)?;
+            if reply.kind != MessageType::Response {
+                return Err(unexpected(format!("Expected a response, got {:?}", reply.kind)));
+            }
+            let response = Response::from_bytes(&reply.payload)?;
+            match reply.request_id {
+                0 => return Ok(response),
+                id if id == request_id => return Ok(response),
+                id if self.pending.contains(&id) && !self.arrived.contains_key(&id) => {
+                    self.arrived.insert(id, response);
+                }
+                id => return Err(unexpected(format!("Response to request {}, which is not pending", id))),
+            }
+        }
+    }
+
+    /// Sends every command, up to [`PIPELINE_DEPTH`] requests and [`PIPELINE_BYTES`]
+    /// ahead of the responses, and returns their responses in the order of the
+    /// commands. As with separate requests, a failed command does not stop the ones after it.
+    pub fn pipeline(&mut self, commands: &[CliCommand]) -> Result<Vec<Response>, Error> {
+        let (mut in_flight, mut bytes) = (VecDeque::new(), 0);
+        let mut responses = Vec::with_capacity(commands.len());
+        for command in commands {
+            let payload = command.as_bytes();
+            let size = payload.len();
+            while in_flight.len() == PIPELINE_DEPTH || (!in_flight.is_empty() && bytes + size > PIPELINE_BYTES) {
+                let (oldest, sent) = in_flight.pop_front().expect("a request in flight");
+                bytes -= sent;
+                responses.push(self.receive(oldest)?);
+            }
+            in_flight.push_back((self.send_message(MessageType::Request, payload)?, size));
+            bytes += size;
+        }
+        for (request_id, _) in in_flight {
+            responses.push(self.receive(request_id)?);
+        }
+        Ok(responses)
+    }
+
+    /// Sends `command` and waits for its response; a failure becomes the error of its category.
+    pub fn call(&mut self, command: &CliCommand) -> Result<Response, ClientError> {
+        match self.request(command)? {
+            Response::Failure(failure) => Err(failure.into()),
+            response => Ok(response),
+        }
+    }
+
+    /// Tells the server the client is leaving.
+    pub fn close(self) -> Result<(), Error> {
+        write_envelope(&self.stream, &Envelope::new(MessageType::Goodbye, self.version, 0, Vec::new()))
+    }
+
+    fn send_message(&mut self, kind: MessageType, payload: Vec<u8>) -> Result<u64, Error> {
+        self.next_request_id += 1;
+        let request_id = self.next_request_id;
+        write_envelope(&self.stream, &Envelope::new(kind, self.version, request_id, payload))?;
+        self.pending.insert(request_id);
+        Ok(request_id)
+    }
+}
+
+/// The error for a first message that is not a welcome: the server's failure when
+/// it sent one.
+fn refused(envelope: Envelope) -> ClientError {
+    match (envelope.kind, Response::from_bytes(&envelope.payload)) {
+        (MessageType::Response, Ok(Response::Failure(failure))) => failure.into(),
+        _ => unexpected(format!("Expected a welcome, got {:?}", envelope.kind)).into(),
+    }
+}
+
+fn unexpected(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxidoc_cli::commands::commands::GetCommandArgs;
+    use oxidoc_cli::network::protocol::PROTOCOL_VERSION;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Acknowledges the hello, then answers each group of `group` requests in
+    /// reverse order, every response naming the request id it answers.
+    fn serve_reversed(group: usize) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let welcome = Envelope::new(MessageType::Welcome, PROTOCOL_VERSION, 0, Welcome::new("test").encode());
+            write_envelope(&stream, &welcome).unwrap();
+            let hello = read_envelope(&stream).unwrap();
+            let ack = Envelope::new(MessageType::Response, PROTOCOL_VERSION, hello.request_id, Response::Ack.as_bytes());
+            write_envelope(&stream, &ack).unwrap();
+            loop {
+                let mut ids = Vec::new();
+                while ids.len() < group {
+                    match read_envelope(&stream) {
+                        Ok(request) => ids.push(request.request_id),
+                        Err(_) => return,
+                    }
+                }
+                for id in ids.into_iter().rev() {
+                    let answer = Response::Success(format!("answer to {}", id)).as_bytes();
+                    write_envelope(&stream, &Envelope::new(MessageType::Response, PROTOCOL_VERSION, id, answer)).unwrap();
+                }
+            }
+        });
+        Connection::open(TcpStream::connect(address).unwrap()).unwrap()
+    }
+
+    /// Acknowledges the hello, then answers each request as soon as it has read it
+    /// with a response of `size` bytes.
+    fn serve_large(size: usize) -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let welcome = Envelope::new(MessageType::Welcome, PROTOCOL_VERSION, 0, Welcome::new("test").encode());
+            write_envelope(&stream, &welcome).unwrap();
+            let hello = read_envelope(&stream).unwrap();
+            let ack = Envelope::new(MessageType::Response, PROTOCOL_VERSION, hello.request_id, Response::Ack.as_bytes());
+            write_envelope(&stream, &ack).unwrap();
+            let answer = Response::Success("x".repeat(size)).as_bytes();
+            while let Ok(request) = read_envelope(&stream) {
+                let response = Envelope::new(MessageType::Response, PROTOCOL_VERSION, request.request_id, answer.clone());
+                if write_envelope(&stream, &response).is_err() {
+                    return;
+                }
+            }
+        });
+        Connection::open(TcpStream::connect(address).unwrap()).unwrap()
+    }
+
+    fn get(key: &str) -> CliCommand {
+        CliCommand::Get(GetCommandArgs { collection: "c".to_string(), key: key.to_string() })
+    }
+
+    #[test]
+    fn responses_are_matched_to_requests_whatever_their_order() {
+        let mut connection = serve_reversed(3);
+        let ids: Vec<u64> = ["a", "b", "c"].iter().map(|key| connection.send(&get(key)).unwrap()).collect();
+        assert_eq!(connection.receive(ids[0]).unwrap(), Response::Success(format!("answer to {}", ids[0])));
+        assert_eq!(connection.receive(ids[2]).unwrap(), Response::Success(format!("answer to {}", ids[2])));
+        assert_eq!(connection.receive(ids[1]).unwrap(), Response::Success(format!("answer to {}", ids[1])));
+        assert_eq!(connection.receive(ids[1]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn responses_over_the_limit_are_refused() {
+        let mut connection = serve_reversed(1);
+        connection.request(&get("a")).unwrap();
+        connection.set_max_response_size(8);
+        assert_eq!(connection.request(&get("b")).unwrap_err().kind(), ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    fn pipelines_return_responses_in_command_order() {
+        let mut connection = serve_reversed(PIPELINE_DEPTH);
+        let commands: Vec<CliCommand> = (0..PIPELINE_DEPTH * 2).map(|i| get(&i.to_string())).collect();
+        let responses = connection.pipeline(&commands).unwrap();
+        // The hello took request id 1.
+        let expected: Vec<Response> =
+            (0..commands.len()).map(|i| Response::Success(format!("answer to {}", i + 2))).collect();
+        assert_eq!(responses, expected);
+    }
+
+    #[test]
+    fn pipelines_of_large_messages_do_not_block_both_sides() {
+        let mut connection = serve_large(256 * 1024);
+        // At 64 in flight, requests and responses this large can fill the socket
+        // buffers of both sides.
+        let commands: Vec<CliCommand> = (0..100).map(|i| get(&format!("{}{}", i, "k".repeat(16 * 1024)))).collect();
+        let responses = connection.pipeline(&commands).unwrap();
+        assert_eq!(responses.len(), commands.len());
+        assert!(responses.iter().all(|response| matches!(response, Response::Success(text) if text.len() == 256 * 1024)));
+    }
 }
@@ -1,8 +1,7 @@
+use crate::network::client::Connection;
 use oxidoc_cli::commands::commands::CliCommand;
-use oxidoc_cli::network::network::{read_message, write_message};
 use std::io;
-use std::io::Write;
-use std::net::TcpStream;
+use std::io::{BufRead, IsTerminal, Write};
 use oxidoc_core::response::Response;
 
 pub struct CommandHandler;
@@ -12,7 +11,10 @@ impl CommandHandler {
     }
 }
 
-pub fn handle(mut stream: TcpStream) {
+pub fn handle(mut connection: Connection) {
+    if !io::stdin().is_terminal() {
+        return run_script(connection, io::stdin().lock());
+    }
     loop {
         print!("oxidoc> ");
         io::stdout().flush().unwrap();
@@ -24,27 +26,86 @@ pub fn handle(mut stream: TcpStream) {
         let input = input.trim();
         if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") {
             println!("Exiting CLI. Goodbye!");
-            stream.flush().unwrap();
+            let _ = connection.close();
             break;
         }
         match CliCommand::parse_command(input) {
-            Ok(command) => {
-                let bytes = command.as_bytes();
-                write_message(&stream, &bytes).expect("Error writing to stream.");
-                
-                let response  = Response::from_bytes(&*read_message(&stream).unwrap());
-                handle_response(response);
-            }
+            Ok(command) => match connection.call(&command) {
+                Ok(response) => handle_response(&command, response),
+                // The server turned down this request; the connection is still fine.
+                Err(e) if e.failure().is_some() => println!("{}", e),
+                Err(e) => {
+                    println!("Lost the connection to the server: {}", e);
+                    break;
+                }
+            },
             Err(e) => println!("Invalid command: {}", e),
         }
     }
 }
 
-fn handle_response(p0: Response) {
+/// Runs the commands of piped input, one per line, pipelined on the connection
+/// rather than waiting for each response, and prints the responses in order.
+fn run_script(mut connection: Connection, input: impl BufRead) {
+    let mut commands = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                println!("Failed to read input: {}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.eq_ignore_ascii_case("exit") || line.eq_ignore_ascii_case("quit") {
+            break;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        match CliCommand::parse_command(line) {
+            Ok(command) => commands.push(command),
+            Err(e) => println!("Invalid command on line {}: {}", number + 1, e),
+        }
+    }
+    match connection.pipeline(&commands) {
+        Ok(responses) => {
+            for (command, response) in commands.iter().zip(responses) {
+                handle_response(command, response);
+            }
+        }
+        Err(e) => println!("Error talking to the server: {}", e),
+    }
+    let _ = connection.close();
+}
+
+fn handle_response(command: &CliCommand, p0: Response) {
     match p0 {
         Response::Success(msg) => println!("Success: {}", msg),
-        Response::Doc(doc) => println!("Document: {}", doc.unwrap().to_string()),
+        Response::Doc(Some(stored)) => println!("Document (version {}): {}", stored.version, stored.doc),
+        Response::Doc(None) => println!("Document not found."),
+        Response::Docs(docs) => {
+            for (key, doc) in &docs {
+                println!("{}: {}", key, doc);
+            }
+            match docs.last() {
+                Some((last, _)) if matches!(command, CliCommand::Scan(_)) => println!(
+                    "{} document(s). Next page: --after {:?} (--end {:?} when scanning with --reverse)",
+                    docs.len(),
+                    last,
+                    last
+                ),
+                Some(_) => println!("{} document(s).", docs.len()),
+                None => println!("No documents."),
+            }
+        }
         Response::Ack => println!("Acknowledged."),
-        Response::Failure(err) => println!("Error: {}", err),
+        Response::Failure(failure) => println!("Error {}", failure),
+        Response::Batch(results) => {
+            for (index, result) in results.into_iter().enumerate() {
+                print!("[{}] ", index);
+                handle_response(command, result);
+            }
+        }
     }
 }
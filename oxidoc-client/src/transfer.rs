@@ -0,0 +1,169 @@
+use crate::errors::ClientError;
+use crate::network::client::Connection;
+use oxidoc_cli::commands::commands::{CliCommand, DataFormat, ExportCommandArgs, ImportCommandArgs};
+use oxidoc_core::response::Response;
+use oxidoc_core::transfer::{RecordReader, RecordWriter};
+use std::collections::VecDeque;
+use std::io::{BufRead, Error, Write};
+
+/// Chunks `import` sends ahead of the server's responses.
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Streams the records of `input` into `collection`, `chunk` records per
+/// request, pipelined. Each chunk is applied atomically, the import as a whole
+/// is not: when one fails, the chunks sent after it may still be applied.
+/// Returns the number of documents imported.
+pub fn import<R: BufRead>(
+    connection: &mut Connection,
+    input: R,
+    format: DataFormat,
+    collection: &str,
+    key_field: Option<&str>,
+    chunk: usize,
+) -> Result<usize, ClientError> {
+    let mut records = RecordReader::new(input, format).peekable();
+    let (mut imported, mut in_flight) = (0, VecDeque::new());
+    while records.peek().is_some() {
+        if in_flight.len() == CHUNKS_IN_FLIGHT {
+            let oldest = in_flight.pop_front().expect("a chunk in flight");
+            expect_success(connection, oldest)?;
+        }
+        let mut data = String::new();
+        for record in records.by_ref().take(chunk.max(1)) {
+            data.push_str(&serde_json::to_string(&record?).map_err(Error::from)?);
+            data.push('\n');
+            imported += 1;
+        }
+        in_flight.push_back(connection.send(&CliCommand::Import(ImportCommandArgs {
+            collection: collection.to_string(),
+            data,
+            format: DataFormat::Ndjson,
+            key_field: key_field.map(str::to_string),
+        }))?);
+    }
+    for request_id in in_flight {
+        expect_success(connection, request_id)?;
+    }
+    Ok(imported)
+}
+
+/// Waits for the response to an import chunk; a failure becomes the error.
+fn expect_success(connection: &mut Connection, request_id: u64) -> Result<(), ClientError> {
+    match connection.receive(request_id)? {
+        Response::Failure(failure) => Err(failure.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Streams `collection` into `output`, `page` documents per request, all read
+/// from one snapshot, which is released however the export ends. Returns the
+/// number of documents exported.
+pub fn export<W: Write>(
+    connection: &mut Connection,
+    output: W,
+    format: DataFormat,
+    collection: &str,
+    key_field: Option<&str>,
+    page: usize,
+) -> Result<usize, ClientError> {
+    connection.call(&CliCommand::OpenSnapshot)?;
+    let mut writer = RecordWriter::new(output, format);
+    let paged = write_pages(connection, &mut writer, collection, key_field, page);
+    let released = connection.call(&CliCommand::ReleaseSnapshot);
+    paged?;
+    released?;
+    let exported = writer.written();
+    writer.finish()?;
+    Ok(exported)
+}
+
+/// Writes every page of `collection` to `writer`, reading from the connection's snapshot.
+fn write_pages<W: Write>(
+    connection: &mut Connection,
+    writer: &mut RecordWriter<W>,
+    collection: &str,
+    key_field: Option<&str>,
+    page: usize,
+) -> Result<(), ClientError> {
+    let mut after = None;
+    loop {
+        let response = connection.call(&CliCommand::Export(ExportCommandArgs {
+            collection: collection.to_string(),
+            after: after.take(),
+            limit: page.max(1),
+            key_field: key_field.map(str::to_string),
+        }))?;
+        let Response::Docs(records) = response else {
+            return Err(Error::other(format!("Unexpected response to export: {}", response)).into());
+        };
+        for (_, record) in &records {
+            writer.write(record)?;
+        }
+        match records.into_iter().last() {
+            Some((last, _)) => after = Some(last),
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxidoc_cli::network::protocol::{read_envelope, write_envelope, Envelope, MessageType, Welcome, PROTOCOL_VERSION};
+    use oxidoc_core::database::Database;
+    use oxidoc_core::executor::{execute, Session};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Answers every request of one connection like the server does.
+    fn serve_one() -> Connection {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let welcome = Envelope::new(MessageType::Welcome, PROTOCOL_VERSION, 0, Welcome::new("test").encode());
+            write_envelope(&stream, &welcome).unwrap();
+            let (mut db, mut session) = (Database::initialize(), Session::new());
+            while let Ok(envelope) = read_envelope(&stream) {
+                let response = match envelope.kind {
+                    MessageType::Hello => Response::Ack,
+                    _ => CliCommand::from_bytes(&envelope.payload)
+                        .and_then(|command| execute(command, &mut db, &mut session))
+                        .unwrap_or_else(|e| Response::from_error(&e)),
+                };
+                let reply = Envelope::new(MessageType::Response, envelope.version, envelope.request_id, response.as_bytes());
+                write_envelope(&stream, &reply).unwrap();
+            }
+        });
+        Connection::open(TcpStream::connect(address).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn export_writes_back_what_import_read() {
+        let mut connection = serve_one();
+        let input: String = (0..25).map(|i| format!("{{\"id\": \"k{:02}\", \"n\": {}}}\n", i, i)).collect();
+        assert_eq!(import(&mut connection, input.as_bytes(), DataFormat::Ndjson, "c", Some("id"), 10).unwrap(), 25);
+
+        let mut exported = Vec::new();
+        assert_eq!(export(&mut connection, &mut exported, DataFormat::Json, "c", Some("id"), 7).unwrap(), 25);
+        let records: Vec<serde_json::Value> = serde_json::from_slice(&exported).unwrap();
+        let expected: Vec<serde_json::Value> = input.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(records, expected);
+
+        let missing_key = import(&mut connection, "{\"n\": 1}".as_bytes(), DataFormat::Ndjson, "c", Some("id"), 10);
+        assert!(matches!(missing_key, Err(ClientError::Parse(failure)) if failure.message.contains("Record 0")));
+        assert!(import(&mut connection, "[1".as_bytes(), DataFormat::Json, "c", None, 10).is_err());
+    }
+
+    #[test]
+    fn failed_exports_release_their_snapshot() {
+        let mut connection = serve_one();
+        let input = "{\"_key\": \"a\", \"doc\": {\"id\": \"b\"}}";
+        assert_eq!(import(&mut connection, input.as_bytes(), DataFormat::Ndjson, "c", None, 10).unwrap(), 1);
+
+        assert!(export(&mut connection, Vec::new(), DataFormat::Ndjson, "c", Some("id"), 10).is_err());
+        // Nothing left to release: the export did.
+        assert!(connection.call(&CliCommand::ReleaseSnapshot).is_err());
+        assert_eq!(export(&mut connection, Vec::new(), DataFormat::Ndjson, "c", None, 10).unwrap(), 1);
+    }
+}
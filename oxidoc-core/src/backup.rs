@@ -0,0 +1,323 @@
+use crate::checkpoint::{self, Checkpoint, CHECKPOINT_FILE};
+use crate::checksum::Crc32;
+use crate::database::WAL_FILE;
+use crate::wal::Wal;
+use std::fmt;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Lists the files of a backup; written last, so a backup without one is incomplete.
+pub const MANIFEST: &str = "MANIFEST";
+const HEADER: &str = "oxidoc-backup 1";
+
+/// What a backup holds: the sequence number of the last write it contains and,
+/// for every file, its size and CRC-32.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub sequence: u64,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupFile {
+    /// Path relative to the backup (and data) directory.
+    pub name: String,
+    pub size: u64,
+    pub crc: u32,
+}
+
+impl Manifest {
+    /// Total size of the backed up files.
+    pub fn bytes(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+
+    fn parse(text: &str) -> Result<Manifest, Error> {
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(corrupt("unknown manifest format"));
+        }
+        let sequence = match lines.next().and_then(|line| line.strip_prefix("sequence ")) {
+            Some(sequence) => sequence.parse().map_err(|_| corrupt("invalid sequence"))?,
+            None => return Err(corrupt("missing sequence")),
+        };
+        let files = lines
+            .map(|line| {
+                let mut fields = line.split(' ');
+                let (Some(name), Some(size), Some(crc), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+                    return Err(corrupt(&format!("invalid file entry {:?}", line)));
+                };
+                if !Path::new(name).components().all(|c| matches!(c, Component::Normal(_))) {
+                    return Err(corrupt(&format!("file {:?} is outside the backup", name)));
+                }
+                Ok(BackupFile {
+                    name: name.to_string(),
+                    size: size.parse().map_err(|_| corrupt(&format!("invalid size of {:?}", name)))?,
+                    crc: u32::from_str_radix(crc, 16).map_err(|_| corrupt(&format!("invalid checksum of {:?}", name)))?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Manifest { sequence, files })
+    }
+}
+
+impl Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "sequence {}", self.sequence)?;
+        for file in &self.files {
+            writeln!(f, "{} {} {:08x}", file.name, file.size, file.crc)?;
+        }
+        Ok(())
+    }
+}
+
+/// The directory under `root` named by `name`, a relative path of plain components:
+/// nothing a client names can lead a backup outside of `root`.
+pub fn resolve(root: &Path, name: &str) -> Result<PathBuf, Error> {
+    let path = Path::new(name);
+    if name.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Backup target {:?} must be a relative path without `.` or `..`", name),
+        ));
+    }
+    Ok(root.join(path))
+}
+
+/// Writes a backup as of `sequence` into `target`, which must not exist yet or be
+/// empty: the checkpoint `fill` writes into it, holding every document, an empty log
+/// after it and their manifest, written last.
+pub(crate) fn write(
+    target: &Path,
+    sequence: u64,
+    fill: impl FnOnce(&Path) -> Result<Checkpoint, Error>,
+) -> Result<Manifest, Error> {
+    ensure_empty(target)?;
+    std::fs::create_dir_all(target)?;
+    fill(target)?;
+    File::create(target.join(WAL_FILE))?.sync_all()?;
+    let mut manifest = Manifest { sequence, files: Vec::new() };
+    for name in [CHECKPOINT_FILE, WAL_FILE] {
+        let path = target.join(name);
+        let (size, crc) = (std::fs::metadata(&path)?.len(), checksum(&path)?);
+        manifest.files.push(BackupFile { name: name.to_string(), size, crc });
+    }
+    let mut file = File::create(target.join(MANIFEST))?;
+    file.write_all(manifest.to_string().as_bytes())?;
+    file.sync_all()?;
+    Ok(manifest)
+}
+
+/// Checks every file of the backup in `source` against its manifest, and that the
+/// checkpoint and write-ahead log are intact and end at the manifest's sequence number.
+pub fn verify(source: &Path) -> Result<Manifest, Error> {
+    let manifest = match std::fs::read_to_string(source.join(MANIFEST)) {
+        Ok(text) => Manifest::parse(&text)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(corrupt("no manifest, the backup is incomplete")),
+        Err(e) => return Err(e),
+    };
+    for file in &manifest.files {
+        let path = source.join(&file.name);
+        let size = std::fs::metadata(&path)?.len();
+        if size != file.size {
+            return Err(corrupt(&format!("{:?} has {} bytes, expected {}", file.name, size, file.size)));
+        }
+        if checksum(&path)? != file.crc {
+            return Err(corrupt(&format!("{:?} fails its checksum", file.name)));
+        }
+    }
+    if !manifest.files.iter().any(|file| file.name == WAL_FILE) {
+        return Err(corrupt("no write-ahead log"));
+    }
+    let checkpointed = match manifest.files.iter().any(|file| file.name == CHECKPOINT_FILE) {
+        true => match checkpoint::verify(&source.join(CHECKPOINT_FILE))? {
+            (sequence, true) => sequence,
+            (_, false) => return Err(corrupt("the checkpoint does not hold the documents")),
+        },
+        false => 0,
+    };
+    let last = Wal::verify(source.join(WAL_FILE))?.max(checkpointed);
+    if last != manifest.sequence {
+        return Err(corrupt(&format!("log ends at {}, expected {}", last, manifest.sequence)));
+    }
+    Ok(manifest)
+}
+
+/// Verifies the backup in `source` and copies it into the data directory `target`,
+/// which must not exist yet or be empty. The database can then be opened from `target`.
+pub fn restore(source: &Path, target: &Path) -> Result<Manifest, Error> {
+    let manifest = verify(source)?;
+    ensure_empty(target)?;
+    // Copy next to the target and rename, so a failed restore leaves no half-filled data directory.
+    let mut staging = target.as_os_str().to_owned();
+    staging.push(".restoring");
+    let staging = PathBuf::from(staging);
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    for file in &manifest.files {
+        let path = staging.join(&file.name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if copy(&source.join(&file.name), &path, file.size)? != file.crc {
+            return Err(corrupt(&format!("{:?} changed while being restored", file.name)));
+        }
+    }
+    if target.exists() {
+        std::fs::remove_dir(target)?;
+    }
+    std::fs::rename(&staging, target)?;
+    Ok(manifest)
+}
+
+fn ensure_empty(dir: &Path) -> Result<(), Error> {
+    match std::fs::read_dir(dir).map(|mut entries| entries.next().is_none()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(Error::new(ErrorKind::AlreadyExists, format!("{} is not empty", dir.display()))),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies the first `size` bytes of `from` into a new file `to`, returning their CRC-32.
+fn copy(from: &Path, to: &Path, size: u64) -> Result<u32, Error> {
+    let mut reader = File::open(from)?.take(size);
+    let mut writer = BufWriter::new(File::create(to)?);
+    let mut crc = Crc32::default();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+    }
+    if copied != size {
+        return Err(Error::new(ErrorKind::UnexpectedEof, format!("{} is shorter than {} bytes", from.display(), size)));
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(crc.finish())
+}
+
+fn checksum(path: &Path) -> Result<u32, Error> {
+    let mut file = File::open(path)?;
+    let mut crc = Crc32::default();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(crc.finish());
+        }
+        crc.update(&buffer[..n]);
+    }
+}
+
+fn corrupt(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Invalid backup: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::test_util::TempDir;
+    use serde_json::json;
+
+    #[test]
+    fn restored_backup_has_the_data_and_versions_as_of_the_backup() {
+        let dir = TempDir::new("backup");
+        let (data, backup, restored) = (dir.path().join("data"), dir.path().join("backup"), dir.path().join("restored"));
+        let mut db = Database::open(&data).unwrap();
+        db.put("c".to_string(), "a".to_string(), json!(1)).unwrap();
+        db.create_index("c".to_string(), "n".to_string(), vec!["n".to_string()], true).unwrap();
+        db.checkpoint().unwrap();
+        db.put("d".to_string(), "b".to_string(), json!({"n": 2})).unwrap();
+        let manifest = db.backup(&backup).unwrap();
+        db.put("c".to_string(), "later".to_string(), json!(3)).unwrap();
+
+        assert_eq!(manifest.sequence, 3);
+        assert_eq!(manifest.files[0].name, CHECKPOINT_FILE);
+        assert_eq!(verify(&backup).unwrap(), manifest);
+        assert!(db.backup(&backup).is_err());
+        assert_eq!(restore(&backup, &restored).unwrap(), manifest);
+        assert!(restore(&backup, &restored).is_err());
+
+        let mut copy = Database::open(&restored).unwrap();
+        assert_eq!(copy.get_versioned("d", "b").unwrap().unwrap().version, 3);
+        assert_eq!(copy.get("c", "later").unwrap(), None);
+        assert_eq!(copy.put("c".to_string(), "next".to_string(), json!(4)).unwrap(), 4);
+        assert!(Database::initialize().backup(&dir.path().join("memory")).is_err());
+    }
+
+    #[test]
+    fn shared_backups_hold_the_snapshot_while_writes_carry_on() {
+        use crate::collection::Scan;
+        use crate::database::SharedDatabase;
+        let dir = TempDir::new("backup");
+        let (data, backup, restored) = (dir.path().join("data"), dir.path().join("backup"), dir.path().join("restored"));
+        let shared = SharedDatabase::new(Database::open(&data).unwrap());
+        for i in 0..2500 {
+            shared.lock().put("c".to_string(), format!("k{:04}", i), json!(i)).unwrap();
+        }
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    shared.lock().put("c".to_string(), format!("k{:04}", i * 5), json!("changed")).unwrap();
+                    shared.lock().delete("c".to_string(), format!("k{:04}", i * 5 + 1)).unwrap();
+                }
+            })
+        };
+        let manifest = shared.backup(&backup).unwrap();
+        writer.join().unwrap();
+
+        restore(&backup, &restored).unwrap();
+        let copy = Database::open(&restored).unwrap();
+        let docs = copy.scan("c", &Scan::default()).unwrap();
+        // Every other write after the first 2500 is a delete.
+        assert_eq!(docs.len(), 2500 - (manifest.sequence as usize - 2500) / 2);
+        for (key, _) in &docs {
+            assert!(copy.get_versioned("c", key).unwrap().unwrap().version <= manifest.sequence);
+        }
+        assert_eq!(Wal::verify(restored.join(WAL_FILE)).unwrap(), 0);
+    }
+
+    #[test]
+    fn damaged_backups_are_not_restored() {
+        let dir = TempDir::new("backup");
+        let (data, backup) = (dir.path().join("data"), dir.path().join("backup"));
+        let mut db = Database::open(&data).unwrap();
+        db.put("c".to_string(), "a".to_string(), json!({"pad": "x".repeat(100)})).unwrap();
+        db.backup(&backup).unwrap();
+        let target = dir.path().join("restored");
+        let damage = |name: &str, edit: &dyn Fn(&mut Vec<u8>)| {
+            let copy = dir.path().join(name);
+            for file in [CHECKPOINT_FILE, WAL_FILE, MANIFEST] {
+                std::fs::create_dir_all(&copy).unwrap();
+                std::fs::copy(backup.join(file), copy.join(file)).unwrap();
+            }
+            let mut bytes = std::fs::read(copy.join(CHECKPOINT_FILE)).unwrap();
+            edit(&mut bytes);
+            std::fs::write(copy.join(CHECKPOINT_FILE), bytes).unwrap();
+            copy
+        };
+
+        let flipped = damage("flipped", &|bytes| bytes[40] ^= 1);
+        assert!(restore(&flipped, &target).unwrap_err().to_string().contains("checksum"));
+        let truncated = damage("truncated", &|bytes| bytes.truncate(bytes.len() - 1));
+        assert!(restore(&truncated, &target).unwrap_err().to_string().contains("bytes, expected"));
+        let incomplete = damage("incomplete", &|_| {});
+        std::fs::remove_file(incomplete.join(MANIFEST)).unwrap();
+        assert!(restore(&incomplete, &target).unwrap_err().to_string().contains("no manifest"));
+        std::fs::write(incomplete.join(MANIFEST), format!("{}\nsequence 1\n../checkpoint 1 0\n", HEADER)).unwrap();
+        assert!(restore(&incomplete, &target).unwrap_err().to_string().contains("outside the backup"));
+        assert!(!target.exists());
+    }
+}
@@ -0,0 +1,164 @@
+use crate::collection::{Collection, Versioned};
+use crate::wal::{self, WalRecord};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub(crate) const CHECKPOINT_FILE: &str = "checkpoint";
+const MAGIC: &[u8; 8] = b"OXCKPT01";
+const HEADER_LEN: u64 = 17;
+
+/// A checkpoint just written: everything up to `sequence` is in it (or, for
+/// documents not counted here, in the storage engines' files), so the log before
+/// it is no longer needed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub sequence: u64,
+    pub collections: usize,
+    pub documents: usize,
+    pub bytes: u64,
+}
+
+impl Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Checkpoint at version {}: {} collection(s) and {} document(s) written, {} bytes.",
+            self.sequence, self.collections, self.documents, self.bytes
+        )
+    }
+}
+
+/// Writes the state of `collections` as of `sequence` into `dir`, replacing the
+/// previous checkpoint only once the new one is complete. Without `documents`
+/// only collections and indexes are written: the documents must already be in
+/// the storage engines' own files.
+pub(crate) fn write(dir: &Path, sequence: u64, collections: &HashMap<String, Collection>, documents: bool) -> Result<Checkpoint, Error> {
+    let mut writer = CheckpointWriter::create(dir, sequence, documents)?;
+    for (name, records) in schema(collections) {
+        writer.collection(&records)?;
+        if !documents {
+            continue;
+        }
+        for entry in collections[&name].iter()? {
+            let (key, stored) = entry?;
+            writer.document(&name, key, stored)?;
+        }
+    }
+    writer.finish()
+}
+
+/// The records creating every collection, named first, and its indexes, by collection name.
+pub(crate) fn schema(collections: &HashMap<String, Collection>) -> Vec<(String, Vec<WalRecord>)> {
+    let mut names: Vec<&String> = collections.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let mut records = vec![WalRecord::CreateCollection { name: name.clone() }];
+            for (index_name, index) in collections[name].indexes() {
+                records.push(match index.expire_after() {
+                    Some(expire_after) => WalRecord::CreateTtlIndex {
+                        collection: name.clone(),
+                        name: index_name.clone(),
+                        field: index.fields()[0].clone(),
+                        expire_after,
+                    },
+                    None => WalRecord::CreateIndex {
+                        collection: name.clone(),
+                        name: index_name.clone(),
+                        fields: index.fields().to_vec(),
+                        unique: index.is_unique(),
+                    },
+                });
+            }
+            (name.clone(), records)
+        })
+        .collect()
+}
+
+/// A checkpoint being written; it replaces the previous one once finished.
+///
+/// The file is `MAGIC`, the sequence number (u64), whether documents are included
+/// (u8) and WAL frames that rebuild the state when applied in order: each collection,
+/// its indexes, then its documents, every one framed with its version as the sequence number.
+pub(crate) struct CheckpointWriter {
+    out: BufWriter<File>,
+    dir: PathBuf,
+    checkpoint: Checkpoint,
+}
+
+impl CheckpointWriter {
+    pub(crate) fn create(dir: &Path, sequence: u64, documents: bool) -> Result<CheckpointWriter, Error> {
+        let mut out = BufWriter::new(File::create(dir.join(format!("{}.tmp", CHECKPOINT_FILE)))?);
+        out.write_all(MAGIC)?;
+        out.write_all(&sequence.to_be_bytes())?;
+        out.write_all(&[documents as u8])?;
+        let checkpoint = Checkpoint { sequence, collections: 0, documents: 0, bytes: HEADER_LEN };
+        Ok(CheckpointWriter { out, dir: dir.to_path_buf(), checkpoint })
+    }
+
+    /// Starts a collection with the records of its [`schema`].
+    pub(crate) fn collection(&mut self, records: &[WalRecord]) -> Result<(), Error> {
+        self.checkpoint.collections += 1;
+        for record in records {
+            self.emit(self.checkpoint.sequence, record)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a document of `collection`, which must be the last one started.
+    pub(crate) fn document(&mut self, collection: &str, key: String, stored: Versioned) -> Result<(), Error> {
+        let record = WalRecord::Put {
+            collection: collection.to_string(),
+            key,
+            value: stored.doc,
+            expires_at: stored.expires_at,
+        };
+        self.checkpoint.documents += 1;
+        self.emit(stored.version, &record)
+    }
+
+    pub(crate) fn finish(self) -> Result<Checkpoint, Error> {
+        self.out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(self.dir.join(format!("{}.tmp", CHECKPOINT_FILE)), self.dir.join(CHECKPOINT_FILE))?;
+        Ok(self.checkpoint)
+    }
+
+    fn emit(&mut self, lsn: u64, record: &WalRecord) -> Result<(), Error> {
+        let frame = wal::frame(lsn, record);
+        self.checkpoint.bytes += frame.len() as u64;
+        self.out.write_all(&frame)
+    }
+}
+
+/// Passes every record of the checkpoint at `path` to `apply`, with the sequence
+/// number it carries, and returns the checkpoint's own sequence number and whether
+/// it holds the documents; `None` when there is no checkpoint. A damaged checkpoint
+/// is an error, never skipped.
+pub(crate) fn read(path: &Path, apply: impl FnMut(u64, WalRecord) -> Result<(), Error>) -> Result<Option<(u64, bool)>, Error> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut header = [0u8; HEADER_LEN as usize];
+    if len < HEADER_LEN || reader.read_exact(&mut header).is_err() || &header[..8] != MAGIC || header[16] > 1 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{} is not a checkpoint", path.display())));
+    }
+    let sequence = u64::from_be_bytes(header[8..16].try_into().unwrap());
+    wal::read_all(&mut reader, len - HEADER_LEN, apply)
+        .map_err(|e| Error::new(e.kind(), format!("Damaged checkpoint {}: {}", path.display(), e)))?;
+    Ok(Some((sequence, header[16] == 1)))
+}
+
+/// Checks that the checkpoint at `path` reads back in full and returns its sequence
+/// number and whether it holds the documents.
+pub(crate) fn verify(path: &Path) -> Result<(u64, bool), Error> {
+    read(path, |_, _| Ok(()))?.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No checkpoint at {}", path.display())))
+}
@@ -1,32 +1,345 @@
+use crate::error::UniqueViolation;
+use crate::index::{self, Index};
+use crate::query::Filter;
+use crate::storage::engine::{EngineStats, EntryIter, StorageEngine};
+use crate::storage::memory::MemoryEngine;
+use crate::storage::range::KeyRange;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Error, ErrorKind};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub type Document = Value;
 
+/// A document together with its version: the sequence number of the write that
+/// stored it. Versions of a key only ever grow, even across a delete.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Versioned {
+    pub version: u64,
+    pub doc: Document,
+    /// Unix time in milliseconds from which the document counts as deleted.
+    pub expires_at: Option<u64>,
+}
+
+impl Versioned {
+    pub fn new(version: u64, doc: Document) -> Versioned {
+        Versioned { version, doc, expires_at: None }
+    }
+
+    pub fn expiring(version: u64, doc: Document, expires_at: Option<u64>) -> Versioned {
+        Versioned { version, doc, expires_at }
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+/// Current Unix time in milliseconds, the clock document expiry is measured against.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Which documents a scan returns: a key range, its direction and an optional cap.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scan {
+    pub range: KeyRange,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+}
+
+/// Documents of one collection, read back without those that have expired.
 #[derive(Debug)]
 pub struct Collection {
-    data: HashMap<String, Document>,
+    engine: Box<dyn StorageEngine>,
+    indexes: BTreeMap<String, Index>,
+    /// Expiry of every document stored with a TTL, by key and by time.
+    expiries: HashMap<String, u64>,
+    expiring: BTreeSet<(u64, String)>,
 }
 
 impl Collection {
     pub fn new() -> Collection {
-        Collection { data: HashMap::new() }
+        Collection::with_engine(Box::new(MemoryEngine::new()))
     }
-    
-    pub fn put(&mut self, key: String, value: Document) {
-        self.data.insert(key, value);
+
+    /// A collection over an empty `engine`.
+    pub fn with_engine(engine: Box<dyn StorageEngine>) -> Collection {
+        Collection { engine, indexes: BTreeMap::new(), expiries: HashMap::new(), expiring: BTreeSet::new() }
+    }
+
+    /// A collection over an `engine` that may already hold documents.
+    pub fn open(engine: Box<dyn StorageEngine>) -> Result<Collection, Error> {
+        let mut collection = Collection::with_engine(engine);
+        let mut expiring = Vec::new();
+        for entry in collection.engine.iter()? {
+            let (key, stored) = entry?;
+            if let Some(at) = stored.expires_at {
+                expiring.push((key, at));
+            }
+        }
+        for (key, at) in expiring {
+            collection.track_expiry(&key, Some(at));
+        }
+        Ok(collection)
+    }
+
+    /// Stores `value` one version above the key's current one (1 for a new key).
+    pub fn put(&mut self, key: String, value: Document) -> Result<(), Error> {
+        let version = self.engine.get(&key)?.map_or(1, |current| current.version + 1);
+        self.put_versioned(key, Versioned::new(version, value))
+    }
+
+    /// Stores `value` with the version chosen by the caller.
+    pub fn put_versioned(&mut self, key: String, value: Versioned) -> Result<(), Error> {
+        self.track_expiry(&key, value.expires_at);
+        if !self.indexes.is_empty() {
+            let old = self.engine.get(&key)?;
+            for index in self.indexes.values_mut() {
+                if let Some(old) = &old {
+                    index.remove(&key, &old.doc);
+                }
+                index.insert(&key, &value.doc);
+            }
+        }
+        self.engine.put(key, value)
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Document>, Error> {
+        Ok(self.get_versioned(key)?.map(|stored| stored.doc))
+    }
+
+    pub fn get_versioned(&self, key: &str) -> Result<Option<Versioned>, Error> {
+        let now = now_millis();
+        Ok(self.engine.get(key)?.filter(|stored| self.is_live(stored, now)))
+    }
+
+    pub fn delete(&mut self, key: String) -> Result<(), Error> {
+        self.track_expiry(&key, None);
+        if !self.indexes.is_empty()
+            && let Some(old) = self.engine.get(&key)?
+        {
+            for index in self.indexes.values_mut() {
+                index.remove(&key, &old.doc);
+            }
+        }
+        self.engine.delete(&key)
     }
 
-    pub fn get(&self, key: &str) -> Option<&Document> {
-        self.data.get(key)
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.engine.iter()?.next().transpose()?.is_none())
     }
 
-    pub fn delete(&mut self, key: String) {
-        self.data.remove(&key);
+    /// Every stored document, with its version, in ascending key order; expired
+    /// documents the reaper has not removed yet included.
+    pub fn iter(&self) -> Result<EntryIter<'_>, Error> {
+        self.engine.iter()
     }
-    
-    pub fn is_empty(&self) -> bool {
-        self.data.is_empty()
+
+    /// Documents in `range`, with their versions, in scan order.
+    pub fn range(&self, range: &KeyRange, reverse: bool) -> Result<EntryIter<'_>, Error> {
+        let now = now_millis();
+        let live = self.engine.scan(range, reverse)?.filter(move |entry| match entry {
+            Ok((_, stored)) => self.is_live(stored, now),
+            Err(_) => true,
+        });
+        Ok(Box::new(live))
+    }
+
+    pub fn scan(&self, scan: &Scan) -> Result<Vec<(String, Document)>, Error> {
+        self.range(&scan.range, scan.reverse)?
+            .take(scan.limit.unwrap_or(usize::MAX))
+            .map(|entry| entry.map(|(key, stored)| (key, stored.doc)))
+            .collect()
+    }
+
+    /// Every document matching `filter`, in ascending key order. Uses the best
+    /// secondary index for the filter when there is one, otherwise scans.
+    pub fn find(&self, filter: &Filter) -> Result<Vec<(String, Document)>, Error> {
+        let mut found = Vec::new();
+        match index::candidates(&self.indexes, filter) {
+            Some(keys) => {
+                for key in keys {
+                    if let Some(stored) = self.get_versioned(&key)?
+                        && filter.matches(&stored.doc)
+                    {
+                        found.push((key, stored.doc));
+                    }
+                }
+            }
+            None => {
+                for entry in self.range(&KeyRange::all(), false)? {
+                    let (key, stored) = entry?;
+                    if filter.matches(&stored.doc) {
+                        found.push((key, stored.doc));
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Builds a secondary index named `name` over the dotted `fields` from the
+    /// current documents; it is kept up to date by every later `put` and `delete`.
+    ///
+    /// Uniqueness is not checked here: callers validate with [`Collection::build_index`]
+    /// and [`Collection::check_unique`] before a write is logged, so that replaying the
+    /// log never fails.
+    pub fn create_index(&mut self, name: String, fields: Vec<String>, unique: bool) -> Result<(), Error> {
+        if self.indexes.contains_key(&name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Index {:?} already exists", name)));
+        }
+        let index = self.build_index(fields, unique)?;
+        self.indexes.insert(name, index);
+        Ok(())
+    }
+
+    /// Builds a TTL index named `name` over `field`: documents expire `seconds` after
+    /// the Unix time it holds.
+    pub fn create_ttl_index(&mut self, name: String, field: String, seconds: u64) -> Result<(), Error> {
+        if self.indexes.contains_key(&name) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Index {:?} already exists", name)));
+        }
+        let mut index = Index::ttl(field, seconds);
+        for entry in self.engine.iter()? {
+            let (key, stored) = entry?;
+            index.insert(&key, &stored.doc);
+        }
+        self.indexes.insert(name, index);
+        Ok(())
+    }
+
+    /// An index over the current documents, without registering it.
+    pub fn build_index(&self, fields: Vec<String>, unique: bool) -> Result<Index, Error> {
+        let mut index = Index::new(fields, unique);
+        for entry in self.engine.iter()? {
+            let (key, stored) = entry?;
+            index.insert(&key, &stored.doc);
+        }
+        Ok(index)
+    }
+
+    /// Fails if storing `doc` at `key` would break one of the unique indexes.
+    /// Expired documents do not hold on to their values.
+    pub fn check_unique(&self, key: &str, doc: &Document) -> Result<(), Error> {
+        for (name, index) in self.indexes.iter().filter(|(_, index)| index.is_unique()) {
+            if let Some(existing) = self.first_live(index.conflicts(key, doc))? {
+                return Err(UniqueViolation {
+                    index: name.clone(),
+                    key: key.to_string(),
+                    existing: existing.to_string(),
+                }
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`Collection::check_unique`] for several writes applied at once (`None`:
+    /// delete): only the resulting documents have to be unique, so two keys may swap values.
+    /// Holders in the index that the writes replace or delete do not count; the written
+    /// documents are checked against each other in an index of their own.
+    pub fn check_unique_all(&self, writes: &BTreeMap<String, Option<Versioned>>) -> Result<(), Error> {
+        for (name, index) in self.indexes.iter().filter(|(_, index)| index.is_unique()) {
+            let mut written = Index::new(index.fields().to_vec(), true);
+            for (key, doc) in writes.iter().filter_map(|(key, stored)| Some((key, &stored.as_ref()?.doc))) {
+                let mut holders = index.conflicts(key, doc);
+                holders.retain(|holder| !writes.contains_key(*holder));
+                let existing = match written.conflict(key, doc) {
+                    Some(existing) => Some(existing),
+                    None => self.first_live(holders)?,
+                };
+                if let Some(existing) = existing {
+                    return Err(UniqueViolation {
+                        index: name.clone(),
+                        key: key.clone(),
+                        existing: existing.to_string(),
+                    }
+                    .into());
+                }
+                written.insert(key, doc);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys of up to `limit` documents that have expired at `now` but are still stored.
+    pub fn expired(&self, now: u64, limit: usize) -> BTreeSet<String> {
+        let mut keys: BTreeSet<String> = self
+            .expiring
+            .iter()
+            .take_while(|(at, _)| *at <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect();
+        for index in self.indexes.values() {
+            if keys.len() == limit {
+                break;
+            }
+            // Asking for `limit` rather than what is left: some may already be in `keys`.
+            for key in index.expired(now as f64 / 1000.0, limit) {
+                if keys.len() == limit {
+                    break;
+                }
+                keys.insert(key);
+            }
+        }
+        keys
+    }
+
+    /// Whether `stored` has neither passed its own expiry nor one set by a TTL index.
+    pub fn is_live(&self, stored: &Versioned, now: u64) -> bool {
+        !stored.is_expired(now) && !self.indexes.values().any(|index| index.has_expired(&stored.doc, now as f64 / 1000.0))
+    }
+
+    fn first_live<'a>(&self, keys: BTreeSet<&'a str>) -> Result<Option<&'a str>, Error> {
+        for key in keys {
+            if self.get_versioned(key)?.is_some() {
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    fn track_expiry(&mut self, key: &str, expires_at: Option<u64>) {
+        if let Some(at) = self.expiries.remove(key) {
+            self.expiring.remove(&(at, key.to_string()));
+        }
+        if let Some(at) = expires_at {
+            self.expiries.insert(key.to_string(), at);
+            self.expiring.insert((at, key.to_string()));
+        }
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> Result<(), Error> {
+        match self.indexes.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(Error::new(ErrorKind::NotFound, format!("Index {:?} not found", name))),
+        }
+    }
+
+    pub fn indexes(&self) -> &BTreeMap<String, Index> {
+        &self.indexes
+    }
+
+    pub fn stats(&self) -> EngineStats {
+        self.engine.stats()
+    }
+
+    /// Writes buffered documents out to the engine's files, if it keeps any.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.engine.flush()
+    }
+
+    /// Removes the collection's persistent state, if any.
+    pub fn destroy(&mut self) -> Result<(), Error> {
+        self.engine.destroy()
+    }
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        Collection::new()
     }
 }
 
@@ -44,38 +357,38 @@ mod tests {
     fn put_and_get_value() {
         let mut collection = Collection::new();
         let value = make_json_object("value1");
-        collection.put("key1".to_string(), value.clone());
+        collection.put("key1".to_string(), value.clone()).unwrap();
         println!("{:?}", collection);
-        assert_eq!(collection.get("key1"), Some(&value));
+        assert_eq!(collection.get("key1").unwrap(), Some(value));
     }
 
     #[test]
     fn get_nonexistent_key_returns_none() {
         let collection = Collection::new();
-        assert_eq!(collection.get("missing"), None);
+        assert_eq!(collection.get("missing").unwrap(), None);
     }
 
     #[test]
     fn delete_removes_value() {
         let mut collection = Collection::new();
         let value = make_json_object("value1");
-        collection.put("key1".to_string(), value);
-        collection.delete("key1".to_string());
-        assert_eq!(collection.get("key1"), None);
+        collection.put("key1".to_string(), value).unwrap();
+        collection.delete("key1".to_string()).unwrap();
+        assert_eq!(collection.get("key1").unwrap(), None);
     }
 
     #[test]
     fn is_empty_true_on_new_collection() {
         let collection = Collection::new();
-        assert!(collection.is_empty());
+        assert!(collection.is_empty().unwrap());
     }
 
     #[test]
     fn is_empty_false_when_has_values() {
         let mut collection = Collection::new();
         let value = make_json_object("value1");
-        collection.put("key1".to_string(), value);
-        assert!(!collection.is_empty());
+        collection.put("key1".to_string(), value).unwrap();
+        assert!(!collection.is_empty().unwrap());
     }
 
     #[test]
@@ -83,18 +396,137 @@ mod tests {
         let mut collection = Collection::new();
         let value1 = make_json_object("value1");
         let value2 = make_json_object("value2");
-        collection.put("key1".to_string(), value1.clone());
-        collection.put("key1".to_string(), value2.clone());
-        assert_eq!(collection.get("key1"), Some(&value2));
-        assert_ne!(collection.get("key1"), Some(&value1));
+        collection.put("key1".to_string(), value1.clone()).unwrap();
+        collection.put("key1".to_string(), value2.clone()).unwrap();
+        assert_eq!(collection.get("key1").unwrap(), Some(value2));
+        assert_ne!(collection.get("key1").unwrap(), Some(value1));
     }
 
     #[test]
     fn delete_nonexistent_key_does_nothing() {
         let mut collection = Collection::new();
         let value = make_json_object("value1");
-        collection.put("key1".to_string(), value.clone());
-        collection.delete("missing".to_string());
-        assert_eq!(collection.get("key1"), Some(&value));
+        collection.put("key1".to_string(), value.clone()).unwrap();
+        collection.delete("missing".to_string()).unwrap();
+        assert_eq!(collection.get("key1").unwrap(), Some(value));
+    }
+
+    #[test]
+    fn scan_applies_range_direction_and_limit() {
+        let mut collection = Collection::new();
+        for key in ["user:1", "user:2", "user:3", "order:1"] {
+            collection.put(key.to_string(), make_json_object(key)).unwrap();
+        }
+        let scan = Scan { range: KeyRange::prefix("user:"), reverse: true, limit: Some(2) };
+        let keys: Vec<String> = collection.scan(&scan).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["user:3", "user:2"]);
+        assert_eq!(collection.scan(&Scan::default()).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn indexes_follow_puts_and_deletes() {
+        let mut collection = Collection::new();
+        collection.put("a".to_string(), serde_json::json!({"n": 1})).unwrap();
+        collection.create_index("n".to_string(), vec!["n".to_string()], false).unwrap();
+        collection.put("b".to_string(), serde_json::json!({"n": 2})).unwrap();
+        collection.put("a".to_string(), serde_json::json!({"n": 2})).unwrap();
+        collection.delete("b".to_string()).unwrap();
+
+        let index = &collection.indexes()["n"];
+        assert_eq!(index.len(), 1);
+        let filter = Filter::parse(&serde_json::json!({"n": 2})).unwrap();
+        assert_eq!(collection.find(&filter).unwrap(), vec![("a".to_string(), serde_json::json!({"n": 2}))]);
+        let filter = Filter::parse(&serde_json::json!({"n": 1})).unwrap();
+        assert!(collection.find(&filter).unwrap().is_empty());
+    }
+
+    #[test]
+    fn index_names_are_unique() {
+        let mut collection = Collection::new();
+        collection.create_index("n".to_string(), vec!["n".to_string()], false).unwrap();
+        let err = collection.create_index("n".to_string(), vec!["m".to_string()], false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        collection.drop_index("n").unwrap();
+        assert_eq!(collection.drop_index("n").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn check_unique_rejects_values_held_by_other_keys() {
+        let mut collection = Collection::new();
+        collection.put("u1".to_string(), serde_json::json!({"email": "a@x"})).unwrap();
+        collection.create_index("email".to_string(), vec!["email".to_string()], true).unwrap();
+
+        collection.check_unique("u1", &serde_json::json!({"email": "a@x", "v": 2})).unwrap();
+        let err = collection.check_unique("u2", &serde_json::json!({"email": "a@x"})).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(err.to_string(), "Unique index \"email\" violated: \"u2\" duplicates the value of \"u1\"");
+        collection.delete("u1".to_string()).unwrap();
+        collection.check_unique("u2", &serde_json::json!({"email": "a@x"})).unwrap();
+    }
+
+    #[test]
+    fn check_unique_all_counts_only_the_resulting_documents() {
+        let mut collection = Collection::new();
+        collection.create_index("email".to_string(), vec!["email".to_string()], true).unwrap();
+        for (key, email) in [("u1", "a@x"), ("u2", "b@x"), ("u3", "c@x")] {
+            collection.put(key.to_string(), serde_json::json!({"email": email})).unwrap();
+        }
+        let doc = |email: &str| Some(Versioned::new(0, serde_json::json!({"email": email})));
+        let check = |writes: &[(&str, Option<Versioned>)]| {
+            let writes = writes.iter().map(|(key, stored)| (key.to_string(), stored.clone())).collect();
+            collection.check_unique_all(&writes)
+        };
+
+        check(&[("u1", doc("b@x")), ("u2", doc("a@x"))]).unwrap();
+        check(&[("u3", None), ("u4", doc("c@x"))]).unwrap();
+        let err = check(&[("u4", doc("a@x"))]).unwrap_err();
+        assert_eq!(err.to_string(), "Unique index \"email\" violated: \"u4\" duplicates the value of \"u1\"");
+        let err = check(&[("u4", doc("d@x")), ("u5", doc("d@x"))]).unwrap_err();
+        assert_eq!(err.to_string(), "Unique index \"email\" violated: \"u5\" duplicates the value of \"u4\"");
+        // Checking leaves the live index as it was.
+        assert_eq!(collection.indexes()["email"].len(), 3);
+    }
+
+    #[test]
+    fn put_bumps_the_version_of_the_key() {
+        let mut collection = Collection::new();
+        collection.put("k".to_string(), make_json_object("v1")).unwrap();
+        collection.put("k".to_string(), make_json_object("v2")).unwrap();
+        assert_eq!(collection.get_versioned("k").unwrap(), Some(Versioned::new(2, make_json_object("v2"))));
+        collection.put_versioned("k".to_string(), Versioned::new(10, make_json_object("v3"))).unwrap();
+        assert_eq!(collection.get_versioned("k").unwrap().unwrap().version, 10);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn expired_documents_are_hidden_until_reaped() {
+        let mut collection = Collection::new();
+        collection.put_versioned("old".to_string(), Versioned::expiring(1, make_json_object("a"), Some(1_000))).unwrap();
+        collection.put_versioned("new".to_string(), Versioned::expiring(2, make_json_object("a"), Some(u64::MAX))).unwrap();
+        collection.create_index("data".to_string(), vec!["data".to_string()], true).unwrap();
+
+        assert_eq!(collection.get("old").unwrap(), None);
+        assert_eq!(collection.scan(&Scan::default()).unwrap().len(), 1);
+        assert_eq!(collection.expired(now_millis(), 10), BTreeSet::from(["old".to_string()]));
+        collection.delete("new".to_string()).unwrap();
+        collection.check_unique("other", &make_json_object("a")).unwrap();
+
+        collection.put("old".to_string(), make_json_object("b")).unwrap();
+        assert!(collection.expired(now_millis(), 10).is_empty());
+    }
+
+    #[test]
+    fn ttl_index_expires_documents_after_their_timestamp() {
+        let mut collection = Collection::new();
+        let now = now_millis() / 1000;
+        collection.put("stale".to_string(), serde_json::json!({"created": now - 120})).unwrap();
+        collection.put("staler".to_string(), serde_json::json!({"created": now - 180})).unwrap();
+        collection.put("fresh".to_string(), serde_json::json!({"created": now})).unwrap();
+        collection.put("untimed".to_string(), serde_json::json!({"created": "yesterday"})).unwrap();
+        collection.create_ttl_index("created".to_string(), "created".to_string(), 60).unwrap();
+
+        let keys: Vec<String> = collection.scan(&Scan::default()).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["fresh", "untimed"]);
+        assert_eq!(collection.expired(now_millis(), 10), BTreeSet::from(["stale".to_string(), "staler".to_string()]));
+        assert_eq!(collection.expired(now_millis(), 1), BTreeSet::from(["staler".to_string()]));
+    }
+}
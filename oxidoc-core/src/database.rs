@@ -1,44 +1,703 @@
-use crate::collection::{Collection, Document};
+use crate::backup::{self, Manifest};
+use crate::checkpoint::{self, Checkpoint, CheckpointWriter, CHECKPOINT_FILE};
+use crate::collection::{now_millis, Collection, Document, Scan, Versioned};
+use crate::error::{DocumentNotFound, DuplicateKey, UniqueViolation, VersionConflict};
+use crate::query::Filter;
+use crate::snapshot::{History, Snapshot, Snapshots};
+use crate::update::Update;
+use crate::storage::engine::{EngineConfig, EngineStats};
+use crate::wal::{Wal, WalRecord};
 use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Display;
+use std::io::{Error, ErrorKind};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub(crate) const WAL_FILE: &str = "wal.log";
+const COLLECTIONS_DIR: &str = "collections";
+/// Documents copied per lock of the database by online backups and checkpoints.
+const DUMP_PAGE: usize = 1024;
+
+/// Whether a put may create a new document, overwrite an existing one, or both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Fails with a [`DuplicateKey`] if the key is taken.
+    Insert,
+    /// Fails with a [`DocumentNotFound`] if the key is free.
+    Replace,
+    #[default]
+    Upsert,
+}
+
+/// Conditions on a put and the lifetime of the document it stores.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PutOptions {
+    pub mode: WriteMode,
+    /// Only write if the document is still at this version (0: only if it does not exist).
+    pub expected: Option<u64>,
+    /// Unix time in milliseconds from which the document counts as deleted.
+    pub expires_at: Option<u64>,
+}
+
+/// Outcome of a successful put.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    pub version: u64,
+    /// `false` when an existing document was overwritten.
+    pub created: bool,
+}
 
 #[derive(Debug)]
 pub struct Database {
     db: HashMap<String, Collection>,
+    wal: Option<Wal>,
+    dir: Option<PathBuf>,
+    engine: EngineConfig,
+    /// Sequence number of the last write, which is also the version it gives a document.
+    sequence: u64,
+    /// Sequence number of the last checkpoint; the log only holds writes after it.
+    checkpointed: u64,
+    snapshots: Snapshots,
+    /// Versions replaced since the oldest open snapshot.
+    history: History,
+    /// Why a logged write could not be applied: the state in memory lacks it, so
+    /// nothing more is written, checkpointed or backed up until the database is reopened.
+    failed: Option<String>,
+    /// Directory under which the `backup` command writes, if backups by name are allowed.
+    backup_dir: Option<PathBuf>,
 }
 
 impl Database {
+    /// Volatile database: nothing survives the process.
     pub fn initialize() -> Database {
-        Database { db: HashMap::new() }
+        Database {
+            db: HashMap::new(),
+            wal: None,
+            dir: None,
+            engine: EngineConfig::InMemory,
+            sequence: 0,
+            checkpointed: 0,
+            snapshots: Snapshots::default(),
+            history: History::default(),
+            failed: None,
+            backup_dir: None,
+        }
+    }
+
+    /// Opens the database stored in `dir` with the default storage engine.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Database, Error> {
+        Database::open_with(dir, EngineConfig::default())
+    }
+
+    /// Opens the database stored in `dir`, creating collections with `engine`,
+    /// loading the last checkpoint and replaying the write-ahead log after it.
+    pub fn open_with<P: AsRef<Path>>(dir: P, engine: EngineConfig) -> Result<Database, Error> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let (mut wal, records) = Wal::open(dir.join(WAL_FILE))?;
+        let mut database = Database {
+            db: HashMap::new(),
+            wal: None,
+            dir: Some(dir.to_path_buf()),
+            engine,
+            sequence: 0,
+            checkpointed: 0,
+            snapshots: Snapshots::default(),
+            history: History::default(),
+            failed: None,
+            backup_dir: None,
+        };
+        if let Some((sequence, documents)) = checkpoint::read(&dir.join(CHECKPOINT_FILE), |lsn, record| database.apply(lsn, record))? {
+            if !documents && !database.engine.is_persistent() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "The checkpoint leaves the documents to the LSM engine's files: open the database with that engine",
+                ));
+            }
+            database.sequence = sequence;
+            database.checkpointed = sequence;
+            wal.resume_after(sequence);
+        }
+        // Records a checkpoint covers are left over from a crash before the log was truncated.
+        // Every record was checked before it was logged, so only I/O can fail here.
+        let checkpointed = database.checkpointed;
+        for (lsn, record) in records.into_iter().filter(|(lsn, _)| *lsn > checkpointed) {
+            database
+                .apply(lsn, record)
+                .map_err(|e| Error::new(e.kind(), format!("Replaying WAL record {}: {}", lsn, e)))?;
+            database.sequence = lsn;
+        }
+        database.wal = Some(wal);
+        Ok(database)
+    }
+
+    pub fn create_collection(&mut self, name: String) -> Result<(), Error> {
+        self.write(WalRecord::CreateCollection { name })?;
+        Ok(())
+    }
+
+    pub fn delete_collection(&mut self, name: &str) -> Result<(), Error> {
+        self.write(WalRecord::DeleteCollection { name: name.to_string() })?;
+        Ok(())
+    }
+
+    /// Stores `value` at `key` and returns its new version. Fails with a
+    /// [`UniqueViolation`](crate::error::UniqueViolation) (and logs nothing) if a unique
+    /// index already holds one of its values under another key.
+    pub fn put(&mut self, collection: String, key: String, value: Document) -> Result<u64, Error> {
+        self.put_if_version(collection, key, value, None)
+    }
+
+    /// Like [`Database::put`], but only if the document is still at version `expected`
+    /// (`Some(0)`: only if it does not exist); otherwise fails with a
+    /// [`VersionConflict`](crate::error::VersionConflict).
+    pub fn put_if_version(
+        &mut self,
+        collection: String,
+        key: String,
+        value: Document,
+        expected: Option<u64>,
+    ) -> Result<u64, Error> {
+        let options = PutOptions { expected, ..PutOptions::default() };
+        Ok(self.put_with(collection, key, value, options)?.version)
+    }
+
+    /// Stores `value` at `key` if the mode of `options` allows it given whether the key
+    /// is taken and, when a version is expected, the document is still at that version.
+    /// Expired documents count as absent. Nothing is logged when a check fails.
+    pub fn put_with(&mut self, collection: String, key: String, value: Document, options: PutOptions) -> Result<Written, Error> {
+        let current = self.get_versioned(&collection, &key)?.map(|stored| stored.version);
+        check_put(&collection, &key, current, options.mode, options.expected)?;
+        if let Some(coll) = self.db.get(&collection) {
+            coll.check_unique(&key, &value)?;
+        }
+        let version = self.write(WalRecord::Put { collection, key, value, expires_at: options.expires_at })?;
+        Ok(Written { version, created: current.is_none() })
+    }
+
+    /// Applies `update` to the document at `key` and stores the result, which is returned
+    /// with its new version and the expiry of the original. Runs under the caller's
+    /// exclusive borrow, so no other write can interleave between reading the document
+    /// and storing the new version.
+    pub fn update(&mut self, collection: String, key: String, update: &Update, expected: Option<u64>) -> Result<Versioned, Error> {
+        let current = self
+            .get_versioned(&collection, &key)?
+            .ok_or_else(|| DocumentNotFound { collection: collection.clone(), key: key.clone() })?;
+        if let Some(expected) = expected
+            && current.version != expected
+        {
+            return Err(VersionConflict { key, expected, actual: Some(current.version) }.into());
+        }
+        let updated = update.apply(&current.doc)?;
+        let options = PutOptions { expires_at: current.expires_at, ..PutOptions::default() };
+        let version = self.put_with(collection, key, updated.clone(), options)?.version;
+        Ok(Versioned::expiring(version, updated, current.expires_at))
+    }
+
+    pub fn get(&self, collection: &str, key: &str) -> Result<Option<Document>, Error> {
+        Ok(self.get_versioned(collection, key)?.map(|stored| stored.doc))
+    }
+
+    pub fn get_versioned(&self, collection: &str, key: &str) -> Result<Option<Versioned>, Error> {
+        match self.db.get(collection) {
+            Some(coll) => coll.get_versioned(key),
+            None => Ok(None),
+        }
+    }
+
+    /// Documents of `collection` selected by `scan`; empty if the collection does not exist.
+    pub fn scan(&self, collection: &str, scan: &Scan) -> Result<Vec<(String, Document)>, Error> {
+        match self.db.get(collection) {
+            Some(coll) => coll.scan(scan),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Documents of `collection` matching `filter`; empty if the collection does not exist.
+    pub fn find(&self, collection: &str, filter: &Filter) -> Result<Vec<(String, Document)>, Error> {
+        match self.db.get(collection) {
+            Some(coll) => coll.find(filter),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Opens a consistent view of every collection as of the last write. Versions it
+    /// needs are kept until the snapshot is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        self.snapshots.open(self.sequence)
+    }
+
+    /// [`Database::get_versioned`] as of `snapshot`.
+    pub fn get_at(&self, snapshot: &Snapshot, collection: &str, key: &str) -> Result<Option<Versioned>, Error> {
+        let current = self.get_versioned(collection, key)?;
+        let stored = self.history.resolve(collection, key, current, snapshot.sequence());
+        Ok(stored.filter(|stored| self.is_live(collection, stored, now_millis())))
+    }
+
+    /// [`Database::scan`] as of `snapshot`.
+    pub fn scan_at(&self, snapshot: &Snapshot, collection: &str, scan: &Scan) -> Result<Vec<(String, Document)>, Error> {
+        let found = self.scan_versioned_at(snapshot, collection, scan)?;
+        Ok(found.into_iter().map(|(key, stored)| (key, stored.doc)).collect())
+    }
+
+    /// [`Database::scan_at`], with the version and expiry of every document.
+    pub fn scan_versioned_at(&self, snapshot: &Snapshot, collection: &str, scan: &Scan) -> Result<Vec<(String, Versioned)>, Error> {
+        let mut current = match self.db.get(collection) {
+            Some(coll) => coll.range(&scan.range, scan.reverse)?,
+            None => Box::new(std::iter::empty()),
+        }
+        .peekable();
+        let mut past = self.history.keys(collection, &scan.range, scan.reverse).peekable();
+        let precedes = |a: &String, b: &String| if scan.reverse { a > b } else { a < b };
+        let limit = scan.limit.unwrap_or(usize::MAX);
+        let now = now_millis();
+        let mut found = Vec::new();
+        while found.len() < limit {
+            // Keys with a past but no current document were deleted since the snapshot.
+            let (key, stored) = match (current.peek(), past.peek()) {
+                (None, None) => break,
+                (Some(Ok((key, _))), Some(old)) if precedes(old, key) => (past.next().cloned().unwrap(), None),
+                (None, Some(_)) => (past.next().cloned().unwrap(), None),
+                (Some(_), old) => {
+                    let (key, stored) = current.next().unwrap()?;
+                    if old == Some(&&key) {
+                        past.next();
+                    }
+                    (key, Some(stored))
+                }
+            };
+            if let Some(stored) = self.history.resolve(collection, &key, stored, snapshot.sequence())
+                && self.is_live(collection, &stored, now)
+            {
+                found.push((key, stored));
+            }
+        }
+        Ok(found)
+    }
+
+    /// [`Database::find`] as of `snapshot`. Indexes only describe the current documents,
+    /// so this always scans.
+    pub fn find_at(&self, snapshot: &Snapshot, collection: &str, filter: &Filter) -> Result<Vec<(String, Document)>, Error> {
+        let mut found = self.scan_at(snapshot, collection, &Scan::default())?;
+        found.retain(|(_, doc)| filter.matches(doc));
+        Ok(found)
     }
 
-    pub fn create_collection(&mut self, name: String) {
-        self.db.entry(name).or_insert_with(Collection::new);
+    pub fn delete(&mut self, collection: String, key: String) -> Result<(), Error> {
+        self.write(WalRecord::Delete { collection, key })?;
+        Ok(())
     }
 
-    pub fn delete_collection(&mut self, name: &str) {
-        self.db.remove(name);
+    /// Creates a secondary index on `collection` (created if missing) over the dotted
+    /// `fields`; more than one field makes a compound index. A unique index is refused
+    /// if two documents already share a value.
+    pub fn create_index(&mut self, collection: String, name: String, fields: Vec<String>, unique: bool) -> Result<(), Error> {
+        if fields.is_empty() || fields.iter().any(|f| f.split('.').any(str::is_empty)) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid index fields {:?}", fields)));
+        }
+        if let Some(coll) = self.db.get(&collection) {
+            if coll.indexes().contains_key(&name) {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("Index {:?} already exists", name)));
+            }
+            if unique && let Some((existing, key)) = coll.build_index(fields.clone(), true)?.duplicate() {
+                return Err(UniqueViolation { index: name, key: key.to_string(), existing: existing.to_string() }.into());
+            }
+        }
+        self.write(WalRecord::CreateIndex { collection, name, fields, unique })?;
+        Ok(())
     }
 
-    pub fn put(&mut self, collection: String, key: String, value: Document) {
-        let coll = self.db.entry(collection).or_insert_with(Collection::new);
-        coll.put(key, value);
+    /// Creates a TTL index on `collection` (created if missing): documents expire
+    /// `expire_after` seconds after the Unix time held by the dotted `field`.
+    pub fn create_ttl_index(&mut self, collection: String, name: String, field: String, expire_after: u64) -> Result<(), Error> {
+        if field.split('.').any(str::is_empty) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid index field {:?}", field)));
+        }
+        if self.db.get(&collection).is_some_and(|coll| coll.indexes().contains_key(&name)) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("Index {:?} already exists", name)));
+        }
+        self.write(WalRecord::CreateTtlIndex { collection, name, field, expire_after })?;
+        Ok(())
     }
 
-    pub fn get(&self, collection: &str, key: &str) -> Option<&Document> {
-        self.db.get(collection).and_then(|coll| coll.get(key))
+    /// Deletes up to `limit` expired documents as a single logged write, returning how
+    /// many there were; fewer than `limit` means none are left. Expired documents are
+    /// already invisible to reads; this reclaims their space.
+    pub fn reap(&mut self, limit: usize) -> Result<usize, Error> {
+        let now = now_millis();
+        let mut expired = Vec::new();
+        for (name, coll) in &self.db {
+            if expired.len() == limit {
+                break;
+            }
+            let keys = coll.expired(now, limit - expired.len());
+            expired.extend(keys.into_iter().map(|key| WalRecord::Delete { collection: name.clone(), key }));
+        }
+        let reaped = expired.len();
+        if reaped > 0 {
+            self.write(WalRecord::Batch(expired))?;
+        }
+        Ok(reaped)
     }
 
-    pub fn delete(&mut self, collection: String, key: String) {
-        if let Some(coll) = self.db.get_mut(&collection) {
-            coll.delete(key);
-        } 
+    pub fn drop_index(&mut self, collection: &str, name: &str) -> Result<(), Error> {
+        if !self.db.get(collection).is_some_and(|coll| coll.indexes().contains_key(name)) {
+            return Err(Error::new(ErrorKind::NotFound, format!("Index {:?} not found", name)));
+        }
+        self.write(WalRecord::DropIndex { collection: collection.to_string(), name: name.to_string() })?;
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the database, as of its last write, into the empty
+    /// or missing directory `target`, from which [`backup::restore`] can rebuild it.
+    /// [`SharedDatabase::backup`] does the same without blocking other users meanwhile.
+    pub fn backup(&self, target: &Path) -> Result<Manifest, Error> {
+        self.check_backup()?;
+        backup::write(target, self.sequence, |dir| checkpoint::write(dir, self.sequence, &self.db, true))
+    }
+
+    fn check_backup(&self) -> Result<(), Error> {
+        if self.wal.is_none() {
+            return Err(Error::new(ErrorKind::Unsupported, "An in-memory database has nothing to back up"));
+        }
+        self.check_not_failed()
+    }
+
+    /// Lets the `backup` command write backups, each into a directory under `dir`.
+    pub fn set_backup_dir(&mut self, dir: PathBuf) {
+        self.backup_dir = Some(dir);
+    }
+
+    pub fn backup_dir(&self) -> Option<&Path> {
+        self.backup_dir.as_deref()
+    }
+
+    /// Persists the whole state as of the last write and empties the log, so that
+    /// opening the database replays only the writes after it. Persistent engines
+    /// flush their documents to their own files; otherwise the checkpoint holds them,
+    /// which [`SharedDatabase::checkpoint`] copies without blocking other users meanwhile.
+    pub fn checkpoint(&mut self) -> Result<Checkpoint, Error> {
+        // After a failed write the log holds one the state lacks: a checkpoint would drop it for good.
+        self.check_not_failed()?;
+        let (Some(dir), Some(wal)) = (&self.dir, self.wal.as_mut()) else {
+            return Err(Error::new(ErrorKind::Unsupported, "An in-memory database cannot be checkpointed"));
+        };
+        let documents = !self.engine.is_persistent();
+        if !documents {
+            for coll in self.db.values_mut() {
+                coll.flush()?;
+            }
+        }
+        let checkpoint = checkpoint::write(dir, self.sequence, &self.db, documents)?;
+        wal.truncate()?;
+        self.checkpointed = self.sequence;
+        Ok(checkpoint)
+    }
+
+    /// Records that a checkpoint written since holds everything up to `sequence`,
+    /// dropping that much of the log.
+    fn checkpointed_through(&mut self, sequence: u64) -> Result<(), Error> {
+        self.check_not_failed()?;
+        if let Some(wal) = self.wal.as_mut() {
+            wal.discard_through(sequence)?;
+        }
+        self.checkpointed = sequence;
+        Ok(())
+    }
+
+    /// Writes logged since the last checkpoint, i.e. replayed by the next open;
+    /// always zero for an in-memory database, which logs nothing.
+    pub fn writes_since_checkpoint(&self) -> u64 {
+        match self.wal {
+            Some(_) => self.sequence - self.checkpointed,
+            None => 0,
+        }
+    }
+
+    pub fn stats(&self) -> DatabaseStats {
+        let mut storage = EngineStats::default();
+        for coll in self.db.values() {
+            storage.merge(&coll.stats());
+        }
+        DatabaseStats {
+            collections: self.db.len(),
+            storage,
+            snapshots: self.snapshots.len(),
+            history: self.history.len(),
+        }
+    }
+
+    pub(crate) fn collection(&self, name: &str) -> Option<&Collection> {
+        self.db.get(name)
+    }
+
+    /// Whether `stored`, a version of a document of `collection`, has not expired.
+    fn is_live(&self, collection: &str, stored: &Versioned, now: u64) -> bool {
+        match self.db.get(collection) {
+            Some(coll) => coll.is_live(stored, now),
+            None => !stored.is_expired(now),
+        }
+    }
+
+    fn check_not_failed(&self) -> Result<(), Error> {
+        match &self.failed {
+            Some(reason) => Err(failed(reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs `record` (when durable) and only then applies it, returning its sequence number.
+    /// Everything that can fail is read before logging; should applying a logged record
+    /// still fail, the database refuses every later write rather than diverge from its log.
+    pub(crate) fn write(&mut self, record: WalRecord) -> Result<u64, Error> {
+        self.check_not_failed()?;
+        let oldest = self.snapshots.oldest();
+        let mut replaced = Vec::new();
+        if oldest.is_some() {
+            self.replaced(&record, &mut replaced)?;
+        }
+        let lsn = match self.wal.as_mut() {
+            Some(wal) => wal.append(&record)?,
+            None => self.sequence + 1,
+        };
+        self.sequence = lsn;
+        self.history.collect(oldest);
+        for (collection, key, previous) in replaced {
+            self.history.record(&collection, &key, lsn, previous);
+        }
+        if let Err(e) = self.apply(lsn, record) {
+            let reason = format!("write {} was logged but could not be applied: {}", lsn, e);
+            eprintln!("Database: {}; refusing further writes", reason);
+            let err = failed(&reason);
+            self.failed = Some(reason);
+            return Err(err);
+        }
+        Ok(lsn)
+    }
+
+    /// Adds to `replaced` every document version `record` is about to replace.
+    fn replaced(&self, record: &WalRecord, replaced: &mut Vec<(String, String, Option<Versioned>)>) -> Result<(), Error> {
+        match record {
+            WalRecord::Put { collection, key, .. } | WalRecord::Delete { collection, key } => {
+                replaced.push((collection.clone(), key.clone(), self.get_versioned(collection, key)?));
+            }
+            WalRecord::DeleteCollection { name } => {
+                if let Some(coll) = self.db.get(name) {
+                    for entry in coll.iter()? {
+                        let (key, stored) = entry?;
+                        replaced.push((name.clone(), key, Some(stored)));
+                    }
+                }
+            }
+            WalRecord::Batch(records) => {
+                for record in records {
+                    self.replaced(record, replaced)?;
+                }
+            }
+            WalRecord::CreateCollection { .. }
+            | WalRecord::CreateIndex { .. }
+            | WalRecord::CreateTtlIndex { .. }
+            | WalRecord::DropIndex { .. } => {}
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, lsn: u64, record: WalRecord) -> Result<(), Error> {
+        match record {
+            WalRecord::CreateCollection { name } => {
+                self.collection_mut(name)?;
+            }
+            WalRecord::DeleteCollection { name } => {
+                if let Some(mut coll) = self.db.remove(&name) {
+                    coll.destroy()?;
+                }
+            }
+            WalRecord::Put { collection, key, value, expires_at } => {
+                self.collection_mut(collection)?.put_versioned(key, Versioned::expiring(lsn, value, expires_at))?;
+            }
+            WalRecord::Delete { collection, key } => {
+                if let Some(coll) = self.db.get_mut(&collection) {
+                    coll.delete(key)?;
+                }
+            }
+            WalRecord::CreateIndex { collection, name, fields, unique } => {
+                self.collection_mut(collection)?.create_index(name, fields, unique)?;
+            }
+            WalRecord::CreateTtlIndex { collection, name, field, expire_after } => {
+                self.collection_mut(collection)?.create_ttl_index(name, field, expire_after)?;
+            }
+            WalRecord::DropIndex { collection, name } => {
+                if let Some(coll) = self.db.get_mut(&collection) {
+                    coll.drop_index(&name)?;
+                }
+            }
+            WalRecord::Batch(records) => {
+                for record in records {
+                    self.apply(lsn, record)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the named collection, creating it with the configured engine if needed.
+    fn collection_mut(&mut self, name: String) -> Result<&mut Collection, Error> {
+        if !self.db.contains_key(&name) {
+            let dir = self.dir.as_ref().map(|d| d.join(COLLECTIONS_DIR).join(dir_name(&name)));
+            let coll = Collection::open(self.engine.open(dir.as_deref())?)?;
+            self.db.insert(name.clone(), coll);
+        }
+        Ok(self.db.get_mut(&name).expect("collection just inserted"))
+    }
+}
+
+/// Fails if a put in `mode`, optionally expecting `expected`, may not replace the
+/// document of `key` currently at version `current` (`None`: absent).
+pub(crate) fn check_put(collection: &str, key: &str, current: Option<u64>, mode: WriteMode, expected: Option<u64>) -> Result<(), Error> {
+    let (collection, key) = (collection.to_string(), key.to_string());
+    match (mode, current) {
+        (WriteMode::Insert, Some(_)) => return Err(DuplicateKey { collection, key }.into()),
+        (WriteMode::Replace, None) => return Err(DocumentNotFound { collection, key }.into()),
+        _ => {}
+    }
+    if let Some(expected) = expected
+        && current.unwrap_or(0) != expected
+    {
+        return Err(VersionConflict { key, expected, actual: current }.into());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DatabaseStats {
+    pub collections: usize,
+    pub storage: EngineStats,
+    /// Open snapshots.
+    pub snapshots: usize,
+    /// Old document versions kept for them.
+    pub history: usize,
+}
+
+impl Display for DatabaseStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Collections: {}. SSTables: {}. Compactions: {}. Bloom filters: {}. Snapshots: {} open, {} old version(s) kept.",
+            self.collections, self.storage.tables, self.storage.compaction, self.storage.bloom, self.snapshots, self.history
+        )
+    }
+}
+
+/// The error of every write to a database whose state lacks a logged write.
+fn failed(reason: &str) -> Error {
+    Error::other(format!("The database refuses writes until it is reopened: {}", reason))
+}
+
+/// File-system safe directory name for a collection: anything but ASCII
+/// alphanumerics, `-` and `_` is percent-encoded.
+fn dir_name(collection: &str) -> String {
+    collection
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Cloneable, thread-safe handle to a single [`Database`] shared by every connection.
+#[derive(Debug, Clone)]
+pub struct SharedDatabase {
+    inner: Arc<Mutex<Database>>,
+    /// Held for the whole of a checkpoint, which only holds `inner` now and then.
+    checkpointing: Arc<Mutex<()>>,
+}
+
+impl SharedDatabase {
+    pub fn new(db: Database) -> SharedDatabase {
+        SharedDatabase { inner: Arc::new(Mutex::new(db)), checkpointing: Arc::new(Mutex::new(())) }
+    }
+
+    /// Locks the database for exclusive use. A panic in another holder does not
+    /// make the database unusable: every mutation is applied atomically under the lock.
+    pub fn lock(&self) -> MutexGuard<'_, Database> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// [`Database::backup`] from a snapshot, locking the database only to read a page
+    /// of documents at a time: other connections carry on while the copy is written.
+    pub fn backup(&self, target: &Path) -> Result<Manifest, Error> {
+        let (snapshot, schema) = {
+            let db = self.lock();
+            db.check_backup()?;
+            (db.snapshot(), checkpoint::schema(&db.db))
+        };
+        backup::write(target, snapshot.sequence(), |dir| {
+            let mut writer = CheckpointWriter::create(dir, snapshot.sequence(), true)?;
+            self.dump(&snapshot, &schema, &mut writer)?;
+            writer.finish()
+        })
+    }
+
+    /// [`Database::checkpoint`], copying the documents from a snapshot a page at a time
+    /// and then dropping the log up to it; writes made in the meantime stay logged.
+    /// Persistent engines only flush their buffered documents, under the lock.
+    pub fn checkpoint(&self) -> Result<Checkpoint, Error> {
+        let _checkpointing = self.checkpointing.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (dir, snapshot, schema) = {
+            let mut db = self.lock();
+            if db.engine.is_persistent() {
+                return db.checkpoint();
+            }
+            db.check_not_failed()?;
+            let (Some(dir), Some(_)) = (&db.dir, &db.wal) else {
+                return Err(Error::new(ErrorKind::Unsupported, "An in-memory database cannot be checkpointed"));
+            };
+            (dir.clone(), db.snapshot(), checkpoint::schema(&db.db))
+        };
+        let mut writer = CheckpointWriter::create(&dir, snapshot.sequence(), true)?;
+        self.dump(&snapshot, &schema, &mut writer)?;
+        let checkpoint = writer.finish()?;
+        self.lock().checkpointed_through(snapshot.sequence())?;
+        Ok(checkpoint)
+    }
+
+    /// Writes every document of the collections of `schema` as of `snapshot`.
+    fn dump(&self, snapshot: &Snapshot, schema: &[(String, Vec<WalRecord>)], writer: &mut CheckpointWriter) -> Result<(), Error> {
+        for (name, records) in schema {
+            writer.collection(records)?;
+            let mut scan = Scan { limit: Some(DUMP_PAGE), ..Scan::default() };
+            loop {
+                let page = self.lock().scan_versioned_at(snapshot, name, &scan)?;
+                let Some((last, _)) = page.last() else {
+                    break;
+                };
+                scan.range.start = Bound::Excluded(last.clone());
+                let full = page.len() == DUMP_PAGE;
+                for (key, stored) in page {
+                    writer.document(name, key, stored)?;
+                }
+                if !full {
+                    break;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::lsm::LsmOptions;
+    use crate::storage::range::KeyRange;
+    use crate::error::ErrorCode;
+    use crate::test_util::TempDir;
     use serde_json::{Map, Value};
+    use std::io::Write;
+    use std::ops::Bound;
 
     fn make_json_object(val: &str) -> Document {
         let mut obj = Map::new();
@@ -49,42 +708,419 @@ mod tests {
     #[test]
     fn create_collection_and_put_and_get() {
         let mut db = Database::initialize();
-        db.create_collection("test_coll".to_string());
+        db.create_collection("test_coll".to_string()).unwrap();
         let value = make_json_object("value1");
-        db.put("test_coll".to_string(), "key1".to_string(), value.clone());
-        assert_eq!(db.get("test_coll", "key1"), Some(&value));
+        db.put("test_coll".to_string(), "key1".to_string(), value.clone()).unwrap();
+        assert_eq!(db.get("test_coll", "key1").unwrap(), Some(value));
     }
 
     #[test]
     fn get_nonexistent_collection_returns_none() {
         let db = Database::initialize();
-        assert_eq!(db.get("missing", "key1"), None);
+        assert_eq!(db.get("missing", "key1").unwrap(), None);
     }
 
     #[test]
     fn get_nonexistent_key_returns_none() {
         let mut db = Database::initialize();
-        db.create_collection("test_coll".to_string());
-        assert_eq!(db.get("test_coll", "missing"), None);
+        db.create_collection("test_coll".to_string()).unwrap();
+        assert_eq!(db.get("test_coll", "missing").unwrap(), None);
     }
 
     #[test]
     fn delete_key_removes_value() {
         let mut db = Database::initialize();
-        db.create_collection("test_coll".to_string());
+        db.create_collection("test_coll".to_string()).unwrap();
         let value = make_json_object("value1");
-        db.put("test_coll".to_string(), "key1".to_string(), value);
-        db.delete("test_coll".to_string(), "key1".to_string());
-        assert_eq!(db.get("test_coll", "key1"), None);
+        db.put("test_coll".to_string(), "key1".to_string(), value).unwrap();
+        db.delete("test_coll".to_string(), "key1".to_string()).unwrap();
+        assert_eq!(db.get("test_coll", "key1").unwrap(), None);
     }
 
     #[test]
     fn delete_collection_always_removes() {
         let mut db = Database::initialize();
-        db.create_collection("test_coll".to_string());
+        db.create_collection("test_coll".to_string()).unwrap();
         let value = make_json_object("value1");
-        db.put("test_coll".to_string(), "key1".to_string(), value);
-        db.delete_collection("test_coll");
-        assert_eq!(db.get("test_coll", "key1"), None);
+        db.put("test_coll".to_string(), "key1".to_string(), value).unwrap();
+        db.delete_collection("test_coll").unwrap();
+        assert_eq!(db.get("test_coll", "key1").unwrap(), None);
+    }
+
+    #[test]
+    fn open_replays_logged_mutations() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.create_collection("test_coll".to_string()).unwrap();
+            db.put("test_coll".to_string(), "key1".to_string(), make_json_object("value1")).unwrap();
+            db.put("test_coll".to_string(), "key2".to_string(), make_json_object("value2")).unwrap();
+            db.delete("test_coll".to_string(), "key2".to_string()).unwrap();
+            db.create_collection("dropped".to_string()).unwrap();
+            db.delete_collection("dropped").unwrap();
+        }
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get("test_coll", "key1").unwrap(), Some(make_json_object("value1")));
+        assert_eq!(db.get("test_coll", "key2").unwrap(), None);
+        assert!(!db.db.contains_key("dropped"));
+    }
+
+    #[test]
+    fn open_survives_torn_wal_tail() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.put("test_coll".to_string(), "key1".to_string(), make_json_object("value1")).unwrap();
+        }
+        let mut wal = std::fs::OpenOptions::new().append(true).open(dir.path().join(WAL_FILE)).unwrap();
+        wal.write_all(&[0, 0, 0, 42, 1, 2]).unwrap();
+        drop(wal);
+
+        let mut db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get("test_coll", "key1").unwrap(), Some(make_json_object("value1")));
+        db.put("test_coll".to_string(), "key2".to_string(), make_json_object("value2")).unwrap();
+        drop(db);
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get("test_coll", "key2").unwrap(), Some(make_json_object("value2")));
+    }
+
+    #[test]
+    fn write_logged_but_not_applied_stops_further_writes() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.put("test_coll".to_string(), "key1".to_string(), make_json_object("value1")).unwrap();
+            // A file where the new collection's directory goes: logging works, opening it fails.
+            std::fs::write(dir.path().join(COLLECTIONS_DIR).join("blocked"), b"").unwrap();
+            assert!(db.create_collection("blocked".to_string()).is_err());
+
+            let refused = db.put("test_coll".to_string(), "key2".to_string(), make_json_object("value2")).unwrap_err();
+            assert!(refused.to_string().contains("write 2 was logged but could not be applied"));
+            assert_eq!(ErrorCode::of(&refused), ErrorCode::Internal);
+            assert!(db.checkpoint().is_err());
+            assert_eq!(db.get("test_coll", "key1").unwrap(), Some(make_json_object("value1")));
+        }
+        std::fs::remove_file(dir.path().join(COLLECTIONS_DIR).join("blocked")).unwrap();
+        let mut db = Database::open(dir.path()).unwrap();
+        assert!(db.db.contains_key("blocked"));
+        assert_eq!(db.get("test_coll", "key2").unwrap(), None);
+        db.put("test_coll".to_string(), "key2".to_string(), make_json_object("value2")).unwrap();
+    }
+
+    #[test]
+    fn shared_database_handles_see_same_data() {
+        let shared = SharedDatabase::new(Database::initialize());
+        let other = shared.clone();
+        let writer = std::thread::spawn(move || {
+            other.lock().put("test_coll".to_string(), "key1".to_string(), make_json_object("value1")).unwrap();
+        });
+        writer.join().unwrap();
+        assert_eq!(shared.lock().get("test_coll", "key1").unwrap(), Some(make_json_object("value1")));
+    }
+
+    #[test]
+    fn lsm_collections_are_stored_on_disk() {
+        let dir = TempDir::new("database");
+        let engine = EngineConfig::Lsm(LsmOptions { memtable_bytes: 128, block_bytes: 64, ..LsmOptions::default() });
+        {
+            let mut db = Database::open_with(dir.path(), engine.clone()).unwrap();
+            for i in 0..20 {
+                db.put("users/eu".to_string(), format!("key{}", i), make_json_object("value")).unwrap();
+            }
+        }
+        let coll_dir = dir.path().join(COLLECTIONS_DIR).join("users%2Feu");
+        assert!(coll_dir.join("MANIFEST").exists());
+
+        let mut db = Database::open_with(dir.path(), engine).unwrap();
+        assert_eq!(db.get("users/eu", "key7").unwrap(), Some(make_json_object("value")));
+        db.delete_collection("users/eu").unwrap();
+        assert!(!coll_dir.exists());
+    }
+
+    #[test]
+    fn scan_pages_through_collection() {
+        let mut db = Database::initialize();
+        for i in 0..5 {
+            db.put("test_coll".to_string(), format!("key{}", i), make_json_object("value")).unwrap();
+        }
+        let first = db.scan("test_coll", &Scan { limit: Some(3), ..Scan::default() }).unwrap();
+        assert_eq!(first.len(), 3);
+        let after = first.last().unwrap().0.clone();
+        let next = Scan {
+            range: KeyRange::new(Bound::Excluded(after), Bound::Unbounded),
+            limit: Some(3),
+            ..Scan::default()
+        };
+        let second: Vec<String> = db.scan("test_coll", &next).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(second, vec!["key3", "key4"]);
+        assert!(db.scan("missing", &Scan::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn indexes_are_replayed_and_maintained() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.put("people".to_string(), "ada".to_string(), serde_json::json!({"age": 36})).unwrap();
+            db.create_index("people".to_string(), "age".to_string(), vec!["age".to_string()], false).unwrap();
+            db.create_index("people".to_string(), "tmp".to_string(), vec!["name".to_string()], false).unwrap();
+            db.drop_index("people", "tmp").unwrap();
+            db.put("people".to_string(), "alan".to_string(), serde_json::json!({"age": 41})).unwrap();
+        }
+        let mut db = Database::open(dir.path()).unwrap();
+        let indexes: Vec<&String> = db.db["people"].indexes().keys().collect();
+        assert_eq!(indexes, vec!["age"]);
+        let filter = Filter::parse(&serde_json::json!({"age": {"$gt": 40}})).unwrap();
+        let found: Vec<String> = db.find("people", &filter).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(found, vec!["alan"]);
+
+        let err = db.create_index("people".to_string(), "age".to_string(), vec!["x".to_string()], false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(db.drop_index("people", "tmp").unwrap_err().kind(), ErrorKind::NotFound);
+        let err = db.create_index("people".to_string(), "bad".to_string(), vec!["a..b".to_string()], false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn unique_index_rejects_duplicates_without_logging_them() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            let users = || "users".to_string();
+            db.put(users(), "u1".to_string(), serde_json::json!({"email": "a@x"})).unwrap();
+            db.create_index(users(), "email".to_string(), vec!["email".to_string()], true).unwrap();
+            let err = db.put(users(), "u2".to_string(), serde_json::json!({"email": "a@x"})).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::UniqueViolation);
+            db.put(users(), "u1".to_string(), serde_json::json!({"email": "a@x", "name": "Ada"})).unwrap();
+
+            db.put(users(), "u3".to_string(), serde_json::json!({"alias": "b@x"})).unwrap();
+            db.put(users(), "u4".to_string(), serde_json::json!({"alias": "b@x"})).unwrap();
+            let err = db.create_index(users(), "alias".to_string(), vec!["alias".to_string()], true).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::UniqueViolation);
+        }
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get("users", "u2").unwrap(), None);
+        assert!(db.db["users"].indexes()["email"].is_unique());
+        assert!(!db.db["users"].indexes().contains_key("alias"));
+    }
+
+    #[test]
+    fn update_is_logged_as_the_resulting_document() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.put("c".to_string(), "k".to_string(), serde_json::json!({"n": 1, "tags": []})).unwrap();
+            let update = Update::parse(&serde_json::json!({"$inc": {"n": 1}, "$push": {"tags": "x"}})).unwrap();
+            let updated = db.update("c".to_string(), "k".to_string(), &update, None).unwrap();
+            assert_eq!(updated.doc, serde_json::json!({"n": 2, "tags": ["x"]}));
+            let err = db.update("c".to_string(), "missing".to_string(), &update, None).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+        }
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get("c", "k").unwrap(), Some(serde_json::json!({"n": 2, "tags": ["x"]})));
+    }
+
+    #[test]
+    fn versions_are_sequence_numbers_and_guard_conditional_writes() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.create_collection("c".to_string()).unwrap();
+            let err = db.put_if_version("c".to_string(), "k".to_string(), make_json_object("v1"), Some(3)).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::VersionConflict);
+            assert_eq!(db.put_if_version("c".to_string(), "k".to_string(), make_json_object("v1"), Some(0)).unwrap(), 2);
+            let err = db.put_if_version("c".to_string(), "k".to_string(), make_json_object("v2"), Some(0)).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::VersionConflict);
+            assert_eq!(db.put_if_version("c".to_string(), "k".to_string(), make_json_object("v2"), Some(2)).unwrap(), 3);
+
+            let update = Update::parse(&serde_json::json!({"$set": {"data": "v3"}})).unwrap();
+            let err = db.update("c".to_string(), "k".to_string(), &update, Some(2)).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::VersionConflict);
+            assert_eq!(db.update("c".to_string(), "k".to_string(), &update, Some(3)).unwrap().version, 4);
+        }
+        let mut db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get_versioned("c", "k").unwrap(), Some(Versioned::new(4, make_json_object("v3"))));
+        assert_eq!(db.put("c".to_string(), "other".to_string(), make_json_object("v")).unwrap(), 5);
+    }
+
+    #[test]
+    fn write_modes_check_whether_the_key_is_taken_before_logging() {
+        let dir = TempDir::new("database");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            let put = |db: &mut Database, value, mode| {
+                let options = PutOptions { mode, ..PutOptions::default() };
+                db.put_with("c".to_string(), "k".to_string(), make_json_object(value), options)
+            };
+            let err = put(&mut db, "v0", WriteMode::Replace).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::DocumentNotFound);
+            assert_eq!(put(&mut db, "v1", WriteMode::Insert).unwrap(), Written { version: 1, created: true });
+            let err = put(&mut db, "v2", WriteMode::Insert).unwrap_err();
+            assert_eq!(ErrorCode::of(&err), ErrorCode::DuplicateKey);
+            assert_eq!(put(&mut db, "v2", WriteMode::Replace).unwrap(), Written { version: 2, created: false });
+            assert_eq!(put(&mut db, "v3", WriteMode::Upsert).unwrap(), Written { version: 3, created: false });
+        }
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get_versioned("c", "k").unwrap(), Some(Versioned::new(3, make_json_object("v3"))));
+    }
+
+    #[test]
+    fn snapshots_see_a_consistent_past_across_collections() {
+        let mut db = Database::initialize();
+        for key in ["a", "b", "c"] {
+            db.put("left".to_string(), key.to_string(), make_json_object(key)).unwrap();
+        }
+        db.put("right".to_string(), "x".to_string(), make_json_object("x")).unwrap();
+        let snapshot = db.snapshot();
+
+        db.delete("left".to_string(), "b".to_string()).unwrap();
+        db.put("left".to_string(), "a".to_string(), make_json_object("changed")).unwrap();
+        db.put("left".to_string(), "aa".to_string(), make_json_object("new")).unwrap();
+        db.delete_collection("right").unwrap();
+
+        let keys = |docs: Vec<(String, Document)>| docs.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert_eq!(keys(db.scan_at(&snapshot, "left", &Scan::default()).unwrap()), vec!["a", "b", "c"]);
+        let scan = Scan { reverse: true, limit: Some(2), ..Scan::default() };
+        assert_eq!(keys(db.scan_at(&snapshot, "left", &scan).unwrap()), vec!["c", "b"]);
+        assert_eq!(db.get_at(&snapshot, "left", "a").unwrap(), Some(Versioned::new(1, make_json_object("a"))));
+        assert_eq!(db.get_at(&snapshot, "right", "x").unwrap(), Some(Versioned::new(4, make_json_object("x"))));
+        assert_eq!(keys(db.scan("left", &Scan::default()).unwrap()), vec!["a", "aa", "c"]);
+        assert!(db.stats().history > 0);
+
+        drop(snapshot);
+        db.put("left".to_string(), "d".to_string(), make_json_object("d")).unwrap();
+        assert_eq!((db.stats().snapshots, db.stats().history), (0, 0));
+    }
+
+    #[test]
+    fn old_versions_are_collected_up_to_the_oldest_snapshot() {
+        let mut db = Database::initialize();
+        db.put("c".to_string(), "k".to_string(), make_json_object("v1")).unwrap();
+        let first = db.snapshot();
+        db.put("c".to_string(), "k".to_string(), make_json_object("v2")).unwrap();
+        let second = db.snapshot();
+        db.put("c".to_string(), "k".to_string(), make_json_object("v3")).unwrap();
+        assert_eq!(db.stats().history, 2);
+
+        drop(first);
+        db.put("c".to_string(), "other".to_string(), make_json_object("v")).unwrap();
+        assert_eq!(db.stats().history, 2);
+        assert_eq!(db.get_at(&second, "c", "k").unwrap(), Some(Versioned::new(2, make_json_object("v2"))));
+        assert_eq!(db.get_at(&second, "c", "other").unwrap(), None);
+    }
+
+    #[test]
+    fn expiry_survives_reopen_and_reap_logs_deletes() {
+        let dir = TempDir::new("database");
+        let engine = EngineConfig::Lsm(LsmOptions { memtable_bytes: 128, ..LsmOptions::default() });
+        let ttl = |expires_at| PutOptions { expires_at: Some(expires_at), ..PutOptions::default() };
+        {
+            let mut db = Database::open_with(dir.path(), engine.clone()).unwrap();
+            for i in 0..10 {
+                db.put_with("sessions".to_string(), format!("gone{}", i), make_json_object("x"), ttl(1)).unwrap();
+            }
+            db.put_with("sessions".to_string(), "alive".to_string(), make_json_object("x"), ttl(u64::MAX)).unwrap();
+            let update = Update::parse(&serde_json::json!({"$set": {"data": "y"}})).unwrap();
+            assert_eq!(db.update("sessions".to_string(), "alive".to_string(), &update, None).unwrap().expires_at, Some(u64::MAX));
+            db.create_ttl_index("events".to_string(), "at".to_string(), "at".to_string(), 0).unwrap();
+            db.put("events".to_string(), "e1".to_string(), serde_json::json!({"at": 1})).unwrap();
+            assert_eq!(db.get("sessions", "gone3").unwrap(), None);
+            // The insert takes the place of the expired document.
+            let insert = PutOptions { mode: WriteMode::Insert, ..PutOptions::default() };
+            db.put_with("sessions".to_string(), "gone0".to_string(), make_json_object("back"), insert).unwrap();
+        }
+        let mut db = Database::open_with(dir.path(), engine).unwrap();
+        assert_eq!(db.get("sessions", "gone5").unwrap(), None);
+        assert_eq!(db.get_versioned("sessions", "alive").unwrap().unwrap().expires_at, Some(u64::MAX));
+        assert_eq!(db.get("events", "e1").unwrap(), None);
+        let before = db.snapshot().sequence();
+        assert_eq!(db.reap(4).unwrap(), 4);
+        assert_eq!(db.reap(100).unwrap(), 6);
+        assert_eq!(db.reap(100).unwrap(), 0);
+        // One logged write per call that found expired documents.
+        assert_eq!(db.snapshot().sequence(), before + 2);
+        assert_eq!(db.get("sessions", "gone0").unwrap(), Some(make_json_object("back")));
+        assert_eq!(db.scan("sessions", &Scan::default()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn checkpoint_bounds_replay_to_later_writes() {
+        for engine in [EngineConfig::InMemory, EngineConfig::Lsm(LsmOptions { memtable_bytes: 256, ..LsmOptions::default() })] {
+            let dir = TempDir::new("database");
+            let wal_len = || std::fs::metadata(dir.path().join(WAL_FILE)).unwrap().len();
+            {
+                let mut db = Database::open_with(dir.path(), engine.clone()).unwrap();
+                db.create_collection("empty".to_string()).unwrap();
+                db.create_index("users".to_string(), "email".to_string(), vec!["email".to_string()], true).unwrap();
+                db.create_ttl_index("users".to_string(), "seen".to_string(), "seen".to_string(), 60).unwrap();
+                for i in 0..20 {
+                    db.put("users".to_string(), format!("u{:02}", i), serde_json::json!({"email": i})).unwrap();
+                }
+                db.delete("users".to_string(), "u05".to_string()).unwrap();
+                let ttl = PutOptions { expires_at: Some(u64::MAX), ..PutOptions::default() };
+                db.put_with("users".to_string(), "tmp".to_string(), serde_json::json!({}), ttl).unwrap();
+
+                let checkpoint = db.checkpoint().unwrap();
+                // Persistent engines hold the documents themselves.
+                let documents = if engine.is_persistent() { 0 } else { 20 };
+                assert_eq!((checkpoint.sequence, checkpoint.collections, checkpoint.documents), (25, 2, documents));
+                assert_eq!((wal_len(), db.writes_since_checkpoint()), (0, 0));
+                db.put("users".to_string(), "u06".to_string(), serde_json::json!({"email": "new"})).unwrap();
+                db.delete("users".to_string(), "u07".to_string()).unwrap();
+            }
+            let mut db = Database::open_with(dir.path(), engine.clone()).unwrap();
+            assert_eq!(db.writes_since_checkpoint(), 2);
+            assert_eq!(db.get_versioned("users", "u06").unwrap().unwrap().version, 26);
+            assert_eq!(db.get_versioned("users", "u08").unwrap().unwrap().version, 12);
+            assert_eq!(db.get_versioned("users", "tmp").unwrap().unwrap().expires_at, Some(u64::MAX));
+            assert_eq!(db.get("users", "u05").unwrap(), None);
+            assert_eq!(db.get("users", "u07").unwrap(), None);
+            assert!(db.collection("empty").is_some());
+            let indexes = db.collection("users").unwrap().indexes();
+            assert_eq!((indexes["email"].is_unique(), indexes["seen"].expire_after()), (true, Some(60)));
+            assert!(db.put("users".to_string(), "dup".to_string(), serde_json::json!({"email": 1})).is_err());
+            assert_eq!(db.put("users".to_string(), "u09".to_string(), serde_json::json!({})).unwrap(), 28);
+
+            // A crash after the checkpoint is written but before the log is emptied.
+            let log = std::fs::read(dir.path().join(WAL_FILE)).unwrap();
+            db.checkpoint().unwrap();
+            drop(db);
+            std::fs::write(dir.path().join(WAL_FILE), log).unwrap();
+            let db = Database::open_with(dir.path(), engine.clone()).unwrap();
+            assert_eq!((db.writes_since_checkpoint(), db.snapshot().sequence()), (0, 28));
+            assert_eq!(db.get_versioned("users", "u09").unwrap().unwrap().version, 28);
+            drop(db);
+            assert_eq!(Database::open_with(dir.path(), EngineConfig::InMemory).is_err(), engine.is_persistent());
+        }
+        assert!(Database::initialize().checkpoint().is_err());
+    }
+
+    #[test]
+    fn shared_checkpoints_keep_the_writes_made_meanwhile() {
+        let dir = TempDir::new("database");
+        let shared = SharedDatabase::new(Database::open_with(dir.path(), EngineConfig::InMemory).unwrap());
+        for i in 0..2500 {
+            shared.lock().put("c".to_string(), format!("k{:04}", i), make_json_object("v")).unwrap();
+        }
+        let writer = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                for i in 0..300 {
+                    shared.lock().put("c".to_string(), format!("w{:03}", i), make_json_object("w")).unwrap();
+                }
+            })
+        };
+        let checkpoint = shared.checkpoint().unwrap();
+        writer.join().unwrap();
+        assert!(checkpoint.sequence >= 2500);
+        assert_eq!(checkpoint.documents as u64, checkpoint.sequence);
+        let sequence = shared.lock().snapshot().sequence();
+        assert_eq!(shared.lock().writes_since_checkpoint(), sequence - checkpoint.sequence);
+        drop(shared);
+
+        let db = Database::open_with(dir.path(), EngineConfig::InMemory).unwrap();
+        assert_eq!(db.snapshot().sequence(), 2800);
+        assert_eq!(db.scan("c", &Scan::default()).unwrap().len(), 2800);
+        assert_eq!(db.get_versioned("c", "w299").unwrap().unwrap().version, 2800);
     }
 }
@@ -0,0 +1,469 @@
+use serde_json::{json, Value};
+use std::error;
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+
+/// Broad kind of a failure, for clients that only need to know how to react. Sent
+/// along with the code, so a client still knows the category of a code it does not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    NotFound,
+    Conflict,
+    InvalidArgument,
+    Parse,
+    ConstraintViolation,
+    Overloaded,
+    Internal,
+}
+
+impl ErrorCategory {
+    pub fn code(self) -> u8 {
+        match self {
+            ErrorCategory::Internal => 0,
+            ErrorCategory::NotFound => 1,
+            ErrorCategory::Conflict => 2,
+            ErrorCategory::InvalidArgument => 3,
+            ErrorCategory::Parse => 4,
+            ErrorCategory::ConstraintViolation => 5,
+            ErrorCategory::Overloaded => 6,
+        }
+    }
+
+    pub fn from_code(code: u8) -> ErrorCategory {
+        match code {
+            1 => ErrorCategory::NotFound,
+            2 => ErrorCategory::Conflict,
+            3 => ErrorCategory::InvalidArgument,
+            4 => ErrorCategory::Parse,
+            5 => ErrorCategory::ConstraintViolation,
+            6 => ErrorCategory::Overloaded,
+            _ => ErrorCategory::Internal,
+        }
+    }
+}
+
+impl Display for ErrorCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCategory::NotFound => "not found",
+            ErrorCategory::Conflict => "conflict",
+            ErrorCategory::InvalidArgument => "invalid argument",
+            ErrorCategory::Parse => "parse error",
+            ErrorCategory::ConstraintViolation => "constraint violation",
+            ErrorCategory::Overloaded => "server overloaded",
+            ErrorCategory::Internal => "internal",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Machine-readable reason carried by a `Response::Failure`. Codes never change meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Anything without a more specific code.
+    Internal,
+    /// Every connection slot is taken.
+    ServerBusy,
+    /// Client and server share no protocol version, or a message broke the one agreed on.
+    UnsupportedProtocol,
+    /// A write would give two documents the same value in a unique index.
+    UniqueViolation,
+    /// A conditional write found the document at another version.
+    VersionConflict,
+    /// An insert found a document already stored at its key.
+    DuplicateKey,
+    /// A replace or update found no document at its key.
+    DocumentNotFound,
+    /// A named object other than a document, e.g. an index, does not exist.
+    NotFound,
+    /// A named object other than a document, e.g. an index, already exists.
+    AlreadyExists,
+    /// A command argument is out of range or does not fit the command.
+    InvalidArgument,
+    /// A document, filter, update or command could not be parsed.
+    ParseError,
+    /// The command is not available on this server, e.g. backups of an in-memory database.
+    Unsupported,
+    /// A message was over the receiver's maximum size; the server closes the connection after saying so.
+    MessageTooLarge,
+}
+
+impl ErrorCode {
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::ServerBusy => 2,
+            ErrorCode::UnsupportedProtocol => 3,
+            ErrorCode::UniqueViolation => 11,
+            ErrorCode::VersionConflict => 12,
+            ErrorCode::DuplicateKey => 13,
+            ErrorCode::DocumentNotFound => 14,
+            ErrorCode::NotFound => 15,
+            ErrorCode::AlreadyExists => 16,
+            ErrorCode::InvalidArgument => 21,
+            ErrorCode::ParseError => 22,
+            ErrorCode::Unsupported => 23,
+            ErrorCode::MessageTooLarge => 24,
+        }
+    }
+
+    /// Unknown codes map to `Internal`, so older clients still get a failure.
+    pub fn from_code(code: u16) -> ErrorCode {
+        match code {
+            2 => ErrorCode::ServerBusy,
+            3 => ErrorCode::UnsupportedProtocol,
+            11 => ErrorCode::UniqueViolation,
+            12 => ErrorCode::VersionConflict,
+            13 => ErrorCode::DuplicateKey,
+            14 => ErrorCode::DocumentNotFound,
+            15 => ErrorCode::NotFound,
+            16 => ErrorCode::AlreadyExists,
+            21 => ErrorCode::InvalidArgument,
+            22 => ErrorCode::ParseError,
+            23 => ErrorCode::Unsupported,
+            24 => ErrorCode::MessageTooLarge,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    pub fn category(self) -> ErrorCategory {
+        match self {
+            ErrorCode::Internal => ErrorCategory::Internal,
+            ErrorCode::ServerBusy => ErrorCategory::Overloaded,
+            ErrorCode::UnsupportedProtocol
+            | ErrorCode::InvalidArgument
+            | ErrorCode::Unsupported
+            | ErrorCode::MessageTooLarge => ErrorCategory::InvalidArgument,
+            ErrorCode::UniqueViolation => ErrorCategory::ConstraintViolation,
+            ErrorCode::VersionConflict | ErrorCode::DuplicateKey | ErrorCode::AlreadyExists => ErrorCategory::Conflict,
+            ErrorCode::DocumentNotFound | ErrorCode::NotFound => ErrorCategory::NotFound,
+            ErrorCode::ParseError => ErrorCategory::Parse,
+        }
+    }
+
+    /// The code for an error returned by the database: that of its error type if
+    /// it has one, else one following its `io::ErrorKind`. Data that does not read
+    /// back without a [`ParseError`] is damaged, not the client's mistake: `Internal`.
+    pub fn of(err: &io::Error) -> ErrorCode {
+        match err.get_ref() {
+            Some(inner) if let Some(aborted) = inner.downcast_ref::<BatchAborted>() => return ErrorCode::of(&aborted.source),
+            Some(inner) if inner.is::<UniqueViolation>() => return ErrorCode::UniqueViolation,
+            Some(inner) if inner.is::<VersionConflict>() => return ErrorCode::VersionConflict,
+            Some(inner) if inner.is::<DuplicateKey>() => return ErrorCode::DuplicateKey,
+            Some(inner) if inner.is::<DocumentNotFound>() => return ErrorCode::DocumentNotFound,
+            Some(inner) if inner.is::<ParseError>() => return ErrorCode::ParseError,
+            _ => {}
+        }
+        match err.kind() {
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            io::ErrorKind::InvalidInput => ErrorCode::InvalidArgument,
+            io::ErrorKind::Unsupported => ErrorCode::Unsupported,
+            io::ErrorKind::FileTooLarge => ErrorCode::MessageTooLarge,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorCode::Internal => "INTERNAL",
+            ErrorCode::ServerBusy => "SERVER_BUSY",
+            ErrorCode::UnsupportedProtocol => "UNSUPPORTED_PROTOCOL",
+            ErrorCode::UniqueViolation => "UNIQUE_VIOLATION",
+            ErrorCode::VersionConflict => "VERSION_CONFLICT",
+            ErrorCode::DuplicateKey => "DUPLICATE_KEY",
+            ErrorCode::DocumentNotFound => "DOCUMENT_NOT_FOUND",
+            ErrorCode::NotFound => "NOT_FOUND",
+            ErrorCode::AlreadyExists => "ALREADY_EXISTS",
+            ErrorCode::InvalidArgument => "INVALID_ARGUMENT",
+            ErrorCode::ParseError => "PARSE_ERROR",
+            ErrorCode::Unsupported => "UNSUPPORTED",
+            ErrorCode::MessageTooLarge => "MESSAGE_TOO_LARGE",
+        };
+        write!(f, "{} ({})", name, self.code())
+    }
+}
+
+/// A failed command as reported to the client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub code: ErrorCode,
+    /// The category the server gave the code, kept even when this build does not know the code.
+    pub category: ErrorCategory,
+    pub message: String,
+    /// Fields of the error for clients to act on, e.g. the versions of a conflict.
+    pub details: Option<Value>,
+}
+
+impl Failure {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Failure {
+        Failure { code, category: code.category(), message: message.into(), details: None }
+    }
+
+    /// The failure for an error returned by the database.
+    pub fn of(err: &io::Error) -> Failure {
+        Failure {
+            details: details(err),
+            ..Failure::new(ErrorCode::of(err), format!("Error processing command: {}", err))
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}]: {}", self.code, self.category, self.message)?;
+        if let Some(details) = &self.details {
+            write!(f, " {}", details)?;
+        }
+        Ok(())
+    }
+}
+
+/// The fields of the error types that have any.
+fn details(err: &io::Error) -> Option<Value> {
+    let inner = err.get_ref()?;
+    if let Some(aborted) = inner.downcast_ref::<BatchAborted>() {
+        let mut details = json!({"operation": aborted.index});
+        if let (Some(Value::Object(source)), Value::Object(fields)) = (self::details(&aborted.source), &mut details) {
+            fields.extend(source);
+        }
+        return Some(details);
+    }
+    if let Some(violation) = inner.downcast_ref::<UniqueViolation>() {
+        return Some(json!({"index": violation.index, "key": violation.key, "existing": violation.existing}));
+    }
+    if let Some(conflict) = inner.downcast_ref::<VersionConflict>() {
+        return Some(json!({"key": conflict.key, "expected": conflict.expected, "actual": conflict.actual}));
+    }
+    if let Some(duplicate) = inner.downcast_ref::<DuplicateKey>() {
+        return Some(json!({"collection": duplicate.collection, "key": duplicate.key}));
+    }
+    let missing = inner.downcast_ref::<DocumentNotFound>()?;
+    Some(json!({"collection": missing.collection, "key": missing.key}))
+}
+
+/// `key` would share the values of unique index `index` with the document at `existing`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UniqueViolation {
+    pub index: String,
+    pub key: String,
+    pub existing: String,
+}
+
+impl Display for UniqueViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unique index {:?} violated: {:?} duplicates the value of {:?}",
+            self.index, self.key, self.existing
+        )
+    }
+}
+
+impl error::Error for UniqueViolation {}
+
+impl From<UniqueViolation> for io::Error {
+    fn from(violation: UniqueViolation) -> io::Error {
+        io::Error::new(io::ErrorKind::AlreadyExists, violation)
+    }
+}
+
+/// `key` was expected at version `expected` (0: absent) but is at `actual`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionConflict {
+    pub key: String,
+    pub expected: u64,
+    pub actual: Option<u64>,
+}
+
+impl Display for VersionConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.actual {
+            Some(actual) => write!(f, "Version conflict on {:?}: expected {}, found {}", self.key, self.expected, actual),
+            None => write!(f, "Version conflict on {:?}: expected {}, but it does not exist", self.key, self.expected),
+        }
+    }
+}
+
+impl error::Error for VersionConflict {}
+
+impl From<VersionConflict> for io::Error {
+    fn from(conflict: VersionConflict) -> io::Error {
+        io::Error::other(conflict)
+    }
+}
+
+/// An insert found `key` already present in `collection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateKey {
+    pub collection: String,
+    pub key: String,
+}
+
+impl Display for DuplicateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Document {:?} already exists in {:?}", self.key, self.collection)
+    }
+}
+
+impl error::Error for DuplicateKey {}
+
+impl From<DuplicateKey> for io::Error {
+    fn from(duplicate: DuplicateKey) -> io::Error {
+        io::Error::new(io::ErrorKind::AlreadyExists, duplicate)
+    }
+}
+
+/// A write that needs an existing document found none at `key` in `collection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentNotFound {
+    pub collection: String,
+    pub key: String,
+}
+
+impl Display for DocumentNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Document {:?} not found in {:?}", self.key, self.collection)
+    }
+}
+
+impl error::Error for DocumentNotFound {}
+
+impl From<DocumentNotFound> for io::Error {
+    fn from(missing: DocumentNotFound) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, missing)
+    }
+}
+
+/// Input from the client that does not parse, e.g. a document that is not JSON.
+/// Returned as an `InvalidData` error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(message: impl Into<String>) -> ParseError {
+        ParseError { message: message.into() }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl From<serde_json::Error> for ParseError {
+    fn from(err: serde_json::Error) -> ParseError {
+        ParseError::new(err.to_string())
+    }
+}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Operation `index` of an atomic batch failed with `source`, so none of it was applied.
+#[derive(Debug)]
+pub struct BatchAborted {
+    pub index: usize,
+    pub source: io::Error,
+}
+
+impl Display for BatchAborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Batch aborted at operation {}: {}", self.index, self.source)
+    }
+}
+
+impl error::Error for BatchAborted {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<BatchAborted> for io::Error {
+    fn from(aborted: BatchAborted) -> io::Error {
+        io::Error::new(aborted.source.kind(), aborted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_roundtrip() {
+        for code in [
+            ErrorCode::Internal,
+            ErrorCode::ServerBusy,
+            ErrorCode::UnsupportedProtocol,
+            ErrorCode::UniqueViolation,
+            ErrorCode::VersionConflict,
+            ErrorCode::DuplicateKey,
+            ErrorCode::DocumentNotFound,
+            ErrorCode::NotFound,
+            ErrorCode::AlreadyExists,
+            ErrorCode::InvalidArgument,
+            ErrorCode::ParseError,
+            ErrorCode::Unsupported,
+            ErrorCode::MessageTooLarge,
+        ] {
+            assert_eq!(ErrorCode::from_code(code.code()), code);
+            assert_eq!(ErrorCategory::from_code(code.category().code()), code.category());
+        }
+        assert_eq!(ErrorCode::from_code(999), ErrorCode::Internal);
+        assert_eq!(ErrorCategory::from_code(99), ErrorCategory::Internal);
+    }
+
+    #[test]
+    fn errors_without_a_type_are_coded_by_kind() {
+        let code = |kind, msg: &str| ErrorCode::of(&io::Error::new(kind, msg.to_string()));
+        assert_eq!(code(io::ErrorKind::NotFound, "Index \"n\" not found"), ErrorCode::NotFound);
+        assert_eq!(code(io::ErrorKind::AlreadyExists, "Index \"n\" already exists"), ErrorCode::AlreadyExists);
+        assert_eq!(code(io::ErrorKind::InvalidInput, "limit must be positive"), ErrorCode::InvalidArgument);
+        let unparsed = serde_json::from_str::<Value>("{").unwrap_err();
+        assert_eq!(ErrorCode::of(&ParseError::from(unparsed).into()), ErrorCode::ParseError);
+        // Stored data that does not read back is damaged, not a bad request.
+        assert_eq!(code(io::ErrorKind::InvalidData, "Corrupt SSTable"), ErrorCode::Internal);
+        assert_eq!(code(io::ErrorKind::UnexpectedEof, "Truncated record"), ErrorCode::Internal);
+        assert_eq!(code(io::ErrorKind::Unsupported, "in-memory"), ErrorCode::Unsupported);
+        assert_eq!(ErrorCode::InvalidArgument.category(), ErrorCategory::InvalidArgument);
+    }
+
+    #[test]
+    fn failures_carry_the_fields_of_their_error() {
+        let conflict: io::Error = VersionConflict { key: "k".to_string(), expected: 3, actual: None }.into();
+        let failure = Failure::of(&conflict);
+        assert_eq!((failure.code, failure.category), (ErrorCode::VersionConflict, ErrorCategory::Conflict));
+        assert_eq!(failure.details, Some(json!({"key": "k", "expected": 3, "actual": null})));
+
+        let aborted: io::Error = BatchAborted { index: 1, source: conflict }.into();
+        assert_eq!(Failure::of(&aborted).details, Some(json!({"operation": 1, "key": "k", "expected": 3, "actual": null})));
+        assert_eq!(Failure::of(&io::Error::other("boom")).details, None);
+    }
+
+    #[test]
+    fn unique_violations_keep_their_code_through_io_errors() {
+        let violation = UniqueViolation { index: "email".to_string(), key: "b".to_string(), existing: "a".to_string() };
+        assert_eq!(ErrorCode::of(&violation.into()), ErrorCode::UniqueViolation);
+        assert_eq!(ErrorCode::of(&io::Error::other("boom")), ErrorCode::Internal);
+    }
+
+    #[test]
+    fn aborted_batches_keep_the_code_of_the_failed_operation() {
+        let duplicate = DuplicateKey { collection: "c".to_string(), key: "k".to_string() };
+        let aborted: io::Error = BatchAborted { index: 2, source: duplicate.into() }.into();
+        assert_eq!(ErrorCode::of(&aborted), ErrorCode::DuplicateKey);
+        assert_eq!(aborted.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(aborted.to_string(), r#"Batch aborted at operation 2: Document "k" already exists in "c""#);
+    }
+}
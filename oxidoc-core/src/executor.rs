@@ -1,40 +1,358 @@
-use crate::database::Database;
+use crate::backup;
+use crate::collection::{now_millis, Document, Scan};
+use crate::backup::Manifest;
+use crate::database::{Database, PutOptions, SharedDatabase, WriteMode};
+use crate::error::{BatchAborted, ParseError};
+use crate::query::Filter;
 use crate::response::Response;
-use oxidoc_cli::commands::commands::CliCommand;
-use std::io::Error;
+use crate::update::Update;
+use crate::snapshot::Snapshot;
+use crate::storage::range::KeyRange;
+use crate::transaction::Transaction;
+use crate::transfer::{from_record, to_record, RecordReader};
+use oxidoc_cli::commands::commands::{BatchOperation, CliCommand, ImportCommandArgs, InsertCommandArgs, PatchFormat, ScanCommandArgs};
+use std::io::{Error, ErrorKind};
+use std::ops::Bound;
+use std::path::PathBuf;
 
+/// Per-connection state: the transaction in progress and the snapshot reads
+/// outside of it use, if any.
+#[derive(Debug, Default)]
+pub struct Session {
+    transaction: Option<Transaction>,
+    snapshot: Option<Snapshot>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session::default()
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+}
+
+/// Runs `command` on its own, outside of any session.
 pub fn execute_command(command: CliCommand, db: &mut Database) -> Result<Response, Error> {
+    execute(command, db, &mut Session::new())
+}
+
+/// Runs `command` like [`execute`], holding the lock of `db` only as long as it must:
+/// backups and checkpoints are copied a page at a time, so other connections are not
+/// held up meanwhile.
+pub fn execute_shared(command: CliCommand, db: &SharedDatabase, session: &mut Session) -> Result<Response, Error> {
     match command {
-        CliCommand::Insert(args) => {
-            let clone = args.clone();
-            let val = serde_json::from_str(&args.value)?;
-            db.put(args.collection, args.key, val);
-            Ok(Response::Success(format!("Insert success. {:?}", clone)))
+        CliCommand::Backup(args) => {
+            let target = backup_target(&db.lock(), &args.target)?;
+            Ok(backed_up(&args.target, db.backup(&target)?))
         }
-        CliCommand::Get(args) => Ok(Response::Doc(db.get(&args.collection, &args.key).cloned())),
+        CliCommand::Checkpoint => Ok(Response::Success(db.checkpoint()?.to_string())),
+        command => execute(command, &mut db.lock(), session),
+    }
+}
+
+/// Runs `command` for the connection owning `session`. While a transaction is in
+/// progress, writes are buffered in it and reads see them; otherwise reads use the
+/// session's snapshot when one is open.
+pub fn execute(command: CliCommand, db: &mut Database, session: &mut Session) -> Result<Response, Error> {
+    match command {
+        CliCommand::Insert(args) => put_from_args(args, WriteMode::Insert, db, session),
+        CliCommand::Replace(args) => put_from_args(args, WriteMode::Replace, db, session),
+        CliCommand::Upsert(args) => put_from_args(args, WriteMode::Upsert, db, session),
+        CliCommand::Get(args) => Ok(Response::Doc(match (session.transaction.as_mut(), &session.snapshot) {
+            (Some(tx), _) => tx.get(db, &args.collection, &args.key)?,
+            (None, Some(snapshot)) => db.get_at(snapshot, &args.collection, &args.key)?,
+            (None, None) => db.get_versioned(&args.collection, &args.key)?,
+        })),
         CliCommand::Delete(args) => {
-            db.delete(args.collection, args.key);
+            match session.transaction.as_mut() {
+                Some(tx) => tx.delete(db, args.collection, args.key)?,
+                None => db.delete(args.collection, args.key)?,
+            }
             Ok(Response::Ack)
         }
-        CliCommand::Status => Ok(Response::Success(
-            "Database status: Operational.".to_string(),
-        )),
+        CliCommand::Status => Ok(Response::Success(format!(
+            "Database status: Operational. {}",
+            db.stats()
+        ))),
         CliCommand::DeleteCollection(args) => {
+            outside_transaction(session, "delete-collection")?;
             let clone = args.clone();
-            db.delete_collection(&args.name);
+            db.delete_collection(&args.name)?;
             Ok(Response::Success(format!(
                 "Collection {:?} deleted.",
                 clone
             )))
         },
         CliCommand::CreateCollection(args) => {
+            outside_transaction(session, "create-collection")?;
             let clone = args.clone();
-            db.create_collection(args.name);
+            db.create_collection(args.name)?;
             Ok(Response::Success(format!(
                 "Collection {:?} created.",
                 clone
             )))
         }
+        CliCommand::Scan(args) => {
+            Ok(Response::Docs(scan(db, session, &args.collection, &scan_from_args(&args))?))
+        }
+        CliCommand::Find(args) => {
+            let filter = Filter::parse(&serde_json::from_str(&args.filter).map_err(ParseError::from)?)?;
+            Ok(Response::Docs(match (&session.transaction, &session.snapshot) {
+                (Some(tx), _) => tx.find(db, &args.collection, &filter)?,
+                (None, Some(snapshot)) => db.find_at(snapshot, &args.collection, &filter)?,
+                (None, None) => db.find(&args.collection, &filter)?,
+            }))
+        }
+        CliCommand::CreateIndex(args) => {
+            outside_transaction(session, "create-index")?;
+            let name = args.name.unwrap_or_else(|| args.fields.join("_"));
+            let kind = match (args.ttl, args.unique) {
+                (Some(seconds), _) => {
+                    let [field] = args.fields.as_slice() else {
+                        return Err(Error::new(ErrorKind::InvalidInput, "A TTL index covers exactly one field"));
+                    };
+                    db.create_ttl_index(args.collection.clone(), name.clone(), field.clone(), seconds)?;
+                    format!("TTL index ({}s)", seconds)
+                }
+                (None, unique) => {
+                    db.create_index(args.collection.clone(), name.clone(), args.fields.clone(), unique)?;
+                    if unique { "Unique index" } else { "Index" }.to_string()
+                }
+            };
+            Ok(Response::Success(format!(
+                "{} {:?} created on {:?} ({}).",
+                kind,
+                name,
+                args.collection,
+                args.fields.join(", ")
+            )))
+        }
+        CliCommand::Update(args) => {
+            let patch = serde_json::from_str(&args.patch).map_err(ParseError::from)?;
+            let update = match args.format {
+                None => Update::parse(&patch)?,
+                Some(PatchFormat::JsonPatch) => Update::json_patch(&patch)?,
+                Some(PatchFormat::MergePatch) => Update::merge_patch(&patch)?,
+                Some(PatchFormat::Operators) => Update::operators(&patch)?,
+            };
+            Ok(Response::Doc(Some(match session.transaction.as_mut() {
+                Some(tx) => tx.update(db, args.collection, args.key, &update, args.if_version)?,
+                None => db.update(args.collection, args.key, &update, args.if_version)?,
+            })))
+        }
+        CliCommand::DropIndex(args) => {
+            outside_transaction(session, "drop-index")?;
+            db.drop_index(&args.collection, &args.name)?;
+            Ok(Response::Success(format!("Index {:?} dropped.", args.name)))
+        }
+        CliCommand::Begin => {
+            outside_transaction(session, "begin")?;
+            session.transaction = Some(Transaction::new());
+            Ok(Response::Success("Transaction started.".to_string()))
+        }
+        CliCommand::Commit => {
+            let tx = session.transaction.take().ok_or_else(no_transaction)?;
+            let writes = tx.len();
+            Ok(Response::Success(match tx.commit(db)? {
+                Some(version) => format!("Transaction committed: {} write(s) at version {}.", writes, version),
+                None => "Transaction committed: no writes.".to_string(),
+            }))
+        }
+        CliCommand::Abort => {
+            let tx = session.transaction.take().ok_or_else(no_transaction)?;
+            Ok(Response::Success(format!("Transaction aborted: {} write(s) discarded.", tx.len())))
+        }
+        CliCommand::OpenSnapshot => {
+            let snapshot = db.snapshot();
+            let message = format!("Snapshot opened at version {}.", snapshot.sequence());
+            session.snapshot = Some(snapshot);
+            Ok(Response::Success(message))
+        }
+        CliCommand::ReleaseSnapshot => match session.snapshot.take() {
+            Some(snapshot) => Ok(Response::Success(format!("Snapshot at version {} released.", snapshot.sequence()))),
+            None => Err(Error::new(ErrorKind::InvalidInput, "No snapshot open")),
+        },
+        CliCommand::Batch(args) => {
+            let operations: Vec<BatchOperation> = serde_json::from_str(&args.ops).map_err(ParseError::from)?;
+            if args.atomic {
+                outside_transaction(session, "batch --atomic")?;
+                return batch_atomically(operations, db);
+            }
+            Ok(Response::Batch(
+                operations
+                    .into_iter()
+                    .map(|op| execute(op.into_command(), db, session).unwrap_or_else(|e| Response::from_error(&e)))
+                    .collect(),
+            ))
+        }
+        CliCommand::Import(args) => import(args, db, session),
+        CliCommand::Export(args) => {
+            let page = Scan {
+                range: KeyRange::new(args.after.map_or(Bound::Unbounded, Bound::Excluded), Bound::Unbounded),
+                reverse: false,
+                limit: Some(args.limit),
+            };
+            let records = scan(db, session, &args.collection, &page)?
+                .into_iter()
+                .map(|(key, doc)| Ok((key.clone(), to_record(key, doc, args.key_field.as_deref())?)))
+                .collect::<Result<_, Error>>()?;
+            Ok(Response::Docs(records))
+        }
+        CliCommand::Backup(args) => {
+            let manifest = db.backup(&backup_target(db, &args.target)?)?;
+            Ok(backed_up(&args.target, manifest))
+        }
+        CliCommand::Checkpoint => Ok(Response::Success(db.checkpoint()?.to_string())),
+    }
+}
+
+/// Where the backup a client named `name` goes: under the database's backup directory.
+fn backup_target(db: &Database, name: &str) -> Result<PathBuf, Error> {
+    match db.backup_dir() {
+        Some(root) => backup::resolve(root, name),
+        None => Err(Error::new(ErrorKind::Unsupported, "Backups are disabled: the server has no backup directory")),
+    }
+}
+
+fn backed_up(name: &str, manifest: Manifest) -> Response {
+    Response::Success(format!(
+        "Backup at version {} written to {:?}: {} file(s), {} bytes.",
+        manifest.sequence,
+        name,
+        manifest.files.len(),
+        manifest.bytes()
+    ))
+}
+
+fn scan(db: &Database, session: &Session, collection: &str, scan: &Scan) -> Result<Vec<(String, Document)>, Error> {
+    match (&session.transaction, &session.snapshot) {
+        (Some(tx), _) => tx.scan(db, collection, scan),
+        (None, Some(snapshot)) => db.scan_at(snapshot, collection, scan),
+        (None, None) => db.scan(collection, scan),
+    }
+}
+
+/// Upserts every record of `args.data`: into the session's transaction when one
+/// is in progress, otherwise all at one version.
+fn import(args: ImportCommandArgs, db: &mut Database, session: &mut Session) -> Result<Response, Error> {
+    let mut own = None;
+    let tx = match session.transaction.as_mut() {
+        Some(tx) => tx,
+        None => own.insert(Transaction::new()),
+    };
+    let mut imported = 0;
+    for (index, record) in RecordReader::new(args.data.as_bytes(), args.format).enumerate() {
+        let (key, doc) = record
+            .and_then(|record| from_record(record, args.key_field.as_deref()))
+            .map_err(|e| ParseError::new(format!("Record {}: {}", index, e)))?;
+        tx.put(db, args.collection.clone(), key, doc, PutOptions::default())?;
+        imported += 1;
+    }
+    let version = match own {
+        Some(tx) => tx.commit(db)?.map_or_else(|| "nothing written".to_string(), |v| format!("version {}", v)),
+        None => "pending commit".to_string(),
+    };
+    Ok(Response::Success(format!("Imported {} document(s) into {:?} ({}).", imported, args.collection, version)))
+}
+
+/// Stages every operation in one transaction and commits it, so either all of
+/// them are applied at the same version or, on the first failure, none.
+fn batch_atomically(operations: Vec<BatchOperation>, db: &mut Database) -> Result<Response, Error> {
+    let mut tx = Transaction::new();
+    let mut staged = Vec::with_capacity(operations.len());
+    for (index, operation) in operations.into_iter().enumerate() {
+        let outcome = match operation {
+            BatchOperation::Insert(write) => stage_put(&mut tx, db, write.into(), WriteMode::Insert).map(Some),
+            BatchOperation::Replace(write) => stage_put(&mut tx, db, write.into(), WriteMode::Replace).map(Some),
+            BatchOperation::Upsert(write) => stage_put(&mut tx, db, write.into(), WriteMode::Upsert).map(Some),
+            BatchOperation::Delete(args) => tx.delete(db, args.collection, args.key).map(|()| None),
+        };
+        staged.push(outcome.map_err(|source| BatchAborted { index, source })?);
+    }
+    let version = format!("version {}", tx.commit(db)?.unwrap_or_default());
+    Ok(Response::Batch(
+        staged
+            .into_iter()
+            .map(|outcome| match outcome {
+                Some((args, created)) => written(&args, created, &version),
+                None => Response::Ack,
+            })
+            .collect(),
+    ))
+}
+
+fn stage_put(tx: &mut Transaction, db: &Database, args: InsertCommandArgs, mode: WriteMode) -> Result<(InsertCommandArgs, bool), Error> {
+    let value = serde_json::from_str(&args.value).map_err(ParseError::from)?;
+    let created = tx.put(db, args.collection.clone(), args.key.clone(), value, put_options(&args, mode))?;
+    Ok((args, created))
+}
+
+fn put_from_args(args: InsertCommandArgs, mode: WriteMode, db: &mut Database, session: &mut Session) -> Result<Response, Error> {
+    let value = serde_json::from_str(&args.value).map_err(ParseError::from)?;
+    let options = put_options(&args, mode);
+    let (created, version) = match session.transaction.as_mut() {
+        Some(tx) => {
+            let created = tx.put(db, args.collection.clone(), args.key.clone(), value, options)?;
+            (created, "pending commit".to_string())
+        }
+        None => {
+            let written = db.put_with(args.collection.clone(), args.key.clone(), value, options)?;
+            (written.created, format!("version {}", written.version))
+        }
+    };
+    Ok(written(&args, created, &version))
+}
+
+fn put_options(args: &InsertCommandArgs, mode: WriteMode) -> PutOptions {
+    PutOptions {
+        mode,
+        expected: args.if_version,
+        expires_at: args.ttl.map(|seconds| now_millis().saturating_add(seconds.saturating_mul(1000))),
+    }
+}
+
+fn written(args: &InsertCommandArgs, created: bool, version: &str) -> Response {
+    Response::Success(format!(
+        "Document {:?} {} in {:?} ({}).",
+        args.key,
+        if created { "created" } else { "modified" },
+        args.collection,
+        version
+    ))
+}
+
+/// Schema changes are not transactional, and transactions do not nest.
+fn outside_transaction(session: &Session, command: &str) -> Result<(), Error> {
+    match session.transaction {
+        Some(_) => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is not allowed inside a transaction", command),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn no_transaction() -> Error {
+    Error::new(ErrorKind::InvalidInput, "No transaction in progress")
+}
+
+fn scan_from_args(args: &ScanCommandArgs) -> Scan {
+    let start = match (&args.start, &args.after) {
+        (Some(start), _) => Bound::Included(start.clone()),
+        (None, Some(after)) => Bound::Excluded(after.clone()),
+        (None, None) => Bound::Unbounded,
+    };
+    let end = args.end.clone().map_or(Bound::Unbounded, Bound::Excluded);
+    let mut range = KeyRange::new(start, end);
+    if let Some(prefix) = &args.prefix {
+        range = range.intersect(KeyRange::prefix(prefix));
+    }
+    Scan {
+        range,
+        reverse: args.reverse,
+        limit: Some(args.limit),
     }
 }
 
@@ -42,8 +360,9 @@ pub fn execute_command(command: CliCommand, db: &mut Database) -> Result<Respons
 mod tests {
     use super::*;
     use crate::database::Database;
-    use oxidoc_cli::commands::commands::{DeleteCommandArgs, GetCommandArgs, InsertCommandArgs};
-    use crate::collection::Document;
+    use oxidoc_cli::commands::commands::{BatchCommandArgs, CreateIndexCommandArgs, DataFormat, ExportCommandArgs, DeleteCommandArgs, FindCommandArgs, GetCommandArgs, InsertCommandArgs, ScanCommandArgs, UpdateCommandArgs};
+    use crate::collection::{Document, Versioned};
+    use crate::error::ErrorCode;
 
     #[test]
     fn insert_returns_success_response() {
@@ -52,6 +371,8 @@ mod tests {
             collection: "test_coll".to_string(),
             key: "key1".to_string(),
             value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
         let response = execute_command(insert_cmd, &mut db);
         assert!(matches!(response, Ok(Response::Success(_))));
@@ -64,16 +385,11 @@ mod tests {
             collection: "test_coll".to_string(),
             key: "key1".to_string(),
             value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
         let response = execute_command(insert_cmd, &mut db);
-        let expected = format!(
-            "Insert success. {:?}",
-            InsertCommandArgs {
-                collection: "test_coll".to_string(),
-                key: "key1".to_string(),
-                value: r#"{"field":"value1"}"#.to_string(),
-            }
-        );
+        let expected = r#"Document "key1" created in "test_coll" (version 1)."#.to_string();
         assert_eq!(response.unwrap(), Response::Success(expected));
     }
 
@@ -85,6 +401,8 @@ mod tests {
             collection: "test_coll".to_string(),
             key: "key1".to_string(),
             value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
         execute_command(insert_cmd, &mut db).unwrap();
         // Poi recupera
@@ -104,6 +422,8 @@ mod tests {
             collection: "test_coll".to_string(),
             key: "key1".to_string(),
             value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
         execute_command(insert_cmd, &mut db).unwrap();
         // Poi recupera
@@ -114,7 +434,7 @@ mod tests {
         let response = execute_command(get_cmd, &mut db);
         let expected: Document = serde_json::from_str(r#"{"field":"value1"}"#).unwrap();
         match response {
-            Ok(Response::Doc(Some(doc))) => assert_eq!(doc, expected),
+            Ok(Response::Doc(Some(stored))) => assert_eq!(stored, Versioned::new(1, expected)),
             _ => panic!("Expected Doc response with value for Get"),
         }
     }
@@ -127,6 +447,8 @@ mod tests {
             collection: "test_coll".to_string(),
             key: "key1".to_string(),
             value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
         execute_command(insert_cmd, &mut db).unwrap();
         // Cancella
@@ -146,6 +468,8 @@ mod tests {
             collection: "test_coll".to_string(),
             key: "key1".to_string(),
             value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
         execute_command(insert_cmd, &mut db).unwrap();
         // Cancella
@@ -162,4 +486,337 @@ mod tests {
         let response = execute_command(get_cmd, &mut db);
         assert!(matches!(response, Ok(Response::Doc(None))));
     }
+
+    #[test]
+    fn status_reports_storage_stats() {
+        let mut db = Database::initialize();
+        db.create_collection("test_coll".to_string()).unwrap();
+        let response = execute_command(CliCommand::Status, &mut db).unwrap();
+        assert_eq!(
+            response,
+            Response::Success(
+                "Database status: Operational. Collections: 1. SSTables: 0. \
+                 Compactions: 0 completed (0 bytes written, 0 tombstones dropped). \
+                 Bloom filters: 0 probes skipped, 0 probes passed (0 false positives). \
+                 Snapshots: 0 open, 0 old version(s) kept."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn scan_returns_page_of_documents() {
+        let mut db = Database::initialize();
+        for key in ["a:1", "a:2", "a:3", "b:1"] {
+            db.put("test_coll".to_string(), key.to_string(), serde_json::json!({"k": key})).unwrap();
+        }
+        let scan = CliCommand::Scan(ScanCommandArgs {
+            collection: "test_coll".to_string(),
+            start: None,
+            after: Some("a:1".to_string()),
+            end: None,
+            prefix: Some("a:".to_string()),
+            limit: 10,
+            reverse: true,
+        });
+        let response = execute_command(scan, &mut db).unwrap();
+        assert_eq!(
+            response,
+            Response::Docs(vec![
+                ("a:3".to_string(), serde_json::json!({"k": "a:3"})),
+                ("a:2".to_string(), serde_json::json!({"k": "a:2"})),
+            ])
+        );
+    }
+
+    #[test]
+    fn find_returns_matching_documents() {
+        let mut db = Database::initialize();
+        for (key, age) in [("ada", 36), ("alan", 41), ("grace", 85)] {
+            db.put("people".to_string(), key.to_string(), serde_json::json!({"age": age})).unwrap();
+        }
+        let find = CliCommand::Find(FindCommandArgs {
+            collection: "people".to_string(),
+            filter: r#"{"age": {"$gt": 40}}"#.to_string(),
+        });
+        let response = execute_command(find, &mut db).unwrap();
+        assert_eq!(
+            response,
+            Response::Docs(vec![
+                ("alan".to_string(), serde_json::json!({"age": 41})),
+                ("grace".to_string(), serde_json::json!({"age": 85})),
+            ])
+        );
+    }
+
+    #[test]
+    fn find_rejects_invalid_filter() {
+        let mut db = Database::initialize();
+        let find = CliCommand::Find(FindCommandArgs {
+            collection: "people".to_string(),
+            filter: r#"{"age": {"$between": [1, 2]}}"#.to_string(),
+        });
+        assert_eq!(ErrorCode::of(&execute_command(find, &mut db).unwrap_err()), ErrorCode::InvalidArgument);
+        let find = CliCommand::Find(FindCommandArgs { collection: "people".to_string(), filter: "{".to_string() });
+        assert_eq!(ErrorCode::of(&execute_command(find, &mut db).unwrap_err()), ErrorCode::ParseError);
+    }
+
+    #[test]
+    fn create_index_defaults_name_and_is_used_by_find() {
+        let mut db = Database::initialize();
+        db.put("people".to_string(), "ada".to_string(), serde_json::json!({"city": "London", "age": 36})).unwrap();
+        let create = CliCommand::CreateIndex(CreateIndexCommandArgs {
+            collection: "people".to_string(),
+            fields: vec!["city".to_string(), "age".to_string()],
+            name: None,
+            unique: false,
+            ttl: None,
+        });
+        let response = execute_command(create.clone(), &mut db).unwrap();
+        assert_eq!(response, Response::Success("Index \"city_age\" created on \"people\" (city, age).".to_string()));
+        assert!(execute_command(create, &mut db).is_err());
+
+        let find = CliCommand::Find(FindCommandArgs {
+            collection: "people".to_string(),
+            filter: r#"{"city": "London", "age": {"$lt": 40}}"#.to_string(),
+        });
+        let response = execute_command(find, &mut db).unwrap();
+        assert_eq!(response, Response::Docs(vec![("ada".to_string(), serde_json::json!({"city": "London", "age": 36}))]));
+    }
+
+    #[test]
+    fn update_returns_the_modified_document() {
+        let mut db = Database::initialize();
+        db.put("c".to_string(), "k".to_string(), serde_json::json!({"a": 1, "b": 2})).unwrap();
+        let update = |patch: &str, format| CliCommand::Update(UpdateCommandArgs {
+            collection: "c".to_string(),
+            key: "k".to_string(),
+            patch: patch.to_string(),
+            format,
+            if_version: None,
+        });
+        let response = execute_command(update(r#"[{"op": "remove", "path": "/a"}]"#, None), &mut db).unwrap();
+        assert_eq!(response, Response::Doc(Some(Versioned::new(2, serde_json::json!({"b": 2})))));
+        // Forced to a merge patch, an operator-looking object is just a member.
+        let response = execute_command(update(r#"{"$set": 1}"#, Some(PatchFormat::MergePatch)), &mut db).unwrap();
+        assert_eq!(response, Response::Doc(Some(Versioned::new(3, serde_json::json!({"b": 2, "$set": 1})))));
+        assert!(execute_command(update("[]", Some(PatchFormat::Operators)), &mut db).is_err());
+    }
+
+    #[test]
+    fn write_with_stale_version_is_rejected_with_its_code() {
+        let mut db = Database::initialize();
+        let args = |value: &str, if_version| InsertCommandArgs {
+            collection: "c".to_string(),
+            key: "k".to_string(),
+            value: value.to_string(),
+            if_version,
+            ttl: None,
+        };
+        execute_command(CliCommand::Upsert(args(r#"{"n": 1}"#, Some(0))), &mut db).unwrap();
+        let err = execute_command(CliCommand::Upsert(args(r#"{"n": 2}"#, Some(0))), &mut db).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::VersionConflict);
+        execute_command(CliCommand::Replace(args(r#"{"n": 2}"#, Some(1))), &mut db).unwrap();
+        assert_eq!(db.get_versioned("c", "k").unwrap(), Some(Versioned::new(2, serde_json::json!({"n": 2}))));
+    }
+
+    #[test]
+    fn write_modes_report_created_or_modified() {
+        let mut db = Database::initialize();
+        let args = InsertCommandArgs {
+            collection: "c".to_string(),
+            key: "k".to_string(),
+            value: r#"{"n": 1}"#.to_string(),
+            if_version: None,
+            ttl: None,
+        };
+        let err = execute_command(CliCommand::Replace(args.clone()), &mut db).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::DocumentNotFound);
+        let response = execute_command(CliCommand::Insert(args.clone()), &mut db).unwrap();
+        assert_eq!(response, Response::Success(r#"Document "k" created in "c" (version 1)."#.to_string()));
+        let err = execute_command(CliCommand::Insert(args.clone()), &mut db).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::DuplicateKey);
+        let response = execute_command(CliCommand::Replace(args.clone()), &mut db).unwrap();
+        assert_eq!(response, Response::Success(r#"Document "k" modified in "c" (version 2)."#.to_string()));
+        let response = execute_command(CliCommand::Upsert(args), &mut db).unwrap();
+        assert_eq!(response, Response::Success(r#"Document "k" modified in "c" (version 3)."#.to_string()));
+    }
+
+    #[test]
+    fn session_buffers_writes_until_commit() {
+        let mut db = Database::initialize();
+        let mut session = Session::new();
+        let insert = |key: &str| CliCommand::Insert(InsertCommandArgs {
+            collection: "c".to_string(),
+            key: key.to_string(),
+            value: r#"{"n": 1}"#.to_string(),
+            if_version: None,
+            ttl: None,
+        });
+        let get = |key: &str| CliCommand::Get(GetCommandArgs { collection: "c".to_string(), key: key.to_string() });
+
+        execute(CliCommand::Begin, &mut db, &mut session).unwrap();
+        assert!(execute(CliCommand::Begin, &mut db, &mut session).is_err());
+        let response = execute(insert("a"), &mut db, &mut session).unwrap();
+        assert_eq!(response, Response::Success(r#"Document "a" created in "c" (pending commit)."#.to_string()));
+        assert!(matches!(execute(get("a"), &mut db, &mut session).unwrap(), Response::Doc(Some(_))));
+        assert_eq!(execute_command(get("a"), &mut db).unwrap(), Response::Doc(None));
+        let response = execute(CliCommand::Commit, &mut db, &mut session).unwrap();
+        assert_eq!(response, Response::Success("Transaction committed: 1 write(s) at version 1.".to_string()));
+        assert!(!session.in_transaction());
+        assert!(matches!(execute_command(get("a"), &mut db).unwrap(), Response::Doc(Some(_))));
+
+        execute(CliCommand::Begin, &mut db, &mut session).unwrap();
+        execute(insert("b"), &mut db, &mut session).unwrap();
+        execute(CliCommand::Abort, &mut db, &mut session).unwrap();
+        assert_eq!(execute_command(get("b"), &mut db).unwrap(), Response::Doc(None));
+        assert!(execute(CliCommand::Commit, &mut db, &mut session).is_err());
+    }
+
+    #[test]
+    fn snapshot_reads_ignore_later_writes() {
+        let mut db = Database::initialize();
+        let mut session = Session::new();
+        db.put("c".to_string(), "a".to_string(), serde_json::json!(1)).unwrap();
+        let response = execute(CliCommand::OpenSnapshot, &mut db, &mut session).unwrap();
+        assert_eq!(response, Response::Success("Snapshot opened at version 1.".to_string()));
+        db.put("c".to_string(), "a".to_string(), serde_json::json!(2)).unwrap();
+        db.put("c".to_string(), "b".to_string(), serde_json::json!(3)).unwrap();
+
+        let get = CliCommand::Get(GetCommandArgs { collection: "c".to_string(), key: "a".to_string() });
+        let response = execute(get.clone(), &mut db, &mut session).unwrap();
+        assert_eq!(response, Response::Doc(Some(Versioned::new(1, serde_json::json!(1)))));
+        let find = CliCommand::Find(FindCommandArgs { collection: "c".to_string(), filter: "{}".to_string() });
+        let response = execute(find, &mut db, &mut session).unwrap();
+        assert_eq!(response, Response::Docs(vec![("a".to_string(), serde_json::json!(1))]));
+
+        execute(CliCommand::ReleaseSnapshot, &mut db, &mut session).unwrap();
+        let response = execute(get, &mut db, &mut session).unwrap();
+        assert_eq!(response, Response::Doc(Some(Versioned::new(2, serde_json::json!(2)))));
+        assert!(execute(CliCommand::ReleaseSnapshot, &mut db, &mut session).is_err());
+    }
+
+    #[test]
+    fn insert_with_ttl_and_ttl_indexes() {
+        let mut db = Database::initialize();
+        let insert = |key: &str, ttl| CliCommand::Insert(InsertCommandArgs {
+            collection: "cache".to_string(),
+            key: key.to_string(),
+            value: "{}".to_string(),
+            if_version: None,
+            ttl,
+        });
+        let get = |key: &str| CliCommand::Get(GetCommandArgs { collection: "cache".to_string(), key: key.to_string() });
+        execute_command(insert("now", Some(0)), &mut db).unwrap();
+        execute_command(insert("later", Some(3600)), &mut db).unwrap();
+        assert_eq!(execute_command(get("now"), &mut db).unwrap(), Response::Doc(None));
+        assert!(matches!(execute_command(get("later"), &mut db).unwrap(), Response::Doc(Some(_))));
+
+        let create = |fields: &[&str]| CliCommand::CreateIndex(CreateIndexCommandArgs {
+            collection: "cache".to_string(),
+            fields: fields.iter().map(|f| f.to_string()).collect(),
+            name: None,
+            unique: false,
+            ttl: Some(60),
+        });
+        assert!(execute_command(create(&["a", "b"]), &mut db).is_err());
+        let response = execute_command(create(&["created"]), &mut db).unwrap();
+        assert_eq!(response, Response::Success(r#"TTL index (60s) "created" created on "cache" (created)."#.to_string()));
+    }
+
+    #[test]
+    fn batch_reports_each_operation_and_atomic_batches_apply_all_or_nothing() {
+        let mut db = Database::initialize();
+        let batch = |ops: serde_json::Value, atomic| CliCommand::Batch(BatchCommandArgs { ops: ops.to_string(), atomic });
+        let get = |key: &str| CliCommand::Get(GetCommandArgs { collection: "c".to_string(), key: key.to_string() });
+
+        let response = execute_command(batch(serde_json::json!([
+            {"op": "insert", "collection": "c", "key": "a", "value": {"n": 1}},
+            {"op": "insert", "collection": "c", "key": "a", "value": {"n": 2}},
+            {"op": "delete", "collection": "c", "key": "a"},
+        ]), false), &mut db).unwrap();
+        let Response::Batch(results) = response else { panic!("not a batch: {:?}", response) };
+        assert_eq!(results[0], Response::Success(r#"Document "a" created in "c" (version 1)."#.to_string()));
+        assert!(matches!(&results[1], Response::Failure(failure) if failure.code == ErrorCode::DuplicateKey));
+        assert_eq!(results[2], Response::Ack);
+
+        let response = execute_command(batch(serde_json::json!([
+            {"op": "upsert", "collection": "c", "key": "a", "value": {}},
+            {"op": "upsert", "collection": "d", "key": "b", "value": {}},
+        ]), true), &mut db).unwrap();
+        assert_eq!(response, Response::Batch(vec![
+            Response::Success(r#"Document "a" created in "c" (version 3)."#.to_string()),
+            Response::Success(r#"Document "b" created in "d" (version 3)."#.to_string()),
+        ]));
+
+        let err = execute_command(batch(serde_json::json!([
+            {"op": "delete", "collection": "c", "key": "a"},
+            {"op": "replace", "collection": "c", "key": "missing", "value": {}},
+        ]), true), &mut db).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::DocumentNotFound);
+        assert!(err.to_string().starts_with("Batch aborted at operation 1:"));
+        assert!(matches!(execute_command(get("a"), &mut db).unwrap(), Response::Doc(Some(_))));
+
+        let mut session = Session::new();
+        execute(CliCommand::Begin, &mut db, &mut session).unwrap();
+        assert!(execute(batch(serde_json::json!([]), true), &mut db, &mut session).is_err());
+        execute(batch(serde_json::json!([{"op": "delete", "collection": "c", "key": "a"}]), false), &mut db, &mut session).unwrap();
+        assert!(matches!(execute_command(get("a"), &mut db).unwrap(), Response::Doc(Some(_))));
+        execute(CliCommand::Commit, &mut db, &mut session).unwrap();
+        assert_eq!(execute_command(get("a"), &mut db).unwrap(), Response::Doc(None));
+    }
+
+    #[test]
+    fn import_upserts_records_and_export_pages_them_back() {
+        let mut db = Database::initialize();
+        let import = |data: &str, key_field: Option<&str>| CliCommand::Import(ImportCommandArgs {
+            collection: "people".to_string(),
+            data: data.to_string(),
+            format: DataFormat::Ndjson,
+            key_field: key_field.map(str::to_string),
+        });
+        let export = |after: Option<&str>, key_field: Option<&str>| CliCommand::Export(ExportCommandArgs {
+            collection: "people".to_string(),
+            after: after.map(str::to_string),
+            limit: 2,
+            key_field: key_field.map(str::to_string),
+        });
+
+        let response = execute_command(import("{\"_key\": \"a\", \"doc\": {\"n\": 1}}\n\n{\"_key\": \"b\", \"doc\": {}}\n", None), &mut db).unwrap();
+        assert_eq!(response, Response::Success(r#"Imported 2 document(s) into "people" (version 1)."#.to_string()));
+        execute_command(import(r#"{"id": "c", "n": 3}"#, Some("id")), &mut db).unwrap();
+        let err = execute_command(import("{\"id\": \"d\"}\n{\"n\": 4}", Some("id")), &mut db).unwrap_err();
+        assert!(err.to_string().starts_with("Record 1: Invalid record"), "{}", err);
+        assert_eq!(ErrorCode::of(&err), ErrorCode::ParseError);
+        assert_eq!(db.get("people", "d").unwrap(), None);
+
+        assert_eq!(execute_command(export(None, None), &mut db).unwrap(), Response::Docs(vec![
+            ("a".to_string(), serde_json::json!({"_key": "a", "doc": {"n": 1}})),
+            ("b".to_string(), serde_json::json!({"_key": "b", "doc": {}})),
+        ]));
+        assert_eq!(execute_command(export(Some("b"), Some("id")), &mut db).unwrap(), Response::Docs(vec![
+            ("c".to_string(), serde_json::json!({"id": "c", "n": 3})),
+        ]));
+        assert_eq!(execute_command(export(Some("c"), None), &mut db).unwrap(), Response::Docs(Vec::new()));
+    }
+
+    #[test]
+    fn backups_are_only_written_under_the_backup_directory() {
+        use crate::test_util::TempDir;
+        use oxidoc_cli::commands::commands::BackupCommandArgs;
+        let dir = TempDir::new("executor");
+        let backup = |target: &str| CliCommand::Backup(BackupCommandArgs { target: target.to_string() });
+        let mut db = Database::open(dir.path().join("data")).unwrap();
+        db.put("c".to_string(), "k".to_string(), serde_json::json!(1)).unwrap();
+        let disabled = execute_command(backup("nightly"), &mut db).unwrap_err();
+        assert_eq!(disabled.kind(), ErrorKind::Unsupported);
+
+        db.set_backup_dir(dir.path().join("backups"));
+        for escape in ["../data", "/tmp/elsewhere", "nightly/../../data", "", "."] {
+            let refused = execute_command(backup(escape), &mut db).unwrap_err();
+            assert_eq!(ErrorCode::of(&refused), ErrorCode::InvalidArgument, "{:?}", escape);
+        }
+        execute_command(backup("nightly/monday"), &mut db).unwrap();
+        assert!(dir.path().join("backups/nightly/monday").join(crate::backup::MANIFEST).exists());
+    }
 }
@@ -0,0 +1,460 @@
+use crate::collection::Document;
+use crate::query::{resolve, Filter, Op};
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+
+/// One component of an index key. Values of different JSON types sort by type
+/// (`Missing < Null < Bool < Number < String < Other`), so a range of one type
+/// stays contiguous.
+#[derive(Debug, Clone)]
+pub enum IndexValue {
+    /// The field is absent (or an empty array); keeps the document reachable
+    /// through the other components of a compound index.
+    Missing,
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// Objects and nested arrays, as canonical JSON. Never searched by the planner.
+    Other(String),
+    /// Sorts after everything; only used to build range bounds.
+    Max,
+}
+
+impl IndexValue {
+    /// Index values for a scalar operand; `None` for values the planner cannot search.
+    fn from_scalar(value: &Value) -> Option<IndexValue> {
+        match value {
+            Value::Null => Some(IndexValue::Null),
+            Value::Bool(b) => Some(IndexValue::Bool(*b)),
+            // `0.0` and `-0.0` are equal to the query engine, so they share a key.
+            Value::Number(n) => n.as_f64().map(|f| IndexValue::Number(if f == 0.0 { 0.0 } else { f })),
+            Value::String(s) => Some(IndexValue::String(s.clone())),
+            Value::Array(_) | Value::Object(_) => None,
+        }
+    }
+
+    /// Every key component a document field contributes: one per element for arrays.
+    fn from_field(value: Option<&Value>) -> Vec<IndexValue> {
+        let to_value = |v: &Value| IndexValue::from_scalar(v).unwrap_or_else(|| IndexValue::Other(v.to_string()));
+        match value {
+            None => vec![IndexValue::Missing],
+            Some(Value::Array(items)) if items.is_empty() => vec![IndexValue::Missing],
+            Some(Value::Array(items)) => items.iter().map(to_value).collect(),
+            Some(value) => vec![to_value(value)],
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            IndexValue::Missing => 0,
+            IndexValue::Null => 1,
+            IndexValue::Bool(_) => 2,
+            IndexValue::Number(_) => 3,
+            IndexValue::String(_) => 4,
+            IndexValue::Other(_) => 5,
+            IndexValue::Max => 6,
+        }
+    }
+}
+
+impl Ord for IndexValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexValue::Bool(a), IndexValue::Bool(b)) => a.cmp(b),
+            (IndexValue::Number(a), IndexValue::Number(b)) => a.total_cmp(b),
+            (IndexValue::String(a), IndexValue::String(b)) | (IndexValue::Other(a), IndexValue::Other(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+
+impl PartialOrd for IndexValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for IndexValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for IndexValue {}
+
+/// Secondary index over one or more dotted field paths.
+///
+/// Entries are `(values, document key)` pairs kept in a sorted set, so lookups
+/// on a prefix of the fields are range scans. A document whose field holds an
+/// array gets one entry per element (per combination, for compound indexes).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Index {
+    fields: Vec<String>,
+    entries: BTreeSet<(Vec<IndexValue>, String)>,
+    /// Fields that held an array in some document since the index was built.
+    multikey: Vec<bool>,
+    unique: bool,
+    /// Seconds after the timestamp in its field that a document expires (TTL index).
+    expire_after: Option<u64>,
+}
+
+impl Index {
+    pub fn new(fields: Vec<String>, unique: bool) -> Index {
+        let multikey = vec![false; fields.len()];
+        Index { fields, entries: BTreeSet::new(), multikey, unique, expire_after: None }
+    }
+
+    /// A TTL index: documents expire `seconds` after the Unix time (in seconds) held
+    /// by `field`, or by its earliest element for arrays. Other values never expire.
+    pub fn ttl(field: String, seconds: u64) -> Index {
+        Index { expire_after: Some(seconds), ..Index::new(vec![field], false) }
+    }
+
+    pub fn expire_after(&self) -> Option<u64> {
+        self.expire_after
+    }
+
+    /// Whether `doc` has expired at Unix time `now` (seconds) according to this TTL index.
+    pub fn has_expired(&self, doc: &Document, now: f64) -> bool {
+        let Some(seconds) = self.expire_after else {
+            return false;
+        };
+        IndexValue::from_field(resolve(doc, &self.fields[0]))
+            .iter()
+            .any(|value| matches!(value, IndexValue::Number(at) if at + seconds as f64 <= now))
+    }
+
+    /// Keys of up to `limit` documents expired at Unix time `now` (seconds) according
+    /// to this TTL index, the longest expired first.
+    pub fn expired(&self, now: f64, limit: usize) -> BTreeSet<String> {
+        let Some(seconds) = self.expire_after else {
+            return BTreeSet::new();
+        };
+        let start = (vec![IndexValue::Number(f64::NEG_INFINITY)], String::new());
+        let end = (vec![IndexValue::Number(now - seconds as f64), IndexValue::Max], String::new());
+        self.entries.range(start..end).take(limit).map(|(_, key)| key.clone()).collect()
+    }
+
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Whether no two documents may share a value (every element, for arrays).
+    /// Documents missing one of the fields are not constrained.
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+
+    /// Another document that already holds one of the values `doc` would get at `key`.
+    pub fn conflict(&self, key: &str, doc: &Document) -> Option<&str> {
+        self.conflicts(key, doc).into_iter().next()
+    }
+
+    /// Every other document that already holds one of the values `doc` would get at `key`.
+    pub fn conflicts(&self, key: &str, doc: &Document) -> BTreeSet<&str> {
+        let mut found = BTreeSet::new();
+        for values in self.keys(doc).into_iter().filter(|values| constrained(values)) {
+            let holders = self.entries.range((values.clone(), String::new())..).take_while(|(other, _)| *other == values);
+            found.extend(holders.map(|(_, other)| other.as_str()).filter(|other| *other != key));
+        }
+        found
+    }
+
+    /// Two distinct documents sharing a value, if any.
+    pub fn duplicate(&self) -> Option<(&str, &str)> {
+        let mut entries = self.entries.iter().filter(|(values, _)| constrained(values));
+        let mut previous = entries.next()?;
+        for entry in entries {
+            if entry.0 == previous.0 && entry.1 != previous.1 {
+                return Some((&previous.1, &entry.1));
+            }
+            previous = entry;
+        }
+        None
+    }
+
+    /// Number of entries (not documents: multikey documents count once per element).
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, key: &str, doc: &Document) {
+        for (i, field) in self.fields.iter().enumerate() {
+            if matches!(resolve(doc, field), Some(Value::Array(_))) {
+                self.multikey[i] = true;
+            }
+        }
+        for values in self.keys(doc) {
+            self.entries.insert((values, key.to_string()));
+        }
+    }
+
+    pub fn remove(&mut self, key: &str, doc: &Document) {
+        for values in self.keys(doc) {
+            self.entries.remove(&(values, key.to_string()));
+        }
+    }
+
+    /// Cartesian product of the values of every indexed field.
+    fn keys(&self, doc: &Document) -> BTreeSet<Vec<IndexValue>> {
+        self.fields.iter().fold(BTreeSet::from([Vec::new()]), |keys, field| {
+            extend_each(keys, &IndexValue::from_field(resolve(doc, field)))
+        })
+    }
+
+    /// Keys of documents whose leading fields equal one of `prefixes` and whose
+    /// next field lies strictly between `lower` and `upper`.
+    fn lookup(&self, prefixes: &BTreeSet<Vec<IndexValue>>, lower: Option<&IndexValue>, upper: Option<&IndexValue>) -> BTreeSet<String> {
+        let mut keys = BTreeSet::new();
+        for prefix in prefixes {
+            let with = |tail: &[IndexValue]| (prefix.iter().chain(tail).cloned().collect::<Vec<_>>(), String::new());
+            let start = match lower {
+                Some(v) => with(&[v.clone(), IndexValue::Max]),
+                None => with(&[]),
+            };
+            let end = with(&[upper.cloned().unwrap_or(IndexValue::Max)]);
+            if start >= end {
+                continue;
+            }
+            let range = self.entries.range((Bound::Included(start), Bound::Excluded(end)));
+            keys.extend(range.map(|(_, key)| key.clone()));
+        }
+        keys
+    }
+}
+
+fn constrained(values: &[IndexValue]) -> bool {
+    !values.contains(&IndexValue::Missing)
+}
+
+/// What a conjunction of predicates says about one field.
+#[derive(Debug, Default)]
+struct FieldBounds {
+    eq: Option<BTreeSet<IndexValue>>,
+    lower: Option<IndexValue>,
+    upper: Option<IndexValue>,
+}
+
+/// Keys of the documents that may match `filter`, found through the best index in
+/// `indexes`, or `None` when no index applies and the collection must be scanned.
+///
+/// The result is a superset: callers still evaluate the filter on every candidate.
+pub fn candidates(indexes: &BTreeMap<String, Index>, filter: &Filter) -> Option<BTreeSet<String>> {
+    match filter {
+        Filter::Or(branches) => branches.iter().try_fold(BTreeSet::new(), |mut keys, branch| {
+            keys.extend(candidates(indexes, branch)?);
+            Some(keys)
+        }),
+        _ => {
+            let mut fields = Vec::new();
+            let mut alternatives = Vec::new();
+            conjuncts(filter, &mut fields, &mut alternatives);
+            best_index(indexes, &fields)
+                .or_else(|| alternatives.into_iter().find_map(|or| candidates(indexes, or)))
+        }
+    }
+}
+
+/// Flattens nested `$and`s into field predicates and the `$or`s among them.
+fn conjuncts<'a>(filter: &'a Filter, fields: &mut Vec<(&'a str, &'a Op)>, alternatives: &mut Vec<&'a Filter>) {
+    match filter {
+        Filter::And(filters) => filters.iter().for_each(|f| conjuncts(f, fields, alternatives)),
+        Filter::Or(_) => alternatives.push(filter),
+        Filter::Field { path, op } => fields.push((path, op)),
+    }
+}
+
+fn best_index(indexes: &BTreeMap<String, Index>, predicates: &[(&str, &Op)]) -> Option<BTreeSet<String>> {
+    let mut best: Option<(&Index, usize, bool)> = None;
+    for index in indexes.values() {
+        let bounds = |i: usize| field_bounds(predicates, &index.fields[i], index.multikey[i]);
+        let equalities = (0..index.fields.len()).take_while(|&i| bounds(i).eq.is_some()).count();
+        let ranged = equalities < index.fields.len() && {
+            let next = bounds(equalities);
+            next.lower.is_some() || next.upper.is_some()
+        };
+        let better = best.is_none_or(|(_, e, r)| (equalities, ranged) > (e, r));
+        if (equalities > 0 || ranged) && better {
+            best = Some((index, equalities, ranged));
+        }
+    }
+
+    let (index, equalities, ranged) = best?;
+    let mut prefixes = BTreeSet::from([Vec::new()]);
+    for i in 0..equalities {
+        let values = field_bounds(predicates, &index.fields[i], index.multikey[i]).eq.expect("equality field");
+        prefixes = extend_each(prefixes, &values);
+    }
+    let next = if ranged {
+        field_bounds(predicates, &index.fields[equalities], index.multikey[equalities])
+    } else {
+        FieldBounds::default()
+    };
+    Some(index.lookup(&prefixes, next.lower.as_ref(), next.upper.as_ref()))
+}
+
+/// Combines the predicates on `path`. On multikey fields different predicates may
+/// be satisfied by different array elements, so only the first one is used.
+fn field_bounds(predicates: &[(&str, &Op)], path: &str, multikey: bool) -> FieldBounds {
+    let mut bounds = FieldBounds::default();
+    for (_, op) in predicates.iter().filter(|(p, _)| *p == path) {
+        let mut used = true;
+        match op {
+            Op::Eq(v) => match IndexValue::from_scalar(v) {
+                Some(v) => narrow_eq(&mut bounds.eq, BTreeSet::from([v])),
+                None => used = false,
+            },
+            Op::In(values) => match values.iter().map(IndexValue::from_scalar).collect::<Option<BTreeSet<_>>>() {
+                Some(values) => narrow_eq(&mut bounds.eq, values),
+                None => used = false,
+            },
+            Op::Gt(v) => match IndexValue::from_scalar(v) {
+                Some(v) => bounds.lower = bounds.lower.take().max(Some(v)),
+                None => used = false,
+            },
+            Op::Lt(v) => match IndexValue::from_scalar(v) {
+                Some(v) => bounds.upper = Some(bounds.upper.take().map_or(v.clone(), |u| u.min(v))),
+                None => used = false,
+            },
+            Op::Ne(_) | Op::Exists(_) => used = false,
+        }
+        if used && multikey {
+            break;
+        }
+    }
+    bounds
+}
+
+/// Every prefix extended with every value.
+fn extend_each<'a>(
+    prefixes: BTreeSet<Vec<IndexValue>>,
+    values: impl IntoIterator<Item = &'a IndexValue> + Clone,
+) -> BTreeSet<Vec<IndexValue>> {
+    let mut keys = BTreeSet::new();
+    for prefix in prefixes {
+        for value in values.clone() {
+            let mut key = prefix.clone();
+            key.push(value.clone());
+            keys.insert(key);
+        }
+    }
+    keys
+}
+
+fn narrow_eq(eq: &mut Option<BTreeSet<IndexValue>>, values: BTreeSet<IndexValue>) {
+    *eq = Some(match eq.take() {
+        Some(current) => current.intersection(&values).cloned().collect(),
+        None => values,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn people() -> Vec<(&'static str, Document)> {
+        vec![
+            ("ada", json!({"age": 36, "city": "London", "tags": ["math", "engines"]})),
+            ("alan", json!({"age": 41, "city": "London", "tags": ["math"]})),
+            ("grace", json!({"age": 85, "city": "New York"})),
+            ("linus", json!({"age": 54.0, "city": "Portland", "tags": []})),
+        ]
+    }
+
+    fn indexes(defs: &[(&str, &[&str])]) -> BTreeMap<String, Index> {
+        defs.iter()
+            .map(|(name, fields)| {
+                let mut index = Index::new(fields.iter().map(|f| f.to_string()).collect(), false);
+                for (key, doc) in people() {
+                    index.insert(key, &doc);
+                }
+                (name.to_string(), index)
+            })
+            .collect()
+    }
+
+    fn plan(indexes: &BTreeMap<String, Index>, filter: Value) -> Option<Vec<String>> {
+        candidates(indexes, &Filter::parse(&filter).unwrap()).map(|keys| keys.into_iter().collect())
+    }
+
+    #[test]
+    fn equality_and_in_use_single_field_index() {
+        let idx = indexes(&[("age", &["age"])]);
+        assert_eq!(plan(&idx, json!({"age": 41})), Some(vec!["alan".to_string()]));
+        assert_eq!(plan(&idx, json!({"age": {"$in": [36, 54, 99]}})), Some(vec!["ada".to_string(), "linus".to_string()]));
+        assert_eq!(plan(&idx, json!({"city": "London"})), None);
+    }
+
+    #[test]
+    fn ranges_stay_within_bounds() {
+        let idx = indexes(&[("age", &["age"])]);
+        assert_eq!(plan(&idx, json!({"age": {"$gt": 40, "$lt": 60}})), Some(vec!["alan".to_string(), "linus".to_string()]));
+        assert_eq!(plan(&idx, json!({"age": {"$gt": 60, "$lt": 40}})), Some(vec![]));
+        assert_eq!(plan(&idx, json!({"age": {"$gt": 80}})), Some(vec!["grace".to_string()]));
+    }
+
+    #[test]
+    fn compound_index_serves_prefix_equality_and_range() {
+        let idx = indexes(&[("city_age", &["city", "age"])]);
+        assert_eq!(plan(&idx, json!({"city": "London"})), Some(vec!["ada".to_string(), "alan".to_string()]));
+        assert_eq!(plan(&idx, json!({"city": "London", "age": {"$gt": 40}})), Some(vec!["alan".to_string()]));
+        assert_eq!(plan(&idx, json!({"age": 36})), None);
+    }
+
+    #[test]
+    fn planner_prefers_index_with_more_equalities() {
+        let idx = indexes(&[("age", &["age"]), ("city_age", &["city", "age"])]);
+        let keys = plan(&idx, json!({"$and": [{"age": 36}, {"city": "London"}]}));
+        assert_eq!(keys, Some(vec!["ada".to_string()]));
+    }
+
+    #[test]
+    fn or_needs_an_index_for_every_branch() {
+        let idx = indexes(&[("age", &["age"])]);
+        assert_eq!(plan(&idx, json!({"$or": [{"age": 36}, {"age": 85}]})), Some(vec!["ada".to_string(), "grace".to_string()]));
+        assert_eq!(plan(&idx, json!({"$or": [{"age": 36}, {"city": "Rome"}]})), None);
+    }
+
+    #[test]
+    fn multikey_fields_index_each_element() {
+        let idx = indexes(&[("tags", &["tags"])]);
+        assert_eq!(plan(&idx, json!({"tags": "math"})), Some(vec!["ada".to_string(), "alan".to_string()]));
+        // Both predicates can hold through different elements, so they are not intersected.
+        let keys = plan(&idx, json!({"$and": [{"tags": "engines"}, {"tags": "math"}]})).unwrap();
+        assert!(keys.contains(&"ada".to_string()));
+    }
+
+    #[test]
+    fn remove_drops_every_entry_of_a_document() {
+        let mut idx = indexes(&[("tags", &["tags"])]);
+        let index = idx.get_mut("tags").unwrap();
+        let entries = index.len();
+        index.remove("ada", &people()[0].1);
+        assert_eq!(index.len(), entries - 2);
+        assert_eq!(plan(&idx, json!({"tags": "engines"})), Some(vec![]));
+    }
+
+    #[test]
+    fn unique_index_reports_conflicts_and_duplicates() {
+        let mut index = Index::new(vec!["email".to_string()], true);
+        index.insert("u1", &json!({"email": "a@x"}));
+        index.insert("u2", &json!({"emails": "b@x"}));
+        assert_eq!(index.conflict("u3", &json!({"email": "a@x"})), Some("u1"));
+        assert_eq!(index.conflict("u1", &json!({"email": "a@x"})), None);
+        assert_eq!(index.conflict("u3", &json!({"email": ["c@x", "a@x"]})), Some("u1"));
+        // Documents without the field never conflict.
+        assert_eq!(index.conflict("u3", &json!({"name": "x"})), None);
+        assert_eq!(index.duplicate(), None);
+
+        index.insert("u3", &json!({"email": "a@x"}));
+        assert_eq!(index.duplicate(), Some(("u1", "u3")));
+    }
+}
@@ -1,4 +1,29 @@
+pub mod backup;
+pub mod checkpoint;
+mod checksum;
+mod codec;
 pub mod collection;
 pub mod database;
+pub mod error;
 pub mod executor;
-pub mod response;
\ No newline at end of file
+pub mod index;
+pub mod query;
+pub mod response;
+pub mod snapshot;
+pub mod storage {
+    pub mod bloom;
+    pub mod compaction;
+    pub mod engine;
+    pub mod lsm;
+    pub mod memory;
+    pub mod memtable;
+    pub mod merge;
+    pub mod range;
+    pub mod sstable;
+}
+pub mod transaction;
+pub mod transfer;
+pub mod update;
+pub mod wal;
+#[cfg(test)]
+mod test_util;
\ No newline at end of file
@@ -0,0 +1,250 @@
+use crate::collection::Document;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::io::{Error, ErrorKind};
+
+/// A parsed MongoDB-style filter, e.g. `{"age": {"$gt": 30}, "address.city": "Rome"}`.
+///
+/// Field names are dotted paths into the document; numeric segments index into arrays.
+/// When a path resolves to an array, `$eq`, `$gt`, `$lt` and `$in` also match if any
+/// element matches, and `$ne` requires that none does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Field { path: String, op: Op },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Eq(Value),
+    Ne(Value),
+    Gt(Value),
+    Lt(Value),
+    In(Vec<Value>),
+    Exists(bool),
+}
+
+impl Filter {
+    /// Parses a filter document. The empty document matches everything.
+    pub fn parse(filter: &Value) -> Result<Filter, Error> {
+        let Value::Object(fields) = filter else {
+            return Err(invalid("filter must be a JSON object"));
+        };
+        let mut clauses = Vec::with_capacity(fields.len());
+        for (name, value) in fields {
+            match name.as_str() {
+                "$and" => clauses.push(Filter::And(parse_list(name, value)?)),
+                "$or" => clauses.push(Filter::Or(parse_list(name, value)?)),
+                op if op.starts_with('$') => {
+                    return Err(invalid(&format!("unknown top-level operator {op}")));
+                }
+                path => parse_field(path, value, &mut clauses)?,
+            }
+        }
+        Ok(match clauses.len() {
+            1 => clauses.pop().expect("one clause"),
+            _ => Filter::And(clauses),
+        })
+    }
+
+    pub fn matches(&self, doc: &Document) -> bool {
+        match self {
+            Filter::And(filters) => filters.iter().all(|f| f.matches(doc)),
+            Filter::Or(filters) => filters.iter().any(|f| f.matches(doc)),
+            Filter::Field { path, op } => op.matches(resolve(doc, path)),
+        }
+    }
+}
+
+impl Op {
+    fn parse(name: &str, operand: &Value) -> Result<Op, Error> {
+        Ok(match name {
+            "$eq" => Op::Eq(operand.clone()),
+            "$ne" => Op::Ne(operand.clone()),
+            "$gt" => Op::Gt(operand.clone()),
+            "$lt" => Op::Lt(operand.clone()),
+            "$in" => match operand {
+                Value::Array(values) => Op::In(values.clone()),
+                _ => return Err(invalid("$in expects an array")),
+            },
+            "$exists" => match operand {
+                Value::Bool(exists) => Op::Exists(*exists),
+                _ => return Err(invalid("$exists expects a boolean")),
+            },
+            other => return Err(invalid(&format!("unknown operator {other}"))),
+        })
+    }
+
+    fn matches(&self, value: Option<&Value>) -> bool {
+        match (self, value) {
+            (Op::Exists(exists), value) => value.is_some() == *exists,
+            (Op::Ne(operand), value) => !Op::Eq(operand.clone()).matches(value),
+            (_, None) => false,
+            (Op::Eq(operand), Some(value)) => any_element(value, |v| json_eq(v, operand)),
+            (Op::Gt(operand), Some(value)) => {
+                any_element(value, |v| compare(v, operand) == Some(Ordering::Greater))
+            }
+            (Op::Lt(operand), Some(value)) => {
+                any_element(value, |v| compare(v, operand) == Some(Ordering::Less))
+            }
+            (Op::In(operands), Some(value)) => {
+                any_element(value, |v| operands.iter().any(|o| json_eq(v, o)))
+            }
+        }
+    }
+}
+
+/// Follows a dotted `path` into `doc`; numeric segments index into arrays.
+pub fn resolve<'a>(doc: &'a Document, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(doc, |value, segment| match value {
+        Value::Object(fields) => fields.get(segment),
+        Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+/// Equality that treats `1` and `1.0` as the same number.
+pub fn json_eq(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64() == y.as_f64(),
+        (Value::Array(xs), Value::Array(ys)) => {
+            xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| json_eq(x, y))
+        }
+        (Value::Object(xs), Value::Object(ys)) => {
+            xs.len() == ys.len() && xs.iter().all(|(k, x)| ys.get(k).is_some_and(|y| json_eq(x, y)))
+        }
+        _ => a == b,
+    }
+}
+
+/// Orders numbers, strings and booleans among themselves; other pairs are incomparable.
+pub fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(x), Value::Number(y)) => x.as_f64()?.partial_cmp(&y.as_f64()?),
+        (Value::String(x), Value::String(y)) => Some(x.cmp(y)),
+        (Value::Bool(x), Value::Bool(y)) => Some(x.cmp(y)),
+        _ => None,
+    }
+}
+
+fn any_element(value: &Value, pred: impl Fn(&Value) -> bool) -> bool {
+    pred(value) || matches!(value, Value::Array(items) if items.iter().any(&pred))
+}
+
+fn parse_list(name: &str, value: &Value) -> Result<Vec<Filter>, Error> {
+    match value {
+        Value::Array(filters) if !filters.is_empty() => filters.iter().map(Filter::parse).collect(),
+        _ => Err(invalid(&format!("{name} expects a non-empty array of filters"))),
+    }
+}
+
+/// `{"path": value}` is an equality test; `{"path": {"$op": operand, ...}}` adds one
+/// clause per operator.
+fn parse_field(path: &str, value: &Value, clauses: &mut Vec<Filter>) -> Result<(), Error> {
+    let operators = match value {
+        Value::Object(fields) if fields.keys().any(|k| k.starts_with('$')) => fields,
+        _ => {
+            clauses.push(Filter::Field { path: path.to_string(), op: Op::Eq(value.clone()) });
+            return Ok(());
+        }
+    };
+    for (name, operand) in operators {
+        if !name.starts_with('$') {
+            return Err(invalid(&format!("cannot mix operators and fields under {path}")));
+        }
+        clauses.push(Filter::Field { path: path.to_string(), op: Op::parse(name, operand)? });
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("Invalid filter: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn doc() -> Document {
+        json!({
+            "name": "Ada",
+            "age": 36,
+            "tags": ["math", "engines"],
+            "address": {"city": "London", "zip": null},
+        })
+    }
+
+    fn matches(filter: Value) -> bool {
+        Filter::parse(&filter).unwrap().matches(&doc())
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        assert!(matches(json!({})));
+    }
+
+    #[test]
+    fn implicit_and_explicit_equality() {
+        assert!(matches(json!({"name": "Ada"})));
+        assert!(matches(json!({"age": {"$eq": 36.0}})));
+        assert!(!matches(json!({"name": "Grace"})));
+        assert!(matches(json!({"address": {"city": "London", "zip": null}})));
+    }
+
+    #[test]
+    fn comparisons_only_match_same_kind() {
+        assert!(matches(json!({"age": {"$gt": 30, "$lt": 40}})));
+        assert!(!matches(json!({"age": {"$gt": 36}})));
+        assert!(!matches(json!({"age": {"$gt": "30"}})));
+        assert!(matches(json!({"name": {"$lt": "Bob"}})));
+    }
+
+    #[test]
+    fn ne_matches_missing_fields() {
+        assert!(matches(json!({"name": {"$ne": "Grace"}})));
+        assert!(matches(json!({"nickname": {"$ne": "Ada"}})));
+        assert!(!matches(json!({"tags": {"$ne": "math"}})));
+    }
+
+    #[test]
+    fn in_and_exists() {
+        assert!(matches(json!({"age": {"$in": [1, 36]}})));
+        assert!(matches(json!({"tags": {"$in": ["engines"]}})));
+        assert!(!matches(json!({"age": {"$in": []}})));
+        assert!(matches(json!({"address.zip": {"$exists": true}})));
+        assert!(matches(json!({"address.country": {"$exists": false}})));
+    }
+
+    #[test]
+    fn dotted_paths_reach_nested_values_and_array_elements() {
+        assert!(matches(json!({"address.city": "London"})));
+        assert!(matches(json!({"tags.1": "engines"})));
+        assert!(matches(json!({"tags": "math"})));
+        assert!(!matches(json!({"name.first": "Ada"})));
+    }
+
+    #[test]
+    fn logical_operators_combine_filters() {
+        assert!(matches(json!({"$or": [{"name": "Grace"}, {"age": {"$gt": 30}}]})));
+        assert!(!matches(json!({"$and": [{"name": "Ada"}, {"age": {"$lt": 30}}]})));
+        assert!(matches(json!({"$and": [{"$or": [{"age": 1}, {"age": 36}]}, {"name": "Ada"}]})));
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for filter in [
+            json!([1]),
+            json!({"$nor": []}),
+            json!({"$or": []}),
+            json!({"age": {"$regex": "a"}}),
+            json!({"age": {"$in": 3}}),
+            json!({"age": {"$exists": 1}}),
+            json!({"age": {"$gt": 1, "x": 2}}),
+        ] {
+            let err = Filter::parse(&filter).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{filter}");
+        }
+    }
+}
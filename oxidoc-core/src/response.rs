@@ -1,57 +1,233 @@
-use crate::collection::Document;
+use crate::codec::{Decoder, Encode};
+use crate::collection::{Document, Versioned};
+use crate::error::{ErrorCategory, ErrorCode, Failure};
 use std::fmt;
 use std::fmt::Display;
+use std::io::{Error, ErrorKind};
+
+const SUCCESS: u8 = 1;
+const NO_DOC: u8 = 2;
+const DOC: u8 = 3;
+const DOCS: u8 = 4;
+const FAILURE: u8 = 5;
+const ACK: u8 = 6;
+const BATCH: u8 = 7;
 
 #[derive(Debug, PartialEq)]
 pub enum Response {
     Success(String),
-    Doc(Option<Document>),
-    Failure(String),
+    /// A single document with its version, or `None` when it does not exist.
+    Doc(Option<Versioned>),
+    /// A page of `(key, document)` pairs, e.g. from a scan.
+    Docs(Vec<(String, Document)>),
+    Failure(Failure),
     Ack,
+    /// One response per operation of a batch, in order.
+    Batch(Vec<Response>),
 }
 
 impl Display for Response {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Response::Success(msg) => write!(f, "Success: {}", msg),
-            Response::Doc(Some(doc)) => write!(f, "Document: {}", serde_json::to_string(doc).unwrap_or_else(|_| "<invalid>".to_string())),
+            Response::Doc(Some(stored)) => write!(
+                f,
+                "Document (version {}): {}",
+                stored.version,
+                serde_json::to_string(&stored.doc).unwrap_or_else(|_| "<invalid>".to_string())
+            ),
             Response::Doc(None) => write!(f, "Document: None"),
-            Response::Failure(msg) => write!(f, "Server responded with failure: {}", msg),
+            Response::Docs(docs) => {
+                write!(f, "Documents: {}", docs.len())?;
+                for (key, doc) in docs {
+                    write!(f, "\n{}: {}", key, doc)?;
+                }
+                Ok(())
+            }
+            Response::Failure(failure) => write!(f, "Server responded with failure {}", failure),
             Response::Ack => write!(f, "Acknowledged"),
+            Response::Batch(results) => {
+                let failed = results.iter().filter(|r| matches!(r, Response::Failure(..))).count();
+                write!(f, "Batch: {} operation(s), {} failed", results.len(), failed)?;
+                for (index, result) in results.iter().enumerate() {
+                    write!(f, "\n{}: {}", index, result)?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
 impl Response {
+    /// The failure reported to a client for an error returned by the database.
+    pub fn from_error(err: &Error) -> Self {
+        Response::Failure(Failure::of(err))
+    }
+
+    /// A failure without details.
+    pub fn failure(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Failure(Failure::new(code, message))
+    }
+
+    /// The payload of a response envelope: a tag byte, then the fields of the variant.
     pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
         match self {
-            Response::Success(msg) => msg.as_bytes().to_vec(),
-            Response::Doc(Some(doc)) => serde_json::to_vec(doc).expect("Serialization failed."),
-            Response::Doc(None) => b"2".to_vec(),
-            Response::Failure(msg) => {
-                let mut r = msg.as_bytes().to_vec();
-                r.insert(0, b'!');
-                r
-            }
-            Response::Ack => b"ACK".to_vec(),
+            Response::Success(msg) => {
+                bytes.put_u8(SUCCESS);
+                bytes.put_str(msg);
+            }
+            Response::Doc(None) => bytes.put_u8(NO_DOC),
+            Response::Doc(Some(stored)) => {
+                bytes.put_u8(DOC);
+                bytes.put_u64(stored.version);
+                bytes.put_bytes(&serde_json::to_vec(&stored.doc).expect("Serialization failed."));
+            }
+            Response::Docs(docs) => {
+                bytes.put_u8(DOCS);
+                bytes.put_u32(docs.len() as u32);
+                for (key, doc) in docs {
+                    bytes.put_str(key);
+                    bytes.put_bytes(&serde_json::to_vec(doc).expect("Serialization failed."));
+                }
+            }
+            Response::Failure(failure) => {
+                bytes.put_u8(FAILURE);
+                bytes.put_u16(failure.code.code());
+                bytes.put_u8(failure.category.code());
+                bytes.put_str(&failure.message);
+                match &failure.details {
+                    Some(details) => {
+                        bytes.put_u8(1);
+                        bytes.put_bytes(&serde_json::to_vec(details).expect("Serialization failed."));
+                    }
+                    None => bytes.put_u8(0),
+                }
+            }
+            Response::Ack => bytes.put_u8(ACK),
+            Response::Batch(results) => {
+                bytes.put_u8(BATCH);
+                bytes.put_u32(results.len() as u32);
+                for result in results {
+                    bytes.put_bytes(&result.as_bytes());
+                }
+            }
         }
+        bytes
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes == b"ACK" {
-            Response::Ack
-        } else if bytes == b"2" {
-            Response::Doc(None)
-        } else if bytes.starts_with(b"{") && bytes.ends_with(b"}") {
-            match serde_json::from_slice::<Document>(bytes) {
-                Ok(doc) => Response::Doc(Some(doc)),
-                Err(e) => Response::Failure(format!("Deserialization failed for Document: {}", e)),
-            }
-        } else if bytes.starts_with(b"!") {
-            Response::Failure(String::from_utf8_lossy(&bytes[1..]).to_string())
-        } else {
-            Response::Success(String::from_utf8_lossy(bytes).to_string())
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut decoder = Decoder::new(bytes);
+        let response = Response::decode(&mut decoder)?;
+        if !decoder.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Trailing bytes after response"));
         }
+        Ok(response)
+    }
+
+    fn decode(decoder: &mut Decoder) -> Result<Self, Error> {
+        let document = |bytes: &[u8]| serde_json::from_slice::<Document>(bytes).map_err(Error::from);
+        Ok(match decoder.get_u8()? {
+            SUCCESS => Response::Success(decoder.get_string()?),
+            NO_DOC => Response::Doc(None),
+            DOC => {
+                let version = decoder.get_u64()?;
+                Response::Doc(Some(Versioned::new(version, document(decoder.get_bytes()?)?)))
+            }
+            DOCS => {
+                let len = decoder.get_u32()?;
+                // Not preallocated: the count comes off the wire.
+                let mut docs = Vec::new();
+                for _ in 0..len {
+                    docs.push((decoder.get_string()?, document(decoder.get_bytes()?)?));
+                }
+                Response::Docs(docs)
+            }
+            FAILURE => Response::Failure(Failure {
+                code: ErrorCode::from_code(decoder.get_u16()?),
+                category: ErrorCategory::from_code(decoder.get_u8()?),
+                message: decoder.get_string()?,
+                details: match decoder.get_u8()? {
+                    0 => None,
+                    _ => Some(document(decoder.get_bytes()?)?),
+                },
+            }),
+            ACK => Response::Ack,
+            BATCH => {
+                let len = decoder.get_u32()?;
+                let mut results = Vec::new();
+                for _ in 0..len {
+                    // Batches hold results of single writes; refusing nested ones bounds the recursion.
+                    match Response::from_bytes(decoder.get_bytes()?)? {
+                        Response::Batch(_) => return Err(Error::new(ErrorKind::InvalidData, "Nested batch response")),
+                        result => results.push(result),
+                    }
+                }
+                Response::Batch(results)
+            }
+            tag => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown response type {}", tag))),
+        })
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn roundtrip(response: Response) {
+        assert_eq!(Response::from_bytes(&response.as_bytes()).unwrap(), response);
+    }
+
+    #[test]
+    fn docs_roundtrip_through_bytes() {
+        roundtrip(Response::Docs(vec![("k1".to_string(), json!({"a": 1})), ("k2".to_string(), json!([1, 2]))]));
+        roundtrip(Response::Docs(Vec::new()));
+    }
+
+    #[test]
+    fn failures_carry_their_code() {
+        let response = Response::failure(ErrorCode::UniqueViolation, "dup: \"a\"");
+        assert_eq!(&response.as_bytes()[..4], &[FAILURE, 0, 11, ErrorCategory::ConstraintViolation.code()]);
+        roundtrip(response);
+        let detailed = Failure { details: Some(json!({"key": "a"})), ..Failure::new(ErrorCode::DuplicateKey, "taken") };
+        roundtrip(Response::Failure(detailed));
+
+        let mut unknown = Response::failure(ErrorCode::Internal, "new").as_bytes();
+        unknown[1..4].copy_from_slice(&[0x01, 0x2c, ErrorCategory::Conflict.code()]);
+        let Ok(Response::Failure(failure)) = Response::from_bytes(&unknown) else { panic!() };
+        assert_eq!((failure.code, failure.category), (ErrorCode::Internal, ErrorCategory::Conflict));
+    }
+
+    #[test]
+    fn versioned_doc_roundtrips_through_bytes() {
+        roundtrip(Response::Doc(Some(Versioned::new(42, json!({"a": "x y"})))));
+        roundtrip(Response::Doc(None));
+    }
+
+    #[test]
+    fn messages_that_looked_like_other_responses_stay_successes() {
+        for msg in ["!1:not a failure", "2", "{\"a\": 1}", "[]", "ACK", "*[]", ""] {
+            roundtrip(Response::Success(msg.to_string()));
+        }
+    }
+
+    #[test]
+    fn batch_results_roundtrip_through_bytes() {
+        roundtrip(Response::Batch(vec![
+            Response::Success("Document \"a\" created".to_string()),
+            Response::Ack,
+            Response::failure(ErrorCode::DuplicateKey, "taken"),
+            Response::Doc(None),
+        ]));
+        roundtrip(Response::Batch(Vec::new()));
+    }
+
+    #[test]
+    fn malformed_payloads_are_errors() {
+        let doc = Response::Doc(Some(Versioned::new(1, json!({"a": 1})))).as_bytes();
+        for bytes in [&[][..], &[99], &doc[..doc.len() - 1], &[ACK, 0], &[BATCH, 0, 0, 0, 1, 0, 0, 0, 1, 99], &[BATCH, 0, 0, 0, 1, 0, 0, 0, 5, BATCH, 0, 0, 0, 0]] {
+            assert!(Response::from_bytes(bytes).is_err(), "{:?}", bytes);
+        }
+    }
+}
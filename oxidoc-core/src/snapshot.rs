@@ -0,0 +1,177 @@
+use crate::collection::Versioned;
+use crate::storage::range::KeyRange;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Point-in-time view of a database: sees every write with a sequence number up
+/// to [`Snapshot::sequence`] and none after it, across all collections. The
+/// versions it needs are kept until it is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+    sequence: u64,
+    open: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Snapshot {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut open = lock(&self.open);
+        if let Some(count) = open.get_mut(&self.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.sequence);
+            }
+        }
+    }
+}
+
+/// Registry of the open snapshots, shared with the [`Snapshot`]s themselves so
+/// that dropping one does not need the database.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshots {
+    open: Arc<Mutex<BTreeMap<u64, usize>>>,
+}
+
+impl Snapshots {
+    pub(crate) fn open(&self, sequence: u64) -> Snapshot {
+        *lock(&self.open).entry(sequence).or_insert(0) += 1;
+        Snapshot { sequence, open: Arc::clone(&self.open) }
+    }
+
+    pub(crate) fn oldest(&self) -> Option<u64> {
+        lock(&self.open).keys().next().copied()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        lock(&self.open).values().sum()
+    }
+}
+
+fn lock(open: &Mutex<BTreeMap<u64, usize>>) -> MutexGuard<'_, BTreeMap<u64, usize>> {
+    open.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Writes to one key since the oldest snapshot: each with the version it replaced.
+type Past = Vec<(u64, Option<Versioned>)>;
+
+/// Document versions overwritten or deleted while a snapshot was open: for every
+/// key, the sequence number of each later write together with the version it replaced
+/// (`None`: the key was absent), oldest first.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    collections: HashMap<String, BTreeMap<String, Past>>,
+    /// Entries at or below this sequence number have already been dropped.
+    horizon: u64,
+    len: usize,
+}
+
+impl History {
+    pub(crate) fn record(&mut self, collection: &str, key: &str, sequence: u64, previous: Option<Versioned>) {
+        self.collections
+            .entry(collection.to_string())
+            .or_default()
+            .entry(key.to_string())
+            .or_default()
+            .push((sequence, previous));
+        self.len += 1;
+    }
+
+    /// Drops every entry no snapshot can see anymore: all of them without open
+    /// snapshots, otherwise those written at or before the oldest one.
+    pub(crate) fn collect(&mut self, oldest: Option<u64>) {
+        let Some(oldest) = oldest else {
+            self.collections.clear();
+            self.len = 0;
+            return;
+        };
+        if oldest <= self.horizon {
+            return;
+        }
+        self.horizon = oldest;
+        let mut len = 0;
+        self.collections.retain(|_, keys| {
+            keys.retain(|_, entries| {
+                entries.retain(|(sequence, _)| *sequence > oldest);
+                len += entries.len();
+                !entries.is_empty()
+            });
+            !keys.is_empty()
+        });
+        self.len = len;
+    }
+
+    /// The version of `key` at `sequence`, given the `current` one.
+    pub(crate) fn resolve(&self, collection: &str, key: &str, current: Option<Versioned>, sequence: u64) -> Option<Versioned> {
+        let entries = self.collections.get(collection).and_then(|keys| keys.get(key));
+        let mut value = current;
+        for (written, previous) in entries.into_iter().flatten().rev() {
+            if *written <= sequence {
+                break;
+            }
+            value = previous.clone();
+        }
+        value
+    }
+
+    /// Keys of `collection` in `range` with a recorded past, in scan order.
+    pub(crate) fn keys<'a>(&'a self, collection: &str, range: &KeyRange, reverse: bool) -> Box<dyn Iterator<Item = &'a String> + 'a> {
+        let Some(keys) = self.collections.get(collection).filter(|_| !range.is_empty()) else {
+            return Box::new(std::iter::empty());
+        };
+        let bounds = (owned(range.start_bound()), owned(range.end_bound()));
+        let keys = keys.range::<String, _>(bounds).map(|(key, _)| key);
+        if reverse { Box::new(keys.rev()) } else { Box::new(keys) }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+}
+
+fn owned(bound: Bound<&str>) -> Bound<String> {
+    bound.map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn snapshots_unregister_when_dropped() {
+        let snapshots = Snapshots::default();
+        let first = snapshots.open(3);
+        let second = snapshots.open(5);
+        let again = snapshots.open(3);
+        assert_eq!((snapshots.oldest(), snapshots.len()), (Some(3), 3));
+        drop(first);
+        assert_eq!(snapshots.oldest(), Some(3));
+        drop(again);
+        assert_eq!(snapshots.oldest(), Some(5));
+        drop(second);
+        assert_eq!(snapshots.oldest(), None);
+    }
+
+    #[test]
+    fn resolve_walks_back_to_the_snapshot() {
+        let mut history = History::default();
+        history.record("c", "k", 4, None);
+        history.record("c", "k", 6, Some(Versioned::new(4, json!(1))));
+        let current = Some(Versioned::new(6, json!(2)));
+        assert_eq!(history.resolve("c", "k", current.clone(), 3), None);
+        assert_eq!(history.resolve("c", "k", current.clone(), 5), Some(Versioned::new(4, json!(1))));
+        assert_eq!(history.resolve("c", "k", current.clone(), 6), current);
+
+        history.collect(Some(5));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.resolve("c", "k", current.clone(), 5), Some(Versioned::new(4, json!(1))));
+        history.collect(None);
+        assert_eq!(history.len(), 0);
+    }
+}
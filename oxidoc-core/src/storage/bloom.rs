@@ -0,0 +1,155 @@
+use crate::checksum::crc32;
+use crate::codec::{Decoder, Encode};
+use std::fmt;
+use std::fmt::Display;
+use std::io::{Error, ErrorKind};
+
+const MAX_HASHES: u32 = 30;
+
+/// Bloom filter over a table's keys. Hashing is FNV-1a based so persisted
+/// filters stay valid across builds and platforms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Sizes a filter for `items` keys at the given false-positive rate.
+    pub fn with_rate(items: usize, false_positive_rate: f64) -> BloomFilter {
+        let rate = false_positive_rate.clamp(1e-9, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(items.max(1) as f64) * rate.ln() / (ln2 * ln2)).ceil().max(8.0) as usize;
+        let num_hashes = ((num_bits as f64 / items.max(1) as f64) * ln2).round().clamp(1.0, MAX_HASHES as f64) as u32;
+        BloomFilter {
+            bits: vec![0u8; num_bits.div_ceil(8)],
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        self.insert_hash(key_hash(key));
+    }
+
+    /// Inserts a key by its [`key_hash`], for callers that cannot keep keys around.
+    pub fn insert_hash(&mut self, hash: u64) {
+        for bit in self.bit_positions(hash) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// `false` means the key is definitely absent.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key_hash(key)).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.bits.len() + 8);
+        body.put_u32(self.num_hashes);
+        body.put_bytes(&self.bits);
+        let mut out = Vec::with_capacity(body.len() + 4);
+        out.put_u32(crc32(&body));
+        out.extend_from_slice(&body);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<BloomFilter, Error> {
+        let mut dec = Decoder::new(bytes);
+        let crc = dec.get_u32()?;
+        if crc32(&bytes[4..]) != crc {
+            return Err(Error::new(ErrorKind::InvalidData, "Bloom filter checksum mismatch"));
+        }
+        let num_hashes = dec.get_u32()?;
+        let bits = dec.get_bytes()?.to_vec();
+        if bits.is_empty() || num_hashes == 0 || num_hashes > MAX_HASHES {
+            return Err(Error::new(ErrorKind::InvalidData, "Malformed Bloom filter"));
+        }
+        Ok(BloomFilter { bits, num_hashes })
+    }
+
+    /// Double hashing: probe `i` lands on `h1 + i * h2`.
+    fn bit_positions(&self, h1: u64) -> impl Iterator<Item = usize> + use<> {
+        let h2 = mix(h1) | 1;
+        let num_bits = (self.bits.len() * 8) as u64;
+        (0..self.num_hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+/// Stable 64-bit FNV-1a hash of a key.
+pub fn key_hash(key: &str) -> u64 {
+    key.as_bytes()
+        .iter().fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// 64-bit finalizer (from MurmurHash3) deriving an independent second hash.
+fn mix(mut h: u64) -> u64 {
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+/// Point-lookup counters for the filters of an engine's tables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BloomStats {
+    /// Table probes skipped because the filter ruled the key out.
+    pub negatives: u64,
+    /// Table probes the filter let through.
+    pub positives: u64,
+    /// Probes let through for keys the table did not contain.
+    pub false_positives: u64,
+}
+
+impl BloomStats {
+    pub fn merge(&mut self, other: &BloomStats) {
+        self.negatives += other.negatives;
+        self.positives += other.positives;
+        self.false_positives += other.false_positives;
+    }
+}
+
+impl Display for BloomStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} probes skipped, {} probes passed ({} false positives)",
+            self.negatives, self.positives, self.false_positives
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserted_keys_are_always_found() {
+        let mut filter = BloomFilter::with_rate(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("key{}", i));
+        }
+        assert!((0..1000).all(|i| filter.may_contain(&format!("key{}", i))));
+    }
+
+    #[test]
+    fn false_positive_rate_is_close_to_target() {
+        let mut filter = BloomFilter::with_rate(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(&format!("key{}", i));
+        }
+        let false_positives = (0..10_000).filter(|i| filter.may_contain(&format!("other{}", i))).count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn encode_decode_roundtrip_and_corruption() {
+        let mut filter = BloomFilter::with_rate(10, 0.05);
+        filter.insert("a");
+        let mut bytes = filter.encode();
+        assert_eq!(BloomFilter::decode(&bytes).unwrap(), filter);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert!(BloomFilter::decode(&bytes).is_err());
+    }
+}
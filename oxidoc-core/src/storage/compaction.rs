@@ -0,0 +1,180 @@
+use crate::storage::sstable::SsTable;
+use std::fmt;
+use std::fmt::Display;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// How the background compactor chooses which SSTables to merge.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionStrategy {
+    /// Merges runs of at least `min_tables` adjacent tables whose sizes fall in
+    /// the same size tier (tiers grow by a factor of 4).
+    SizeTiered { min_tables: usize },
+    /// Flushed tables live in L0 and are merged into L1 once `l0_tables` of them
+    /// pile up. Every deeper level holds a single sorted run; level `n` is pushed
+    /// down into `n + 1` when it exceeds `level_bytes * multiplier^(n - 1)`.
+    Leveled { l0_tables: usize, level_bytes: u64, multiplier: u64 },
+}
+
+impl Default for CompactionStrategy {
+    fn default() -> Self {
+        CompactionStrategy::SizeTiered { min_tables: 4 }
+    }
+}
+
+/// Progress counters of one engine's compactor, reported by `Status`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionStats {
+    pub running: bool,
+    /// Tables being merged by the running compaction.
+    pub input_tables: usize,
+    /// Entries written by the running (or last finished) compaction.
+    pub entries_written: u64,
+    pub completed: u64,
+    pub bytes_written: u64,
+    pub tombstones_dropped: u64,
+}
+
+impl CompactionStats {
+    pub fn merge(&mut self, other: &CompactionStats) {
+        self.running |= other.running;
+        self.input_tables += other.input_tables;
+        self.entries_written += other.entries_written;
+        self.completed += other.completed;
+        self.bytes_written += other.bytes_written;
+        self.tombstones_dropped += other.tombstones_dropped;
+    }
+}
+
+impl Display for CompactionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} completed ({} bytes written, {} tombstones dropped)",
+            self.completed, self.bytes_written, self.tombstones_dropped
+        )?;
+        if self.running {
+            write!(f, ", running: {} tables, {} entries written", self.input_tables, self.entries_written)?;
+        }
+        Ok(())
+    }
+}
+
+/// An SSTable together with the level it belongs to.
+#[derive(Debug, Clone)]
+pub struct LeveledTable {
+    pub table: Arc<SsTable>,
+    pub level: u32,
+}
+
+/// Tables to merge: a contiguous range of the newest-first table list, so
+/// the merged output can take their place without reordering newer data.
+#[derive(Debug, PartialEq)]
+pub struct CompactionPlan {
+    pub inputs: Range<usize>,
+    pub output_level: u32,
+}
+
+const MAX_TABLES_PER_COMPACTION: usize = 32;
+
+impl CompactionStrategy {
+    /// Picks the next compaction for `tables` (newest first), if any is due.
+    pub fn plan(&self, tables: &[LeveledTable]) -> Option<CompactionPlan> {
+        match *self {
+            CompactionStrategy::SizeTiered { min_tables } => plan_size_tiered(tables, min_tables.max(2)),
+            CompactionStrategy::Leveled { l0_tables, level_bytes, multiplier } => {
+                plan_leveled(tables, l0_tables.max(1), level_bytes, multiplier.max(2))
+            }
+        }
+    }
+}
+
+fn size_tier(bytes: u64) -> u32 {
+    (64 - bytes.leading_zeros()) / 2
+}
+
+fn plan_size_tiered(tables: &[LeveledTable], min_tables: usize) -> Option<CompactionPlan> {
+    let mut start = 0;
+    while start < tables.len() {
+        let tier = size_tier(tables[start].table.size_bytes());
+        let mut end = start + 1;
+        while end < tables.len()
+            && end - start < MAX_TABLES_PER_COMPACTION
+            && size_tier(tables[end].table.size_bytes()) == tier
+        {
+            end += 1;
+        }
+        if end - start >= min_tables {
+            return Some(CompactionPlan { inputs: start..end, output_level: 0 });
+        }
+        start = end;
+    }
+    None
+}
+
+fn plan_leveled(tables: &[LeveledTable], l0_tables: usize, level_bytes: u64, multiplier: u64) -> Option<CompactionPlan> {
+    let l0_count = tables.iter().take_while(|t| t.level == 0).count();
+    if l0_count >= l0_tables {
+        let with_l1 = tables.get(l0_count).is_some_and(|t| t.level == 1);
+        return Some(CompactionPlan {
+            inputs: 0..l0_count + with_l1 as usize,
+            output_level: 1,
+        });
+    }
+    for (i, t) in tables.iter().enumerate().skip(l0_count) {
+        let limit = level_bytes.saturating_mul(multiplier.saturating_pow(t.level.saturating_sub(1)));
+        if t.table.size_bytes() > limit {
+            let with_next = tables.get(i + 1).is_some_and(|n| n.level == t.level + 1);
+            return Some(CompactionPlan {
+                inputs: i..i + 1 + with_next as usize,
+                output_level: t.level + 1,
+            });
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::test_util::versioned;
+    use serde_json::json;
+
+    fn table(dir: &TempDir, id: u64, entries: usize, level: u32) -> LeveledTable {
+        let path = dir.path().join(format!("{}.sst", id));
+        let rows = (0..entries).map(|i| Ok((format!("key{:06}", i), Some(versioned(json!({"n": i}))))));
+        LeveledTable {
+            table: Arc::new(SsTable::write(&path, id, rows, 4096, None).unwrap()),
+            level,
+        }
+    }
+
+    #[test]
+    fn size_tiered_merges_run_of_similar_tables() {
+        let dir = TempDir::new("compaction");
+        let strategy = CompactionStrategy::SizeTiered { min_tables: 3 };
+        let mut tables = vec![table(&dir, 1, 10, 0), table(&dir, 2, 10, 0)];
+        assert_eq!(strategy.plan(&tables), None);
+        tables.push(table(&dir, 3, 10, 0));
+        tables.push(table(&dir, 4, 5000, 0));
+        assert_eq!(strategy.plan(&tables), Some(CompactionPlan { inputs: 0..3, output_level: 0 }));
+    }
+
+    #[test]
+    fn leveled_pushes_l0_into_l1() {
+        let dir = TempDir::new("compaction");
+        let strategy = CompactionStrategy::Leveled { l0_tables: 2, level_bytes: u64::MAX, multiplier: 10 };
+        let tables = vec![table(&dir, 3, 10, 0), table(&dir, 2, 10, 0), table(&dir, 1, 100, 1)];
+        assert_eq!(strategy.plan(&tables), Some(CompactionPlan { inputs: 0..3, output_level: 1 }));
+        assert_eq!(strategy.plan(&tables[1..]), None);
+    }
+
+    #[test]
+    fn leveled_pushes_oversized_level_down() {
+        let dir = TempDir::new("compaction");
+        let strategy = CompactionStrategy::Leveled { l0_tables: 4, level_bytes: 1024, multiplier: 10 };
+        let tables = vec![table(&dir, 2, 1000, 1), table(&dir, 1, 10, 2)];
+        assert_eq!(strategy.plan(&tables), Some(CompactionPlan { inputs: 0..2, output_level: 2 }));
+    }
+}
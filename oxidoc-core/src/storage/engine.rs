@@ -0,0 +1,88 @@
+use crate::collection::Versioned;
+use crate::storage::bloom::BloomStats;
+use crate::storage::compaction::CompactionStats;
+use crate::storage::lsm::{LsmEngine, LsmOptions};
+use crate::storage::memory::MemoryEngine;
+use crate::storage::range::KeyRange;
+use std::fmt::Debug;
+use std::io::Error;
+use std::path::Path;
+
+/// Live `(key, document)` pairs in key order.
+pub type EntryIter<'a> = Box<dyn Iterator<Item = Result<(String, Versioned), Error>> + 'a>;
+
+/// Key → document storage backing a single collection.
+pub trait StorageEngine: Debug + Send {
+    fn get(&self, key: &str) -> Result<Option<Versioned>, Error>;
+
+    fn put(&mut self, key: String, value: Versioned) -> Result<(), Error>;
+
+    fn delete(&mut self, key: &str) -> Result<(), Error>;
+
+    /// Live documents in `range`, ascending or descending when `reverse` is set.
+    fn scan(&self, range: &KeyRange, reverse: bool) -> Result<EntryIter<'_>, Error>;
+
+    fn iter(&self) -> Result<EntryIter<'_>, Error> {
+        self.scan(&KeyRange::all(), false)
+    }
+
+    /// Writes buffered documents out to the engine's own files, if it keeps any.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Releases any persistent state; called when the collection is dropped.
+    fn destroy(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+}
+
+/// Storage-level counters, aggregated across collections by `Status`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    /// On-disk tables currently live.
+    pub tables: usize,
+    pub compaction: CompactionStats,
+    pub bloom: BloomStats,
+}
+
+impl EngineStats {
+    pub fn merge(&mut self, other: &EngineStats) {
+        self.tables += other.tables;
+        self.compaction.merge(&other.compaction);
+        self.bloom.merge(&other.bloom);
+    }
+}
+
+/// Which [`StorageEngine`] a database creates its collections with.
+#[derive(Debug, Clone)]
+pub enum EngineConfig {
+    InMemory,
+    Lsm(LsmOptions),
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig::Lsm(LsmOptions::default())
+    }
+}
+
+impl EngineConfig {
+    /// Whether collections keep their documents in files of their own (given a directory).
+    pub fn is_persistent(&self) -> bool {
+        matches!(self, EngineConfig::Lsm(_))
+    }
+
+    /// Creates (or reopens) the engine for a collection. Persistent engines keep
+    /// their files under `dir`; without one every engine falls back to memory.
+    pub fn open(&self, dir: Option<&Path>) -> Result<Box<dyn StorageEngine>, Error> {
+        match (self, dir) {
+            (EngineConfig::Lsm(options), Some(dir)) => Ok(Box::new(LsmEngine::open(dir, options.clone())?)),
+            _ => Ok(Box::new(MemoryEngine::new())),
+        }
+    }
+}
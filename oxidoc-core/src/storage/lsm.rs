@@ -0,0 +1,670 @@
+use crate::collection::Versioned;
+use crate::storage::bloom::BloomStats;
+use crate::storage::compaction::{CompactionStats, CompactionStrategy, LeveledTable};
+use crate::storage::engine::{EngineStats, EntryIter, StorageEngine};
+use crate::storage::memtable::Memtable;
+use crate::storage::merge::{MergeIter, SlotIter};
+use crate::storage::range::KeyRange;
+use crate::storage::sstable::{self, SsTable};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+const TABLE_EXTENSION: &str = "sst";
+/// How often (in entries) a running compaction publishes its progress.
+const PROGRESS_INTERVAL: u64 = 256;
+
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// Approximate memtable size that triggers a flush to a new SSTable.
+    pub memtable_bytes: usize,
+    /// Target size of SSTable data blocks.
+    pub block_bytes: usize,
+    /// False-positive rate of the Bloom filter written with every SSTable;
+    /// `None` writes tables without filters.
+    pub bloom_false_positive_rate: Option<f64>,
+    pub compaction: CompactionStrategy,
+    /// Run compactions on a background thread after every flush. When off,
+    /// tables are only merged by explicit [`LsmEngine::compact`] calls.
+    pub background_compaction: bool,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_bytes: 4 * 1024 * 1024,
+            block_bytes: 4 * 1024,
+            bloom_false_positive_rate: Some(0.01),
+            compaction: CompactionStrategy::default(),
+            background_compaction: true,
+        }
+    }
+}
+
+/// Log-structured merge tree: writes land in a sorted memtable which is flushed
+/// to immutable SSTables; reads consult the memtable, then tables newest-first.
+/// A background compactor merges tables without blocking reads or writes.
+///
+/// The memtable itself is not logged here: durability comes from the database WAL.
+#[derive(Debug)]
+pub struct LsmEngine {
+    shared: Arc<LsmShared>,
+    memtable: Memtable,
+    compactor: Option<JoinHandle<()>>,
+}
+
+/// State shared between the engine and its compactor thread.
+#[derive(Debug)]
+struct LsmShared {
+    dir: PathBuf,
+    options: LsmOptions,
+    /// Live tables, newest first, as recorded in the manifest.
+    tables: RwLock<Vec<LeveledTable>>,
+    next_table_id: AtomicU64,
+    stats: Mutex<CompactionStats>,
+    bloom: BloomCounters,
+    /// Serializes compactions so two never pick overlapping inputs.
+    compaction_lock: Mutex<()>,
+    signal: Mutex<Signal>,
+    wakeup: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct BloomCounters {
+    negatives: AtomicU64,
+    positives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl BloomCounters {
+    fn snapshot(&self) -> BloomStats {
+        BloomStats {
+            negatives: self.negatives.load(Ordering::Relaxed),
+            positives: self.positives.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Signal {
+    pending: bool,
+    shutdown: bool,
+}
+
+impl LsmEngine {
+    pub fn open(dir: &Path, options: LsmOptions) -> Result<LsmEngine, Error> {
+        std::fs::create_dir_all(dir)?;
+        let listed = read_manifest(dir)?;
+        let tables = listed
+            .iter()
+            .map(|&(id, level)| {
+                SsTable::open(&table_path(dir, id), id).map(|table| LeveledTable { table: Arc::new(table), level })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let ids: Vec<u64> = listed.iter().map(|(id, _)| *id).collect();
+        remove_unlisted_files(dir, &ids)?;
+
+        let background = options.background_compaction;
+        let shared = Arc::new(LsmShared {
+            dir: dir.to_path_buf(),
+            options,
+            tables: RwLock::new(tables),
+            next_table_id: AtomicU64::new(ids.iter().max().map_or(1, |id| id + 1)),
+            stats: Mutex::new(CompactionStats::default()),
+            bloom: BloomCounters::default(),
+            compaction_lock: Mutex::new(()),
+            signal: Mutex::new(Signal { pending: true, shutdown: false }),
+            wakeup: Condvar::new(),
+        });
+        let compactor = if background {
+            let shared = Arc::clone(&shared);
+            Some(
+                thread::Builder::new()
+                    .name("oxidoc-compactor".to_string())
+                    .spawn(move || compaction_loop(shared))?,
+            )
+        } else {
+            None
+        };
+        Ok(LsmEngine { shared, memtable: Memtable::new(), compactor })
+    }
+
+    /// Writes the memtable out as the newest SSTable.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = self.shared.allocate_table_id();
+        let entries = self
+            .memtable
+            .scan(&KeyRange::all(), false)
+            .map(|(k, v)| Ok((k.clone(), v.clone())));
+        let options = &self.shared.options;
+        let table = SsTable::write(
+            &table_path(&self.shared.dir, id),
+            id,
+            entries,
+            options.block_bytes,
+            options.bloom_false_positive_rate,
+        )?;
+        {
+            let mut tables = self.shared.tables_mut();
+            tables.insert(0, LeveledTable { table: Arc::new(table), level: 0 });
+            write_manifest(&self.shared.dir, &tables)?;
+        }
+        self.memtable = Memtable::new();
+        self.shared.notify_compactor();
+        Ok(())
+    }
+
+    /// Runs one compaction round in the calling thread. Returns whether any
+    /// tables were merged.
+    pub fn compact(&self) -> Result<bool, Error> {
+        self.shared.compact_once()
+    }
+
+    pub fn table_count(&self) -> usize {
+        self.shared.tables().len()
+    }
+
+    fn maybe_flush(&mut self) -> Result<(), Error> {
+        if self.memtable.approx_bytes() >= self.shared.options.memtable_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn stop_compactor(&mut self) {
+        if let Some(handle) = self.compactor.take() {
+            self.shared.signal().shutdown = true;
+            self.shared.wakeup.notify_all();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for LsmEngine {
+    fn drop(&mut self) {
+        self.stop_compactor();
+    }
+}
+
+impl StorageEngine for LsmEngine {
+    fn get(&self, key: &str) -> Result<Option<Versioned>, Error> {
+        if let Some(slot) = self.memtable.get(key) {
+            return Ok(slot.clone());
+        }
+        let bloom = &self.shared.bloom;
+        for table in self.shared.tables() {
+            if !table.may_contain(key) {
+                bloom.negatives.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            if let Some(slot) = table.get(key)? {
+                if table.has_filter() {
+                    bloom.positives.fetch_add(1, Ordering::Relaxed);
+                }
+                return Ok(slot);
+            }
+            if table.has_filter() {
+                bloom.positives.fetch_add(1, Ordering::Relaxed);
+                bloom.false_positives.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(None)
+    }
+
+    fn put(&mut self, key: String, value: Versioned) -> Result<(), Error> {
+        self.memtable.put(key, value);
+        self.maybe_flush()
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.memtable.delete(key.to_string());
+        self.maybe_flush()
+    }
+
+    fn scan(&self, range: &KeyRange, reverse: bool) -> Result<EntryIter<'_>, Error> {
+        let tables = self.shared.tables();
+        let mut sources: Vec<SlotIter<'_>> = Vec::with_capacity(tables.len() + 1);
+        sources.push(Box::new(
+            self.memtable
+                .scan(range, reverse)
+                .map(|(k, v)| Ok((k.clone(), v.clone()))),
+        ));
+        for table in &tables {
+            sources.push(Box::new(table.scan(range, reverse)));
+        }
+        Ok(Box::new(MergeIter::new(sources, reverse).filter_map(|entry| match entry {
+            Ok((key, Some(doc))) => Some(Ok((key, doc))),
+            Ok((_, None)) => None,
+            Err(e) => Some(Err(e)),
+        })))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        LsmEngine::flush(self)
+    }
+
+    fn destroy(&mut self) -> Result<(), Error> {
+        self.stop_compactor();
+        self.shared.tables_mut().clear();
+        self.memtable = Memtable::new();
+        match std::fs::remove_dir_all(&self.shared.dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn stats(&self) -> EngineStats {
+        EngineStats {
+            tables: self.table_count(),
+            compaction: lock(&self.shared.stats).clone(),
+            bloom: self.shared.bloom.snapshot(),
+        }
+    }
+}
+
+impl LsmShared {
+    fn allocate_table_id(&self) -> u64 {
+        self.next_table_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Point-in-time copy of the table list, newest first.
+    fn tables(&self) -> Vec<Arc<SsTable>> {
+        self.tables
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|t| Arc::clone(&t.table))
+            .collect()
+    }
+
+    fn tables_mut(&self) -> std::sync::RwLockWriteGuard<'_, Vec<LeveledTable>> {
+        self.tables.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn signal(&self) -> MutexGuard<'_, Signal> {
+        lock(&self.signal)
+    }
+
+    fn notify_compactor(&self) {
+        self.signal().pending = true;
+        self.wakeup.notify_all();
+    }
+
+    fn compact_once(&self) -> Result<bool, Error> {
+        let _serial = lock(&self.compaction_lock);
+        let (inputs, output_level, bottom) = {
+            let tables = self.tables.read().unwrap_or_else(|e| e.into_inner());
+            let Some(plan) = self.options.compaction.plan(&tables) else {
+                return Ok(false);
+            };
+            let bottom = plan.inputs.end == tables.len();
+            (tables[plan.inputs].to_vec(), plan.output_level, bottom)
+        };
+
+        {
+            let mut stats = lock(&self.stats);
+            stats.running = true;
+            stats.input_tables = inputs.len();
+            stats.entries_written = 0;
+        }
+        let result = self.merge(&inputs, output_level, bottom);
+        let mut stats = lock(&self.stats);
+        stats.running = false;
+        stats.input_tables = 0;
+        let output = result?;
+        stats.completed += 1;
+        stats.bytes_written += output.as_ref().map_or(0, |t| t.table.size_bytes());
+        drop(stats);
+
+        self.install(&inputs, output)?;
+        for input in &inputs {
+            let _ = input.table.remove_files();
+        }
+        Ok(true)
+    }
+
+    /// Merges `inputs` into a single table, keeping only the newest version of
+    /// each key. Tombstones are dropped when nothing older remains underneath.
+    fn merge(&self, inputs: &[LeveledTable], level: u32, bottom: bool) -> Result<Option<LeveledTable>, Error> {
+        let sources: Vec<SlotIter<'_>> = inputs
+            .iter()
+            .map(|t| Box::new(t.table.scan(&KeyRange::all(), false)) as SlotIter<'_>)
+            .collect();
+        let mut written = 0u64;
+        let mut dropped = 0u64;
+        let entries = MergeIter::new(sources, false).filter(|entry| {
+            let drop_entry = bottom && matches!(entry, Ok((_, None)));
+            if drop_entry {
+                dropped += 1;
+            } else {
+                written += 1;
+                if written.is_multiple_of(PROGRESS_INTERVAL) {
+                    lock(&self.stats).entries_written = written;
+                }
+            }
+            !drop_entry
+        });
+        let id = self.allocate_table_id();
+        let path = table_path(&self.dir, id);
+        let table = SsTable::write(&path, id, entries, self.options.block_bytes, self.options.bloom_false_positive_rate)?;
+        {
+            let mut stats = lock(&self.stats);
+            stats.entries_written = written;
+            stats.tombstones_dropped += dropped;
+        }
+        if written == 0 {
+            drop(table);
+            std::fs::remove_file(&path)?;
+            return Ok(None);
+        }
+        Ok(Some(LeveledTable { table: Arc::new(table), level }))
+    }
+
+    /// Swaps the compacted `inputs` for `output` in the table list.
+    fn install(&self, inputs: &[LeveledTable], output: Option<LeveledTable>) -> Result<(), Error> {
+        let mut tables = self.tables_mut();
+        let start = tables
+            .iter()
+            .position(|t| t.table.id() == inputs[0].table.id())
+            .ok_or_else(|| Error::other("Compaction input vanished from the table list"))?;
+        tables.splice(start..start + inputs.len(), output);
+        write_manifest(&self.dir, &tables)
+    }
+}
+
+fn compaction_loop(shared: Arc<LsmShared>) {
+    loop {
+        {
+            let mut signal = shared.signal();
+            while !signal.pending && !signal.shutdown {
+                signal = shared.wakeup.wait(signal).unwrap_or_else(|e| e.into_inner());
+            }
+            if signal.shutdown {
+                return;
+            }
+            signal.pending = false;
+        }
+        loop {
+            if shared.signal().shutdown {
+                return;
+            }
+            match shared.compact_once() {
+                Ok(true) => continue,
+                Ok(false) => break,
+                Err(e) => {
+                    eprintln!("Compaction failed in {}: {}", shared.dir.display(), e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.{}", id, TABLE_EXTENSION))
+}
+
+/// `(id, level)` of every live table, newest first.
+fn read_manifest(dir: &Path) -> Result<Vec<(u64, u32)>, Error> {
+    let content = match std::fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let corrupt = |e: std::num::ParseIntError| Error::new(ErrorKind::InvalidData, format!("Corrupt manifest: {}", e));
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let mut fields = line.split_whitespace();
+            let id = fields.next().unwrap_or_default().parse::<u64>().map_err(corrupt)?;
+            let level = fields.next().map_or(Ok(0), |l| l.parse::<u32>()).map_err(corrupt)?;
+            Ok((id, level))
+        })
+        .collect()
+}
+
+/// Atomically replaces the manifest with `tables`.
+fn write_manifest(dir: &Path, tables: &[LeveledTable]) -> Result<(), Error> {
+    let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = File::create(&tmp_path)?;
+    for t in tables {
+        writeln!(file, "{} {}", t.table.id(), t.level)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(tmp_path, dir.join(MANIFEST_FILE))
+}
+
+/// Drops leftovers of interrupted flushes and compactions: temporary files and
+/// tables (with their filters) that are not, or no longer, in the manifest.
+fn remove_unlisted_files(dir: &Path, ids: &[u64]) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let stale = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("tmp") => true,
+            Some(TABLE_EXTENSION | sstable::FILTER_EXTENSION) => !path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+                .is_some_and(|id| ids.contains(&id)),
+            _ => false,
+        };
+        if stale {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::test_util::versioned;
+    use serde_json::json;
+
+    fn small_options() -> LsmOptions {
+        LsmOptions {
+            memtable_bytes: 256,
+            block_bytes: 64,
+            background_compaction: false,
+            ..LsmOptions::default()
+        }
+    }
+
+    fn manual_options() -> LsmOptions {
+        LsmOptions { background_compaction: false, ..LsmOptions::default() }
+    }
+
+    #[test]
+    fn put_get_delete_through_memtable() {
+        let dir = TempDir::new("lsm");
+        let mut engine = LsmEngine::open(dir.path(), manual_options()).unwrap();
+        engine.put("k1".to_string(), versioned(json!({"v": 1}))).unwrap();
+        assert_eq!(engine.get("k1").unwrap(), Some(versioned(json!({"v": 1}))));
+        engine.delete("k1").unwrap();
+        assert_eq!(engine.get("k1").unwrap(), None);
+        assert_eq!(engine.table_count(), 0);
+    }
+
+    #[test]
+    fn memtable_flushes_to_sstables_when_full() {
+        let dir = TempDir::new("lsm");
+        let mut engine = LsmEngine::open(dir.path(), small_options()).unwrap();
+        for i in 0..100 {
+            engine.put(format!("key{:03}", i), versioned(json!({"n": i}))).unwrap();
+        }
+        assert!(engine.table_count() > 1);
+        assert_eq!(engine.get("key000").unwrap(), Some(versioned(json!({"n": 0}))));
+        assert_eq!(engine.get("key099").unwrap(), Some(versioned(json!({"n": 99}))));
+    }
+
+    #[test]
+    fn newest_table_shadows_older_values_and_tombstones() {
+        let dir = TempDir::new("lsm");
+        let mut engine = LsmEngine::open(dir.path(), manual_options()).unwrap();
+        engine.put("a".to_string(), versioned(json!("old"))).unwrap();
+        engine.put("b".to_string(), versioned(json!("keep"))).unwrap();
+        engine.flush().unwrap();
+        engine.put("a".to_string(), versioned(json!("new"))).unwrap();
+        engine.delete("b").unwrap();
+        engine.flush().unwrap();
+        assert_eq!(engine.table_count(), 2);
+        assert_eq!(engine.get("a").unwrap(), Some(versioned(json!("new"))));
+        assert_eq!(engine.get("b").unwrap(), None);
+        let live: Vec<(String, Versioned)> = engine.iter().unwrap().map(Result::unwrap).collect();
+        assert_eq!(live, vec![("a".to_string(), versioned(json!("new")))]);
+    }
+
+    #[test]
+    fn flushed_tables_survive_reopen() {
+        let dir = TempDir::new("lsm");
+        {
+            let mut engine = LsmEngine::open(dir.path(), manual_options()).unwrap();
+            engine.put("a".to_string(), versioned(json!(1))).unwrap();
+            engine.flush().unwrap();
+        }
+        std::fs::write(dir.path().join("000099.sst"), b"leftover").unwrap();
+        let engine = LsmEngine::open(dir.path(), manual_options()).unwrap();
+        assert_eq!(engine.get("a").unwrap(), Some(versioned(json!(1))));
+        assert!(!dir.path().join("000099.sst").exists());
+    }
+
+    #[test]
+    fn destroy_removes_directory() {
+        let dir = TempDir::new("lsm");
+        let path = dir.path().join("coll");
+        let mut engine = LsmEngine::open(&path, manual_options()).unwrap();
+        engine.put("a".to_string(), versioned(json!(1))).unwrap();
+        engine.flush().unwrap();
+        engine.destroy().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn compaction_merges_tables_and_drops_shadowed_data() {
+        let dir = TempDir::new("lsm");
+        let options = LsmOptions {
+            compaction: CompactionStrategy::SizeTiered { min_tables: 3 },
+            ..manual_options()
+        };
+        let mut engine = LsmEngine::open(dir.path(), options.clone()).unwrap();
+        engine.put("a".to_string(), versioned(json!(1))).unwrap();
+        engine.put("b".to_string(), versioned(json!(1))).unwrap();
+        engine.flush().unwrap();
+        engine.put("a".to_string(), versioned(json!(2))).unwrap();
+        engine.flush().unwrap();
+        engine.delete("b").unwrap();
+        engine.flush().unwrap();
+        assert_eq!(engine.table_count(), 3);
+
+        assert!(engine.compact().unwrap());
+        assert_eq!(engine.table_count(), 1);
+        assert!(!engine.compact().unwrap());
+        let stats = engine.stats().compaction;
+        assert_eq!(stats.completed, 1);
+        assert_eq!(stats.entries_written, 1);
+        assert_eq!(stats.tombstones_dropped, 1);
+        assert!(!stats.running);
+
+        drop(engine);
+        let engine = LsmEngine::open(dir.path(), options).unwrap();
+        assert_eq!(engine.table_count(), 1);
+        assert_eq!(engine.get("a").unwrap(), Some(versioned(json!(2))));
+        assert_eq!(engine.get("b").unwrap(), None);
+        let mut files: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files, vec!["000004.filter", "000004.sst", "MANIFEST"]);
+    }
+
+    #[test]
+    fn background_compactor_keeps_table_count_bounded() {
+        let dir = TempDir::new("lsm");
+        let options = LsmOptions {
+            memtable_bytes: 64,
+            compaction: CompactionStrategy::SizeTiered { min_tables: 2 },
+            ..LsmOptions::default()
+        };
+        let mut engine = LsmEngine::open(dir.path(), options).unwrap();
+        for i in 0..40 {
+            engine.put(format!("key{:02}", i % 10), versioned(json!({"n": i}))).unwrap();
+        }
+        let compacted = (0..100).any(|_| {
+            thread::sleep(std::time::Duration::from_millis(10));
+            engine.stats().compaction.completed > 0 && !engine.stats().compaction.running
+        });
+        assert!(compacted);
+        for i in 30..40 {
+            assert_eq!(engine.get(&format!("key{:02}", i % 10)).unwrap(), Some(versioned(json!({"n": i}))));
+        }
+    }
+
+    #[test]
+    fn bloom_filters_skip_tables_for_missing_keys() {
+        let dir = TempDir::new("lsm");
+        let mut engine = LsmEngine::open(dir.path(), manual_options()).unwrap();
+        for i in 0..50 {
+            engine.put(format!("key{:02}", i), versioned(json!(i))).unwrap();
+        }
+        engine.flush().unwrap();
+        engine.put("other".to_string(), versioned(json!(0))).unwrap();
+        engine.flush().unwrap();
+
+        for i in 0..100 {
+            assert_eq!(engine.get(&format!("missing{}", i)).unwrap(), None);
+        }
+        assert_eq!(engine.get("key07").unwrap(), Some(versioned(json!(7))));
+        let bloom = engine.stats().bloom;
+        assert!(bloom.negatives >= 190, "{:?}", bloom);
+        assert_eq!(bloom.positives - bloom.false_positives, 1);
+    }
+
+    #[test]
+    fn tables_without_filters_are_still_readable() {
+        let dir = TempDir::new("lsm");
+        let options = LsmOptions { bloom_false_positive_rate: None, ..manual_options() };
+        let mut engine = LsmEngine::open(dir.path(), options).unwrap();
+        engine.put("a".to_string(), versioned(json!(1))).unwrap();
+        engine.flush().unwrap();
+        assert_eq!(engine.get("a").unwrap(), Some(versioned(json!(1))));
+        assert_eq!(engine.get("b").unwrap(), None);
+        assert_eq!(engine.stats().bloom, BloomStats::default());
+    }
+
+    #[test]
+    fn scan_merges_memtable_and_tables_in_both_directions() {
+        let dir = TempDir::new("lsm");
+        let mut engine = LsmEngine::open(dir.path(), manual_options()).unwrap();
+        engine.put("a".to_string(), versioned(json!(1))).unwrap();
+        engine.put("b".to_string(), versioned(json!(1))).unwrap();
+        engine.put("c".to_string(), versioned(json!(1))).unwrap();
+        engine.flush().unwrap();
+        engine.put("b".to_string(), versioned(json!(2))).unwrap();
+        engine.delete("c").unwrap();
+        engine.put("d".to_string(), versioned(json!(2))).unwrap();
+
+        let forward: Vec<(String, Versioned)> = engine.scan(&KeyRange::all(), false).unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            forward,
+            vec![("a".to_string(), versioned(json!(1))), ("b".to_string(), versioned(json!(2))), ("d".to_string(), versioned(json!(2)))]
+        );
+        let range = KeyRange::new(std::ops::Bound::Excluded("a".to_string()), std::ops::Bound::Unbounded);
+        let backward: Vec<String> = engine.scan(&range, true).unwrap().map(|e| e.unwrap().0).collect();
+        assert_eq!(backward, vec!["d", "b"]);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::collection::Versioned;
+use crate::storage::engine::{EntryIter, StorageEngine};
+use crate::storage::range::KeyRange;
+use std::collections::BTreeMap;
+use std::io::Error;
+
+/// Volatile engine keeping every document in an ordered map.
+#[derive(Debug, Default)]
+pub struct MemoryEngine {
+    data: BTreeMap<String, Versioned>,
+}
+
+impl MemoryEngine {
+    pub fn new() -> MemoryEngine {
+        MemoryEngine { data: BTreeMap::new() }
+    }
+}
+
+impl StorageEngine for MemoryEngine {
+    fn get(&self, key: &str) -> Result<Option<Versioned>, Error> {
+        Ok(self.data.get(key).cloned())
+    }
+
+    fn put(&mut self, key: String, value: Versioned) -> Result<(), Error> {
+        self.data.insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Error> {
+        self.data.remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, range: &KeyRange, reverse: bool) -> Result<EntryIter<'_>, Error> {
+        if range.is_empty() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let entries = self
+            .data
+            .range::<str, _>((range.start_bound(), range.end_bound()))
+            .map(|(k, v)| Ok((k.clone(), v.clone())));
+        if reverse { Ok(Box::new(entries.rev())) } else { Ok(Box::new(entries)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::versioned;
+    use serde_json::json;
+
+    #[test]
+    fn scan_returns_range_in_both_directions() {
+        let mut engine = MemoryEngine::new();
+        for key in ["a1", "a2", "a3", "b1"] {
+            engine.put(key.to_string(), versioned(json!(key))).unwrap();
+        }
+        let keys: Vec<String> = engine.scan(&KeyRange::prefix("a"), true).unwrap().map(|e| e.unwrap().0).collect();
+        assert_eq!(keys, vec!["a3", "a2", "a1"]);
+        let empty = KeyRange::new(std::ops::Bound::Included("b".to_string()), std::ops::Bound::Excluded("a".to_string()));
+        assert_eq!(engine.scan(&empty, false).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::collection::Versioned;
+use crate::storage::range::KeyRange;
+use std::collections::BTreeMap;
+
+/// A key's newest state: `None` is a tombstone left by a delete.
+pub type Slot = Option<Versioned>;
+
+/// Sorted in-memory write buffer of an LSM tree.
+#[derive(Debug, Default)]
+pub struct Memtable {
+    entries: BTreeMap<String, Slot>,
+    approx_bytes: usize,
+}
+
+impl Memtable {
+    pub fn new() -> Memtable {
+        Memtable::default()
+    }
+
+    pub fn put(&mut self, key: String, value: Versioned) {
+        self.approx_bytes += key.len() + size_of::<u64>() + value.doc.to_string().len();
+        self.entries.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: String) {
+        self.approx_bytes += key.len();
+        self.entries.insert(key, None);
+    }
+
+    /// `Some(None)` means the key was deleted here and older tables must not be consulted.
+    pub fn get(&self, key: &str) -> Option<&Slot> {
+        self.entries.get(key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn approx_bytes(&self) -> usize {
+        self.approx_bytes
+    }
+
+    /// Entries of `range`, tombstones included, ascending or descending.
+    pub fn scan(&self, range: &KeyRange, reverse: bool) -> Box<dyn Iterator<Item = (&String, &Slot)> + '_> {
+        if range.is_empty() {
+            return Box::new(std::iter::empty());
+        }
+        let entries = self.entries.range::<str, _>((range.start_bound(), range.end_bound()));
+        if reverse { Box::new(entries.rev()) } else { Box::new(entries) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::versioned;
+    use serde_json::json;
+
+    #[test]
+    fn delete_leaves_tombstone() {
+        let mut memtable = Memtable::new();
+        memtable.put("k".to_string(), versioned(json!(1)));
+        memtable.delete("k".to_string());
+        assert_eq!(memtable.get("k"), Some(&None));
+        assert_eq!(memtable.get("other"), None);
+    }
+
+    #[test]
+    fn iterates_in_key_order() {
+        let mut memtable = Memtable::new();
+        memtable.put("b".to_string(), versioned(json!(2)));
+        memtable.put("a".to_string(), versioned(json!(1)));
+        memtable.delete("c".to_string());
+        let keys: Vec<&String> = memtable.scan(&KeyRange::all(), false).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+        let keys: Vec<&String> = memtable.scan(&KeyRange::prefix("b"), true).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec!["b"]);
+        assert!(memtable.approx_bytes() > 0);
+    }
+}
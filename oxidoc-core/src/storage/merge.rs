@@ -0,0 +1,108 @@
+use crate::storage::memtable::Slot;
+use std::io::Error;
+
+pub type SlotIter<'a> = Box<dyn Iterator<Item = Result<(String, Slot), Error>> + 'a>;
+
+/// K-way merge of key-sorted sources, all ascending or (with `reverse`) all
+/// descending. When several sources hold the same key only the entry from the
+/// earliest source (the newest data) is yielded.
+pub struct MergeIter<'a> {
+    sources: Vec<SlotIter<'a>>,
+    heads: Vec<Option<(String, Slot)>>,
+    reverse: bool,
+    primed: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(sources: Vec<SlotIter<'a>>, reverse: bool) -> MergeIter<'a> {
+        let heads = sources.iter().map(|_| None).collect();
+        MergeIter { sources, heads, reverse, primed: false }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<(), Error> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, Slot)>, Error> {
+        if !self.primed {
+            self.primed = true;
+            for i in 0..self.sources.len() {
+                self.advance(i)?;
+            }
+        }
+        let comes_first = |key: &String, other: &String| if self.reverse { key > other } else { key < other };
+        let mut newest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head
+                && newest.is_none_or(|n| comes_first(key, &self.heads[n].as_ref().unwrap().0))
+            {
+                newest = Some(i);
+            }
+        }
+        let Some(newest) = newest else {
+            return Ok(None);
+        };
+        let entry = self.heads[newest].take().unwrap();
+        for i in 0..self.heads.len() {
+            if i == newest || self.heads[i].as_ref().is_some_and(|(k, _)| *k == entry.0) {
+                self.advance(i)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<(String, Slot), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::versioned;
+    use serde_json::json;
+
+    fn source(entries: Vec<(&'static str, Slot)>) -> SlotIter<'static> {
+        Box::new(entries.into_iter().map(|(k, v)| Ok((k.to_string(), v))))
+    }
+
+    #[test]
+    fn merges_in_key_order_with_newest_winning() {
+        let newest = source(vec![("b", Some(versioned(json!("new")))), ("d", None)]);
+        let oldest = source(vec![("a", Some(versioned(json!(1)))), ("b", Some(versioned(json!("old")))), ("d", Some(versioned(json!(4))))]);
+        let merged: Vec<(String, Slot)> = MergeIter::new(vec![newest, oldest], false).map(Result::unwrap).collect();
+        assert_eq!(
+            merged,
+            vec![
+                ("a".to_string(), Some(versioned(json!(1)))),
+                ("b".to_string(), Some(versioned(json!("new")))),
+                ("d".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_of_no_sources_is_empty() {
+        assert!(MergeIter::new(Vec::new(), false).next().is_none());
+    }
+
+    #[test]
+    fn merges_descending_sources_in_reverse() {
+        let newest = source(vec![("d", None), ("b", Some(versioned(json!("new"))))]);
+        let oldest = source(vec![("d", Some(versioned(json!(4)))), ("b", Some(versioned(json!("old")))), ("a", Some(versioned(json!(1))))]);
+        let keys: Vec<(String, Slot)> = MergeIter::new(vec![newest, oldest], true).map(Result::unwrap).collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("d".to_string(), None),
+                ("b".to_string(), Some(versioned(json!("new")))),
+                ("a".to_string(), Some(versioned(json!(1)))),
+            ]
+        );
+    }
+}
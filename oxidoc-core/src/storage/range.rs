@@ -0,0 +1,147 @@
+use std::cmp::Ordering;
+use std::ops::Bound;
+
+/// Key interval used by scans. Keys compare as strings (byte order).
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyRange {
+    pub start: Bound<String>,
+    pub end: Bound<String>,
+}
+
+impl Default for KeyRange {
+    fn default() -> Self {
+        KeyRange::all()
+    }
+}
+
+impl KeyRange {
+    pub fn all() -> KeyRange {
+        KeyRange { start: Bound::Unbounded, end: Bound::Unbounded }
+    }
+
+    pub fn new(start: Bound<String>, end: Bound<String>) -> KeyRange {
+        KeyRange { start, end }
+    }
+
+    /// Every key starting with `prefix`.
+    pub fn prefix(prefix: &str) -> KeyRange {
+        KeyRange {
+            start: Bound::Included(prefix.to_string()),
+            end: prefix_end(prefix),
+        }
+    }
+
+    /// Narrows this range to the part also covered by `other`.
+    pub fn intersect(self, other: KeyRange) -> KeyRange {
+        KeyRange {
+            start: tighter(self.start, other.start, Ordering::Greater),
+            end: tighter(self.end, other.end, Ordering::Less),
+        }
+    }
+
+    pub fn start_bound(&self) -> Bound<&str> {
+        self.start.as_ref().map(String::as_str)
+    }
+
+    pub fn end_bound(&self) -> Bound<&str> {
+        self.end.as_ref().map(String::as_str)
+    }
+
+    pub fn is_after_start(&self, key: &str) -> bool {
+        match &self.start {
+            Bound::Included(s) => key >= s.as_str(),
+            Bound::Excluded(s) => key > s.as_str(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    pub fn is_before_end(&self, key: &str) -> bool {
+        match &self.end {
+            Bound::Included(e) => key <= e.as_str(),
+            Bound::Excluded(e) => key < e.as_str(),
+            Bound::Unbounded => true,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.is_after_start(key) && self.is_before_end(key)
+    }
+
+    /// `true` when no key can fall in the range. Ordered maps panic on such
+    /// ranges, so engines check this first.
+    pub fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(s), Bound::Included(e)) => s > e,
+            (Bound::Included(s), Bound::Excluded(e))
+            | (Bound::Excluded(s), Bound::Included(e))
+            | (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+            _ => false,
+        }
+    }
+}
+
+/// Smallest string greater than every string starting with `prefix`.
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            chars.push(next);
+            return Bound::Excluded(chars.into_iter().collect());
+        }
+    }
+    Bound::Unbounded
+}
+
+/// Picks the more restrictive of two bounds: the larger for starts
+/// (`prefer = Greater`), the smaller for ends (`prefer = Less`).
+fn tighter(a: Bound<String>, b: Bound<String>, prefer: Ordering) -> Bound<String> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => match x.cmp(y) {
+            Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+            Ordering::Equal => b,
+            order if order == prefer => a,
+            _ => b,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_range_covers_only_prefixed_keys() {
+        let range = KeyRange::prefix("user:");
+        assert!(range.contains("user:"));
+        assert!(range.contains("user:zzz"));
+        assert!(!range.contains("user;"));
+        assert!(!range.contains("use"));
+        assert_eq!(range.end, Bound::Excluded("user;".to_string()));
+    }
+
+    #[test]
+    fn prefix_of_max_chars_is_unbounded() {
+        let prefix = char::MAX.to_string();
+        assert_eq!(KeyRange::prefix(&prefix).end, Bound::Unbounded);
+    }
+
+    #[test]
+    fn intersect_keeps_tightest_bounds() {
+        let range = KeyRange::prefix("a").intersect(KeyRange::new(
+            Bound::Excluded("a5".to_string()),
+            Bound::Excluded("z".to_string()),
+        ));
+        assert_eq!(range.start, Bound::Excluded("a5".to_string()));
+        assert_eq!(range.end, Bound::Excluded("b".to_string()));
+    }
+
+    #[test]
+    fn detects_empty_ranges() {
+        assert!(KeyRange::new(Bound::Included("b".to_string()), Bound::Excluded("a".to_string())).is_empty());
+        assert!(KeyRange::new(Bound::Excluded("a".to_string()), Bound::Excluded("a".to_string())).is_empty());
+        assert!(!KeyRange::new(Bound::Included("a".to_string()), Bound::Included("a".to_string())).is_empty());
+        assert!(!KeyRange::all().is_empty());
+    }
+}
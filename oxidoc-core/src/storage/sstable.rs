@@ -0,0 +1,480 @@
+use crate::checksum::crc32;
+use crate::codec::{Decoder, Encode};
+use crate::collection::Versioned;
+use crate::storage::bloom::{key_hash, BloomFilter};
+use crate::storage::memtable::Slot;
+use crate::storage::range::KeyRange;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const MAGIC: u64 = u64::from_be_bytes(*b"OXSST001");
+/// Footer: index offset (u64), index length (u32), index CRC-32 (u32), magic (u64).
+const FOOTER_LEN: u64 = 24;
+
+pub const FILTER_EXTENSION: &str = "filter";
+
+const TAG_TOMBSTONE: u8 = 0;
+/// Unversioned value written before documents carried versions; read as version 0.
+const TAG_VALUE: u8 = 1;
+const TAG_VERSIONED: u8 = 2;
+/// Versioned value followed by its expiry (Unix milliseconds).
+const TAG_EXPIRING: u8 = 3;
+
+/// Location of a data block, keyed by the last key it contains.
+#[derive(Debug, Clone)]
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+/// Immutable, sorted on-disk table.
+///
+/// Layout: `[data block]* [index block] [footer]`. Data blocks hold
+/// `(key, tag, version, [expiry,] value)` entries in key order; the index block lists every data
+/// block with its last key and checksum so a lookup reads at most one block.
+/// An optional Bloom filter over the keys is stored next to the table in a
+/// `.filter` file.
+#[derive(Debug)]
+pub struct SsTable {
+    id: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+    filter: Option<BloomFilter>,
+    size_bytes: u64,
+}
+
+impl SsTable {
+    /// Writes `entries` (sorted, unique keys) to `path` and opens the result,
+    /// building a Bloom filter at `bloom_rate` false positives when given.
+    /// The table only becomes visible under `path` once it is fully synced.
+    pub fn write<I>(path: &Path, id: u64, entries: I, block_size: usize, bloom_rate: Option<f64>) -> Result<SsTable, Error>
+    where
+        I: Iterator<Item = Result<(String, Slot), Error>>,
+    {
+        let tmp_path = path.with_extension("tmp");
+        let mut out = BufWriter::new(File::create(&tmp_path)?);
+        let mut index = Vec::new();
+        let mut block = Vec::new();
+        let mut offset = 0u64;
+        let mut last_key = String::new();
+        let mut key_hashes = Vec::new();
+
+        for entry in entries {
+            let (key, slot) = entry?;
+            if bloom_rate.is_some() {
+                key_hashes.push(key_hash(&key));
+            }
+            block.put_str(&key);
+            match &slot {
+                Some(value) => {
+                    block.put_u8(if value.expires_at.is_some() { TAG_EXPIRING } else { TAG_VERSIONED });
+                    block.put_u64(value.version);
+                    if let Some(at) = value.expires_at {
+                        block.put_u64(at);
+                    }
+                    block.put_bytes(&serde_json::to_vec(&value.doc)?);
+                }
+                None => block.put_u8(TAG_TOMBSTONE),
+            }
+            last_key = key;
+            if block.len() >= block_size {
+                index.push(flush_block(&mut out, &mut block, &mut offset, &last_key)?);
+            }
+        }
+        if !block.is_empty() {
+            index.push(flush_block(&mut out, &mut block, &mut offset, &last_key)?);
+        }
+
+        let mut index_block = Vec::new();
+        index_block.put_u32(index.len() as u32);
+        for handle in &index {
+            index_block.put_str(&handle.last_key);
+            index_block.put_u64(handle.offset);
+            index_block.put_u32(handle.len);
+            index_block.put_u32(handle.crc);
+        }
+        let mut footer = Vec::new();
+        footer.put_u64(offset);
+        footer.put_u32(index_block.len() as u32);
+        footer.put_u32(crc32(&index_block));
+        footer.put_u64(MAGIC);
+        out.write_all(&index_block)?;
+        out.write_all(&footer)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        if let Some(rate) = bloom_rate {
+            let mut filter = BloomFilter::with_rate(key_hashes.len(), rate);
+            key_hashes.into_iter().for_each(|h| filter.insert_hash(h));
+            let filter_path = filter_path(path);
+            let filter_tmp = filter_path.with_extension("filter.tmp");
+            let mut file = File::create(&filter_tmp)?;
+            file.write_all(&filter.encode())?;
+            file.sync_all()?;
+            std::fs::rename(&filter_tmp, &filter_path)?;
+        }
+        std::fs::rename(&tmp_path, path)?;
+        SsTable::open(path, id)
+    }
+
+    pub fn open(path: &Path, id: u64) -> Result<SsTable, Error> {
+        let mut file = OpenOptions::new().read(true).open(path)?;
+        let size_bytes = file.metadata()?.len();
+        if size_bytes < FOOTER_LEN {
+            return Err(corrupt(path, "file too short"));
+        }
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let mut dec = Decoder::new(&footer);
+        let index_offset = dec.get_u64()?;
+        let index_len = dec.get_u32()?;
+        let index_crc = dec.get_u32()?;
+        if dec.get_u64()? != MAGIC || index_offset + index_len as u64 + FOOTER_LEN != size_bytes {
+            return Err(corrupt(path, "bad footer"));
+        }
+
+        let index_block = read_at(&mut file, index_offset, index_len)?;
+        if crc32(&index_block) != index_crc {
+            return Err(corrupt(path, "index checksum mismatch"));
+        }
+        let mut dec = Decoder::new(&index_block);
+        let count = dec.get_u32()?;
+        let mut index = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            index.push(BlockHandle {
+                last_key: dec.get_string()?,
+                offset: dec.get_u64()?,
+                len: dec.get_u32()?,
+                crc: dec.get_u32()?,
+            });
+        }
+        Ok(SsTable {
+            id,
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            index,
+            filter: read_filter(path),
+            size_bytes,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        self.size_bytes
+    }
+
+    pub fn has_filter(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// Consults the Bloom filter: `false` means the key is definitely not in
+    /// this table. Tables without a filter always answer `true`.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.filter.as_ref().is_none_or(|f| f.may_contain(key))
+    }
+
+    /// Deletes the table and its filter from disk.
+    pub fn remove_files(&self) -> Result<(), Error> {
+        match std::fs::remove_file(filter_path(&self.path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::remove_file(&self.path)
+    }
+
+    /// `None` when the key is not in this table, `Some(None)` for a tombstone.
+    pub fn get(&self, key: &str) -> Result<Option<Slot>, Error> {
+        let block_idx = self.index.partition_point(|h| h.last_key.as_str() < key);
+        let Some(handle) = self.index.get(block_idx) else {
+            return Ok(None);
+        };
+        let entries = self.read_block(handle)?;
+        Ok(entries
+            .binary_search_by(|(k, _)| k.as_str().cmp(key))
+            .ok()
+            .map(|i| entries[i].1.clone()))
+    }
+
+    /// Iterates the entries of `range`, tombstones included, in ascending key
+    /// order or descending when `reverse` is set.
+    pub fn scan(self: &Arc<Self>, range: &KeyRange, reverse: bool) -> SsTableIter {
+        let first_block = |bound: Bound<&str>| match bound {
+            Bound::Included(k) | Bound::Excluded(k) => self.index.partition_point(|h| h.last_key.as_str() < k),
+            Bound::Unbounded => 0,
+        };
+        let next_block = if reverse {
+            let last = self.index.len().checked_sub(1);
+            match range.end_bound() {
+                Bound::Unbounded => last,
+                end => last.map(|last| first_block(end).min(last)),
+            }
+        } else {
+            Some(first_block(range.start_bound())).filter(|&i| i < self.index.len())
+        };
+        SsTableIter {
+            table: Arc::clone(self),
+            range: range.clone(),
+            reverse,
+            next_block,
+            entries: Vec::new().into_iter(),
+        }
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> Result<Vec<(String, Slot)>, Error> {
+        let bytes = {
+            let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+            read_at(&mut file, handle.offset, handle.len)?
+        };
+        if crc32(&bytes) != handle.crc {
+            return Err(corrupt(&self.path, "block checksum mismatch"));
+        }
+        let mut dec = Decoder::new(&bytes);
+        let mut entries = Vec::new();
+        while !dec.is_empty() {
+            let key = dec.get_string()?;
+            let slot = match dec.get_u8()? {
+                TAG_VALUE => Some(Versioned::new(0, serde_json::from_slice(dec.get_bytes()?)?)),
+                TAG_VERSIONED => Some(Versioned::new(dec.get_u64()?, serde_json::from_slice(dec.get_bytes()?)?)),
+                TAG_EXPIRING => {
+                    let (version, expires_at) = (dec.get_u64()?, dec.get_u64()?);
+                    Some(Versioned::expiring(version, serde_json::from_slice(dec.get_bytes()?)?, Some(expires_at)))
+                }
+                TAG_TOMBSTONE => None,
+                _ => return Err(corrupt(&self.path, "unknown entry tag")),
+            };
+            entries.push((key, slot));
+        }
+        Ok(entries)
+    }
+}
+
+pub struct SsTableIter {
+    table: Arc<SsTable>,
+    range: KeyRange,
+    reverse: bool,
+    /// Index of the block to load once `entries` is drained.
+    next_block: Option<usize>,
+    entries: std::vec::IntoIter<(String, Slot)>,
+}
+
+impl SsTableIter {
+    fn load_next_block(&mut self) -> Result<bool, Error> {
+        let Some(idx) = self.next_block else {
+            return Ok(false);
+        };
+        self.next_block = if self.reverse {
+            idx.checked_sub(1)
+        } else {
+            Some(idx + 1).filter(|&i| i < self.table.index.len())
+        };
+        let mut entries = self.table.read_block(&self.table.index[idx])?;
+        if self.reverse {
+            entries.reverse();
+        }
+        self.entries = entries.into_iter();
+        Ok(true)
+    }
+}
+
+impl Iterator for SsTableIter {
+    type Item = Result<(String, Slot), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            for (key, slot) in self.entries.by_ref() {
+                let (not_yet, past) = if self.reverse {
+                    (!self.range.is_before_end(&key), !self.range.is_after_start(&key))
+                } else {
+                    (!self.range.is_after_start(&key), !self.range.is_before_end(&key))
+                };
+                if past {
+                    self.next_block = None;
+                    self.entries = Vec::new().into_iter();
+                    return None;
+                }
+                if !not_yet {
+                    return Some(Ok((key, slot)));
+                }
+            }
+            match self.load_next_block() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => {
+                    self.next_block = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+fn flush_block<W: Write>(out: &mut W, block: &mut Vec<u8>, offset: &mut u64, last_key: &str) -> Result<BlockHandle, Error> {
+    out.write_all(block)?;
+    let handle = BlockHandle {
+        last_key: last_key.to_string(),
+        offset: *offset,
+        len: block.len() as u32,
+        crc: crc32(block),
+    };
+    *offset += block.len() as u64;
+    block.clear();
+    Ok(handle)
+}
+
+pub fn filter_path(table_path: &Path) -> PathBuf {
+    table_path.with_extension(FILTER_EXTENSION)
+}
+
+/// Loads the table's filter. A missing or damaged filter only costs
+/// performance, so it is reported and ignored.
+fn read_filter(table_path: &Path) -> Option<BloomFilter> {
+    let path = filter_path(table_path);
+    match std::fs::read(&path) {
+        Ok(bytes) => BloomFilter::decode(&bytes)
+            .map_err(|e| eprintln!("Ignoring Bloom filter {}: {}", path.display(), e))
+            .ok(),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("Ignoring Bloom filter {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: u32) -> Result<Vec<u8>, Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn corrupt(path: &Path, reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("Corrupt SSTable {}: {}", path.display(), reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use crate::test_util::versioned;
+    use serde_json::json;
+
+    fn sample_entries(n: usize) -> Vec<(String, Slot)> {
+        (0..n)
+            .map(|i| {
+                let slot = match i % 10 {
+                    3 => None,
+                    5 => Some(Versioned::expiring(1, json!({"n": i}), Some(i as u64))),
+                    _ => Some(versioned(json!({"n": i}))),
+                };
+                (format!("key{:04}", i), slot)
+            })
+            .collect()
+    }
+
+    fn write_table(dir: &TempDir, entries: &[(String, Slot)]) -> Arc<SsTable> {
+        let path = dir.path().join("1.sst");
+        Arc::new(SsTable::write(&path, 1, entries.iter().cloned().map(Ok), 256, Some(0.01)).unwrap())
+    }
+
+    #[test]
+    fn get_finds_values_and_tombstones() {
+        let dir = TempDir::new("sstable");
+        let table = write_table(&dir, &sample_entries(200));
+        assert_eq!(table.get("key0042").unwrap(), Some(Some(versioned(json!({"n": 42})))));
+        assert_eq!(table.get("key0043").unwrap(), Some(None));
+        assert_eq!(table.get("key9999").unwrap(), None);
+        assert_eq!(table.get("a").unwrap(), None);
+    }
+
+    #[test]
+    fn reopened_table_iterates_all_entries_in_order() {
+        let dir = TempDir::new("sstable");
+        let entries = sample_entries(200);
+        drop(write_table(&dir, &entries));
+        let table = Arc::new(SsTable::open(&dir.path().join("1.sst"), 1).unwrap());
+        let read: Vec<(String, Slot)> = table.scan(&KeyRange::all(), false).map(Result::unwrap).collect();
+        assert_eq!(read, entries);
+        let mut reversed: Vec<(String, Slot)> = table.scan(&KeyRange::all(), true).map(Result::unwrap).collect();
+        reversed.reverse();
+        assert_eq!(reversed, entries);
+    }
+
+    fn keys(iter: SsTableIter) -> Vec<String> {
+        iter.map(|e| e.unwrap().0).collect()
+    }
+
+    #[test]
+    fn scan_respects_range_bounds() {
+        let dir = TempDir::new("sstable");
+        let table = write_table(&dir, &sample_entries(200));
+        let range = KeyRange::new(Bound::Excluded("key0100".to_string()), Bound::Included("key0103".to_string()));
+        assert_eq!(keys(table.scan(&range, false)), vec!["key0101", "key0102", "key0103"]);
+        assert_eq!(keys(table.scan(&range, true)), vec!["key0103", "key0102", "key0101"]);
+        let range = KeyRange::new(Bound::Included("key0150".to_string()), Bound::Excluded("key0152".to_string()));
+        assert_eq!(keys(table.scan(&range, true)), vec!["key0151", "key0150"]);
+    }
+
+    #[test]
+    fn scan_outside_table_is_empty() {
+        let dir = TempDir::new("sstable");
+        let table = write_table(&dir, &sample_entries(20));
+        assert!(keys(table.scan(&KeyRange::prefix("zzz"), false)).is_empty());
+        assert!(keys(table.scan(&KeyRange::prefix("zzz"), true)).is_empty());
+        assert!(keys(table.scan(&KeyRange::prefix("a"), true)).is_empty());
+        assert_eq!(keys(table.scan(&KeyRange::prefix("key001"), true)).len(), 10);
+    }
+
+    #[test]
+    fn corrupted_block_is_reported() {
+        let dir = TempDir::new("sstable");
+        drop(write_table(&dir, &sample_entries(50)));
+        let path = dir.path().join("1.sst");
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[5] ^= 0xFF;
+        std::fs::write(&path, bytes).unwrap();
+        let table = SsTable::open(&path, 1).unwrap();
+        let err = table.get("key0000").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn filter_is_persisted_next_to_table() {
+        let dir = TempDir::new("sstable");
+        drop(write_table(&dir, &sample_entries(100)));
+        let path = dir.path().join("1.sst");
+        assert!(filter_path(&path).exists());
+        let table = SsTable::open(&path, 1).unwrap();
+        assert!(table.has_filter());
+        assert!(table.may_contain("key0050"));
+        assert!(table.may_contain("key0053"));
+        let absent = (0..1000).filter(|i| table.may_contain(&format!("missing{}", i))).count();
+        assert!(absent < 50);
+
+        table.remove_files().unwrap();
+        assert!(!path.exists());
+        assert!(!filter_path(&path).exists());
+    }
+
+    #[test]
+    fn table_without_filter_may_contain_anything() {
+        let dir = TempDir::new("sstable");
+        let path = dir.path().join("1.sst");
+        let table = SsTable::write(&path, 1, sample_entries(10).into_iter().map(Ok), 256, None).unwrap();
+        assert!(!table.has_filter());
+        assert!(table.may_contain("anything"));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::collection::{Document, Scan, Versioned};
+use crate::database::{check_put, Database, PutOptions};
+use crate::error::{DocumentNotFound, VersionConflict};
+use crate::query::Filter;
+use crate::update::Update;
+use crate::wal::WalRecord;
+use std::collections::BTreeMap;
+use std::io::Error;
+
+/// Writes buffered until [`Transaction::commit`], which applies all of them or none.
+///
+/// Reads see the transaction's own writes. Isolation is optimistic: the commit fails
+/// with a [`VersionConflict`] if any document the transaction read or wrote has been
+/// changed by someone else in the meantime. Keys returned by `scan` and `find` but
+/// never read by key are not checked.
+///
+/// Versions seen inside a transaction are those of the committed documents it
+/// started from (0 for a document it created); its writes get theirs at commit.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    /// Buffered writes, in the order they will be logged.
+    writes: Vec<WalRecord>,
+    /// Latest buffered state of every written key (`None`: deleted), by collection.
+    overlay: BTreeMap<String, BTreeMap<String, Option<Versioned>>>,
+    /// Committed version (0: absent) of every key touched, as first seen.
+    observed: BTreeMap<(String, String), u64>,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction::default()
+    }
+
+    /// Number of buffered writes.
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn get(&mut self, db: &Database, collection: &str, key: &str) -> Result<Option<Versioned>, Error> {
+        let committed = self.observe(db, collection, key)?;
+        match self.overlay.get(collection).and_then(|writes| writes.get(key)) {
+            Some(buffered) => Ok(buffered.clone()),
+            None => Ok(committed),
+        }
+    }
+
+    /// Buffers a put, checked against the transaction's view; returns whether it creates the document.
+    pub fn put(&mut self, db: &Database, collection: String, key: String, value: Document, options: PutOptions) -> Result<bool, Error> {
+        let current = self.get(db, &collection, &key)?.map(|stored| stored.version);
+        check_put(&collection, &key, current, options.mode, options.expected)?;
+        self.buffer(collection, key, Some((value, options.expires_at)));
+        Ok(current.is_none())
+    }
+
+    /// Buffers the result of applying `update` to the document at `key`, and returns it.
+    pub fn update(
+        &mut self,
+        db: &Database,
+        collection: String,
+        key: String,
+        update: &Update,
+        expected: Option<u64>,
+    ) -> Result<Versioned, Error> {
+        let current = self
+            .get(db, &collection, &key)?
+            .ok_or_else(|| DocumentNotFound { collection: collection.clone(), key: key.clone() })?;
+        if let Some(expected) = expected
+            && current.version != expected
+        {
+            return Err(VersionConflict { key, expected, actual: Some(current.version) }.into());
+        }
+        let updated = update.apply(&current.doc)?;
+        self.buffer(collection, key, Some((updated.clone(), current.expires_at)));
+        Ok(Versioned::expiring(current.version, updated, current.expires_at))
+    }
+
+    pub fn delete(&mut self, db: &Database, collection: String, key: String) -> Result<(), Error> {
+        self.observe(db, &collection, &key)?;
+        self.buffer(collection, key, None);
+        Ok(())
+    }
+
+    /// [`Database::scan`] as seen by the transaction.
+    pub fn scan(&self, db: &Database, collection: &str, scan: &Scan) -> Result<Vec<(String, Document)>, Error> {
+        // Buffered writes can hide at most one committed document each.
+        let hidden = self.overlay.get(collection).map_or(0, BTreeMap::len);
+        let committed = Scan { limit: scan.limit.map(|limit| limit.saturating_add(hidden)), ..scan.clone() };
+        let docs = db.scan(collection, &committed)?;
+        Ok(self.merge(collection, docs, |key, _| scan.range.contains(key), scan.reverse, scan.limit))
+    }
+
+    /// [`Database::find`] as seen by the transaction.
+    pub fn find(&self, db: &Database, collection: &str, filter: &Filter) -> Result<Vec<(String, Document)>, Error> {
+        let docs = db.find(collection, filter)?;
+        Ok(self.merge(collection, docs, |_, doc| filter.matches(doc), false, None))
+    }
+
+    /// Applies every buffered write to `db` as a single logged unit, returning its
+    /// sequence number (`None` when there was nothing to write). Nothing is applied
+    /// if a touched document has changed since the transaction first saw it or if
+    /// the writes would break a unique index.
+    pub fn commit(self, db: &mut Database) -> Result<Option<u64>, Error> {
+        for ((collection, key), &expected) in &self.observed {
+            let actual = db.get_versioned(collection, key)?.map(|stored| stored.version);
+            if actual.unwrap_or(0) != expected {
+                return Err(VersionConflict { key: key.clone(), expected, actual }.into());
+            }
+        }
+        for (collection, writes) in &self.overlay {
+            if let Some(coll) = db.collection(collection) {
+                coll.check_unique_all(writes)?;
+            }
+        }
+        if self.writes.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(db.write(WalRecord::Batch(self.writes))?))
+    }
+
+    /// The committed document at `key`, remembering its version the first time.
+    fn observe(&mut self, db: &Database, collection: &str, key: &str) -> Result<Option<Versioned>, Error> {
+        let committed = db.get_versioned(collection, key)?;
+        self.observed
+            .entry((collection.to_string(), key.to_string()))
+            .or_insert_with(|| committed.as_ref().map_or(0, |stored| stored.version));
+        Ok(committed)
+    }
+
+    /// Records a put of a document with its expiry, or a delete (`None`), of a key already observed.
+    fn buffer(&mut self, collection: String, key: String, value: Option<(Document, Option<u64>)>) {
+        let version = self.observed[&(collection.clone(), key.clone())];
+        let stored = value.clone().map(|(doc, expires_at)| Versioned::expiring(version, doc, expires_at));
+        self.overlay.entry(collection.clone()).or_default().insert(key.clone(), stored);
+        self.writes.push(match value {
+            Some((value, expires_at)) => WalRecord::Put { collection, key, value, expires_at },
+            None => WalRecord::Delete { collection, key },
+        });
+    }
+
+    /// Replaces the buffered keys of `docs` (sorted as `reverse` says) with their
+    /// buffered documents, where `keep` still selects them.
+    fn merge(
+        &self,
+        collection: &str,
+        mut docs: Vec<(String, Document)>,
+        keep: impl Fn(&str, &Document) -> bool,
+        reverse: bool,
+        limit: Option<usize>,
+    ) -> Vec<(String, Document)> {
+        let Some(writes) = self.overlay.get(collection) else {
+            return docs;
+        };
+        docs.retain(|(key, _)| !writes.contains_key(key));
+        for (key, stored) in writes {
+            if let Some(stored) = stored
+                && keep(key, &stored.doc)
+            {
+                docs.push((key.clone(), stored.doc.clone()));
+            }
+        }
+        docs.sort_by(|(a, _), (b, _)| if reverse { b.cmp(a) } else { a.cmp(b) });
+        docs.truncate(limit.unwrap_or(usize::MAX));
+        docs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::test_util::TempDir;
+    use serde_json::json;
+
+    fn put(tx: &mut Transaction, db: &Database, key: &str, value: Document) -> Result<bool, Error> {
+        tx.put(db, "accounts".to_string(), key.to_string(), value, PutOptions::default())
+    }
+
+    #[test]
+    fn writes_are_visible_to_the_transaction_only_until_commit() {
+        let mut db = Database::initialize();
+        db.put("accounts".to_string(), "a".to_string(), json!({"balance": 10})).unwrap();
+        let mut tx = Transaction::new();
+        put(&mut tx, &db, "a", json!({"balance": 5})).unwrap();
+        assert!(put(&mut tx, &db, "b", json!({"balance": 5})).unwrap());
+
+        assert_eq!(tx.get(&db, "accounts", "a").unwrap(), Some(Versioned::new(1, json!({"balance": 5}))));
+        assert_eq!(db.get("accounts", "b").unwrap(), None);
+        let filter = Filter::parse(&json!({"balance": 5})).unwrap();
+        assert_eq!(tx.find(&db, "accounts", &filter).unwrap().len(), 2);
+        assert!(db.find("accounts", &filter).unwrap().is_empty());
+
+        assert_eq!(tx.commit(&mut db).unwrap(), Some(2));
+        assert_eq!(db.get_versioned("accounts", "a").unwrap(), Some(Versioned::new(2, json!({"balance": 5}))));
+        assert_eq!(db.get_versioned("accounts", "b").unwrap(), Some(Versioned::new(2, json!({"balance": 5}))));
+    }
+
+    #[test]
+    fn scans_merge_buffered_writes_in_order() {
+        let mut db = Database::initialize();
+        for key in ["a", "b", "c", "d"] {
+            db.put("accounts".to_string(), key.to_string(), json!({})).unwrap();
+        }
+        let mut tx = Transaction::new();
+        tx.delete(&db, "accounts".to_string(), "a".to_string()).unwrap();
+        tx.delete(&db, "accounts".to_string(), "b".to_string()).unwrap();
+        put(&mut tx, &db, "bb", json!({})).unwrap();
+        let scan = Scan { limit: Some(2), ..Scan::default() };
+        let keys: Vec<String> = tx.scan(&db, "accounts", &scan).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["bb", "c"]);
+        let scan = Scan { reverse: true, limit: Some(3), ..Scan::default() };
+        let keys: Vec<String> = tx.scan(&db, "accounts", &scan).unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["d", "c", "bb"]);
+    }
+
+    #[test]
+    fn commit_fails_without_applying_anything_if_a_touched_document_changed() {
+        let mut db = Database::initialize();
+        db.put("accounts".to_string(), "a".to_string(), json!({"balance": 10})).unwrap();
+        let mut tx = Transaction::new();
+        tx.get(&db, "accounts", "a").unwrap();
+        put(&mut tx, &db, "b", json!({"balance": 10})).unwrap();
+        db.put("accounts".to_string(), "a".to_string(), json!({"balance": 0})).unwrap();
+
+        let err = tx.commit(&mut db).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::VersionConflict);
+        assert_eq!(db.get("accounts", "b").unwrap(), None);
+    }
+
+    #[test]
+    fn unique_indexes_only_check_the_committed_result() {
+        let mut db = Database::initialize();
+        db.create_index("accounts".to_string(), "iban".to_string(), vec!["iban".to_string()], true).unwrap();
+        db.put("accounts".to_string(), "a".to_string(), json!({"iban": "1"})).unwrap();
+        db.put("accounts".to_string(), "b".to_string(), json!({"iban": "2"})).unwrap();
+
+        let mut tx = Transaction::new();
+        put(&mut tx, &db, "a", json!({"iban": "2"})).unwrap();
+        put(&mut tx, &db, "b", json!({"iban": "1"})).unwrap();
+        tx.commit(&mut db).unwrap();
+        assert_eq!(db.get("accounts", "a").unwrap(), Some(json!({"iban": "2"})));
+
+        let mut tx = Transaction::new();
+        put(&mut tx, &db, "c", json!({"iban": "1"})).unwrap();
+        let err = tx.commit(&mut db).unwrap_err();
+        assert_eq!(ErrorCode::of(&err), ErrorCode::UniqueViolation);
+        assert_eq!(db.get("accounts", "c").unwrap(), None);
+    }
+
+    #[test]
+    fn committed_transactions_are_replayed_as_a_whole() {
+        let dir = TempDir::new("transaction");
+        {
+            let mut db = Database::open(dir.path()).unwrap();
+            db.put("accounts".to_string(), "a".to_string(), json!({"balance": 10})).unwrap();
+            let mut tx = Transaction::new();
+            let debit = Update::parse(&json!({"$inc": {"balance": -4}})).unwrap();
+            tx.update(&db, "accounts".to_string(), "a".to_string(), &debit, Some(1)).unwrap();
+            put(&mut tx, &db, "b", json!({"balance": 4})).unwrap();
+            tx.commit(&mut db).unwrap();
+        }
+        let db = Database::open(dir.path()).unwrap();
+        assert_eq!(db.get_versioned("accounts", "a").unwrap(), Some(Versioned::new(2, json!({"balance": 6}))));
+        assert_eq!(db.get_versioned("accounts", "b").unwrap(), Some(Versioned::new(2, json!({"balance": 4}))));
+    }
+}
@@ -0,0 +1,262 @@
+use crate::collection::Document;
+use crate::error::ParseError;
+use oxidoc_cli::commands::commands::DataFormat;
+use serde_json::{Map, Value};
+use std::io::{BufRead, Error, ErrorKind, Write};
+
+/// Field holding the key of a `{"_key": ..., "doc": ...}` record.
+pub const KEY: &str = "_key";
+/// Field holding the document of a `{"_key": ..., "doc": ...}` record.
+pub const DOC: &str = "doc";
+
+/// The record a document is exported as: `{"_key": key, "doc": doc}`, or with
+/// `key_field` the document itself, carrying its key in that field. A document
+/// already holding something else there could not be imported back under its key.
+pub fn to_record(key: String, doc: Document, key_field: Option<&str>) -> Result<Value, Error> {
+    let Some(field) = key_field else {
+        return Ok(Value::Object(Map::from_iter([(KEY.to_string(), Value::String(key)), (DOC.to_string(), doc)])));
+    };
+    let Value::Object(mut object) = doc else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Document {:?} is not an object and cannot carry its key in {:?}", key, field),
+        ));
+    };
+    match object.get(field) {
+        None => {
+            object.insert(field.to_string(), Value::String(key));
+        }
+        Some(Value::String(held)) if *held == key => {}
+        Some(Value::Number(held)) if held.to_string() == key => {}
+        Some(held) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Document {:?} holds {} in {:?}, which is not its key", key, held, field),
+            ));
+        }
+    }
+    Ok(Value::Object(object))
+}
+
+/// The key and document of an imported record, the reverse of [`to_record`]. A
+/// key field may hold a string or a number; it is kept in the document.
+pub fn from_record(record: Value, key_field: Option<&str>) -> Result<(String, Document), Error> {
+    let Value::Object(mut object) = record else {
+        return Err(invalid_record("records must be objects"));
+    };
+    let Some(field) = key_field else {
+        let (Some(Value::String(key)), Some(doc)) = (object.remove(KEY), object.remove(DOC)) else {
+            return Err(invalid_record("expected {\"_key\": <string>, \"doc\": <document>}"));
+        };
+        return Ok((key, doc));
+    };
+    let key = match object.get(field) {
+        Some(Value::String(key)) => key.clone(),
+        Some(Value::Number(key)) => key.to_string(),
+        _ => return Err(invalid_record(&format!("no string or number in key field {:?}", field))),
+    };
+    Ok((key, Value::Object(object)))
+}
+
+fn invalid_record(reason: &str) -> Error {
+    ParseError::new(format!("Invalid record: {}", reason)).into()
+}
+
+/// Reads records one at a time from newline-delimited JSON or from a JSON array,
+/// holding no more than one record in memory.
+pub struct RecordReader<R> {
+    input: R,
+    format: DataFormat,
+    /// Whether the opening `[` of a JSON array has been read, and the closing `]`.
+    opened: bool,
+    closed: bool,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    pub fn new(input: R, format: DataFormat) -> RecordReader<R> {
+        RecordReader { input, format, opened: false, closed: false }
+    }
+
+    fn next_line(&mut self) -> Result<Option<Value>, Error> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.input.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                return Ok(Some(serde_json::from_str(&line).map_err(ParseError::from)?));
+            }
+        }
+    }
+
+    fn next_element(&mut self) -> Result<Option<Value>, Error> {
+        if self.closed {
+            return Ok(None);
+        }
+        if !self.opened {
+            match self.skip_whitespace()? {
+                Some(b'[') => self.opened = true,
+                _ => return Err(ParseError::new("Expected a JSON array").into()),
+            }
+            self.input.consume(1);
+            if self.skip_whitespace()? == Some(b']') {
+                self.input.consume(1);
+                self.closed = true;
+                return Ok(None);
+            }
+        }
+        // Collect the bytes up to the `,` or `]` that ends the element at depth zero.
+        let mut element = Vec::new();
+        let (mut depth, mut in_string, mut escaped) = (0usize, false, false);
+        loop {
+            let Some(byte) = self.next_byte()? else {
+                return Err(ParseError::new("Unterminated JSON array").into());
+            };
+            if in_string {
+                match byte {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+            } else {
+                match byte {
+                    b'"' => in_string = true,
+                    b'[' | b'{' => depth += 1,
+                    b',' | b']' if depth == 0 => {
+                        self.closed = byte == b']';
+                        return Ok(Some(serde_json::from_slice(&element).map_err(ParseError::from)?));
+                    }
+                    b']' | b'}' => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            element.push(byte);
+        }
+    }
+
+    fn skip_whitespace(&mut self) -> Result<Option<u8>, Error> {
+        loop {
+            let buffer = self.input.fill_buf()?;
+            let Some(&byte) = buffer.first() else {
+                return Ok(None);
+            };
+            if !byte.is_ascii_whitespace() {
+                return Ok(Some(byte));
+            }
+            self.input.consume(1);
+        }
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>, Error> {
+        let byte = self.input.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.input.consume(1);
+        }
+        Ok(byte)
+    }
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = Result<Value, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            DataFormat::Ndjson => self.next_line(),
+            DataFormat::Json => self.next_element(),
+        }
+        .transpose()
+    }
+}
+
+/// Writes records one at a time as newline-delimited JSON or as a JSON array;
+/// [`RecordWriter::finish`] closes the array.
+pub struct RecordWriter<W: Write> {
+    output: W,
+    format: DataFormat,
+    written: usize,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(output: W, format: DataFormat) -> RecordWriter<W> {
+        RecordWriter { output, format, written: 0 }
+    }
+
+    pub fn write(&mut self, record: &Value) -> Result<(), Error> {
+        match self.format {
+            DataFormat::Ndjson => {
+                serde_json::to_writer(&mut self.output, record)?;
+                self.output.write_all(b"\n")?;
+            }
+            DataFormat::Json => {
+                self.output.write_all(if self.written == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut self.output, record)?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Number of records written so far.
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        if self.format == DataFormat::Json {
+            self.output.write_all(if self.written == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn read(input: &str, format: DataFormat) -> Result<Vec<Value>, Error> {
+        RecordReader::new(input.as_bytes(), format).collect()
+    }
+
+    #[test]
+    fn records_roundtrip_through_both_formats() {
+        let records = vec![json!({"_key": "a", "doc": {"s": "x,]}\"["}}), json!({"_key": "b", "doc": [1, {"n": null}]}), json!(3)];
+        for format in [DataFormat::Ndjson, DataFormat::Json] {
+            let mut writer = RecordWriter::new(Vec::new(), format);
+            for record in &records {
+                writer.write(record).unwrap();
+            }
+            let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+            assert_eq!(read(&output, format).unwrap(), records);
+            let empty = String::from_utf8(RecordWriter::new(Vec::new(), format).finish().unwrap()).unwrap();
+            assert_eq!(read(&empty, format).unwrap(), Vec::<Value>::new());
+        }
+        assert_eq!(read(" [ 1 ,\n\"2\" ] ", DataFormat::Json).unwrap(), vec![json!(1), json!("2")]);
+        assert_eq!(read("\n{\"a\": 1}\n\n", DataFormat::Ndjson).unwrap(), vec![json!({"a": 1})]);
+        assert!(read("{\"a\": 1}", DataFormat::Json).is_err());
+        assert!(read("[1, 2", DataFormat::Json).is_err());
+        assert!(read("[1,, 2]", DataFormat::Json).is_err());
+    }
+
+    #[test]
+    fn records_carry_keys_in_a_wrapper_or_a_field() {
+        let doc = json!({"name": "Ada"});
+        let record = to_record("u1".to_string(), doc.clone(), None).unwrap();
+        assert_eq!(record, json!({"_key": "u1", "doc": {"name": "Ada"}}));
+        assert_eq!(from_record(record, None).unwrap(), ("u1".to_string(), doc.clone()));
+
+        let record = to_record("u1".to_string(), doc, Some("id")).unwrap();
+        assert_eq!(record, json!({"id": "u1", "name": "Ada"}));
+        assert_eq!(from_record(record.clone(), Some("id")).unwrap(), ("u1".to_string(), record));
+        assert_eq!(from_record(json!({"id": 7}), Some("id")).unwrap().0, "7");
+        assert!(from_record(json!({"name": "Ada"}), Some("id")).is_err());
+        assert!(from_record(json!({"doc": {}}), None).is_err());
+        assert!(to_record("k".to_string(), json!([1]), Some("id")).is_err());
+        assert_eq!(to_record("7".to_string(), json!({"id": 7}), Some("id")).unwrap(), json!({"id": 7}));
+        let err = to_record("a".to_string(), json!({"id": "b"}), Some("id")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
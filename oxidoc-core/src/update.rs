@@ -0,0 +1,465 @@
+use crate::collection::Document;
+use crate::query::{json_eq, Filter};
+use serde_json::{Map, Number, Value};
+use std::io::{Error, ErrorKind};
+
+/// A partial modification of a stored document.
+///
+/// Updates are applied to a copy: if any step fails the stored document is left untouched.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    /// RFC 6902 JSON Patch: a list of `add`/`remove`/`replace`/`move`/`copy`/`test` operations.
+    JsonPatch(Vec<PatchOp>),
+    /// RFC 7386 Merge Patch: objects are merged recursively, `null` removes a member.
+    MergePatch(Value),
+    /// Mongo-style operators over dotted paths, e.g. `{"$inc": {"stats.visits": 1}}`.
+    Operators(Vec<FieldUpdate>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOp {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldUpdate {
+    Set { path: String, value: Value },
+    Unset { path: String },
+    Inc { path: String, by: Number },
+    /// Appends every value, in order.
+    Push { path: String, values: Vec<Value> },
+    /// Removes every element equal to the value, or matching it when it is a filter.
+    Pull { path: String, matcher: Value },
+}
+
+impl Update {
+    /// A JSON array is a JSON Patch, an object whose keys are all `$` operators is an
+    /// operator update, any other object is a Merge Patch.
+    pub fn parse(update: &Value) -> Result<Update, Error> {
+        match update {
+            Value::Array(_) => Update::json_patch(update),
+            Value::Object(fields) if !fields.is_empty() && fields.keys().all(|k| k.starts_with('$')) => {
+                Update::operators(update)
+            }
+            _ => Update::merge_patch(update),
+        }
+    }
+
+    pub fn json_patch(patch: &Value) -> Result<Update, Error> {
+        let Value::Array(ops) = patch else {
+            return Err(invalid("a JSON Patch must be an array of operations"));
+        };
+        ops.iter().map(PatchOp::parse).collect::<Result<_, _>>().map(Update::JsonPatch)
+    }
+
+    pub fn merge_patch(patch: &Value) -> Result<Update, Error> {
+        Ok(Update::MergePatch(patch.clone()))
+    }
+
+    pub fn operators(update: &Value) -> Result<Update, Error> {
+        let Value::Object(operators) = update else {
+            return Err(invalid("an operator update must be an object"));
+        };
+        let mut updates = Vec::new();
+        for (operator, fields) in operators {
+            let Value::Object(fields) = fields else {
+                return Err(invalid(&format!("{operator} expects an object of fields")));
+            };
+            for (path, operand) in fields {
+                if path.is_empty() || path.split('.').any(str::is_empty) {
+                    return Err(invalid(&format!("invalid field path {path:?}")));
+                }
+                updates.push(FieldUpdate::parse(operator, path.clone(), operand)?);
+            }
+        }
+        Ok(Update::Operators(updates))
+    }
+
+    /// The document that results from applying the update to `doc`.
+    pub fn apply(&self, doc: &Document) -> Result<Document, Error> {
+        let mut doc = doc.clone();
+        match self {
+            Update::JsonPatch(ops) => ops.iter().try_for_each(|op| op.apply(&mut doc))?,
+            Update::MergePatch(patch) => merge(&mut doc, patch),
+            Update::Operators(updates) => updates.iter().try_for_each(|u| u.apply(&mut doc))?,
+        }
+        Ok(doc)
+    }
+}
+
+impl PatchOp {
+    fn parse(op: &Value) -> Result<PatchOp, Error> {
+        let member = |name: &str| op.get(name).ok_or_else(|| invalid(&format!("patch operation without {name:?}: {op}")));
+        let pointer = |name: &str| match member(name)? {
+            Value::String(p) if p.is_empty() || p.starts_with('/') => Ok(p.clone()),
+            other => Err(invalid(&format!("{name:?} is not a JSON Pointer: {other}"))),
+        };
+        let Some(Value::String(kind)) = op.get("op") else {
+            return Err(invalid(&format!("patch operation without \"op\": {op}")));
+        };
+        Ok(match kind.as_str() {
+            "add" => PatchOp::Add { path: pointer("path")?, value: member("value")?.clone() },
+            "remove" => PatchOp::Remove { path: pointer("path")? },
+            "replace" => PatchOp::Replace { path: pointer("path")?, value: member("value")?.clone() },
+            "move" => PatchOp::Move { from: pointer("from")?, path: pointer("path")? },
+            "copy" => PatchOp::Copy { from: pointer("from")?, path: pointer("path")? },
+            "test" => PatchOp::Test { path: pointer("path")?, value: member("value")?.clone() },
+            other => return Err(invalid(&format!("unknown patch operation {other:?}"))),
+        })
+    }
+
+    fn apply(&self, doc: &mut Document) -> Result<(), Error> {
+        match self {
+            PatchOp::Add { path, value } => add(doc, path, value.clone()),
+            PatchOp::Remove { path } => remove(doc, path).map(drop),
+            PatchOp::Replace { path, value } => {
+                let target = doc.pointer_mut(path).ok_or_else(|| missing(path))?;
+                *target = value.clone();
+                Ok(())
+            }
+            PatchOp::Move { from, path } => {
+                if path.starts_with(&format!("{from}/")) {
+                    return Err(invalid(&format!("cannot move {from:?} into its own child {path:?}")));
+                }
+                let value = remove(doc, from)?;
+                add(doc, path, value)
+            }
+            PatchOp::Copy { from, path } => {
+                let value = doc.pointer(from).ok_or_else(|| missing(from))?.clone();
+                add(doc, path, value)
+            }
+            PatchOp::Test { path, value } => match doc.pointer(path) {
+                Some(current) if json_eq(current, value) => Ok(()),
+                _ => Err(Error::new(ErrorKind::InvalidInput, format!("Patch test failed at {path:?}"))),
+            },
+        }
+    }
+}
+
+impl FieldUpdate {
+    fn parse(operator: &str, path: String, operand: &Value) -> Result<FieldUpdate, Error> {
+        Ok(match operator {
+            "$set" => FieldUpdate::Set { path, value: operand.clone() },
+            "$unset" => FieldUpdate::Unset { path },
+            "$inc" => match operand {
+                Value::Number(by) => FieldUpdate::Inc { path, by: by.clone() },
+                _ => return Err(invalid(&format!("$inc of {path:?} expects a number"))),
+            },
+            "$push" => match operand.get("$each") {
+                Some(Value::Array(values)) => FieldUpdate::Push { path, values: values.clone() },
+                Some(_) => return Err(invalid("$each expects an array")),
+                None => FieldUpdate::Push { path, values: vec![operand.clone()] },
+            },
+            "$pull" => {
+                if operand.is_object() {
+                    element_filter(operand)?;
+                }
+                FieldUpdate::Pull { path, matcher: operand.clone() }
+            }
+            other => return Err(invalid(&format!("unknown update operator {other}"))),
+        })
+    }
+
+    fn apply(&self, doc: &mut Document) -> Result<(), Error> {
+        match self {
+            FieldUpdate::Set { path, value } => {
+                *field_mut(doc, path)? = value.clone();
+            }
+            FieldUpdate::Unset { path } => {
+                let (parent, last) = match path.rsplit_once('.') {
+                    Some((parent, last)) => (existing_field_mut(doc, parent), last),
+                    None => (Some(doc), path.as_str()),
+                };
+                match parent {
+                    Some(Value::Object(fields)) => {
+                        fields.remove(last);
+                    }
+                    // Like Mongo, unsetting an array element leaves a null behind so
+                    // that the other elements keep their positions.
+                    Some(Value::Array(items)) => {
+                        if let Some(item) = last.parse::<usize>().ok().and_then(|i| items.get_mut(i)) {
+                            *item = Value::Null;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            FieldUpdate::Inc { path, by } => {
+                let field = field_mut(doc, path)?;
+                *field = match field {
+                    Value::Null => Value::Number(by.clone()),
+                    Value::Number(current) => Value::Number(add_numbers(current, by).ok_or_else(|| {
+                        invalid(&format!("$inc of {path:?} overflows"))
+                    })?),
+                    other => return Err(invalid(&format!("cannot $inc {path:?}: {other} is not a number"))),
+                };
+            }
+            FieldUpdate::Push { path, values } => {
+                let field = field_mut(doc, path)?;
+                match field {
+                    Value::Null => *field = Value::Array(values.clone()),
+                    Value::Array(items) => items.extend(values.iter().cloned()),
+                    other => return Err(invalid(&format!("cannot $push to {path:?}: {other} is not an array"))),
+                }
+            }
+            FieldUpdate::Pull { path, matcher } => match existing_field_mut(doc, path) {
+                Some(Value::Array(items)) => {
+                    let filter = if matcher.is_object() { Some(element_filter(matcher)?) } else { None };
+                    items.retain(|item| match &filter {
+                        Some(filter) => !filter.matches(&wrap_element(item)),
+                        None => !json_eq(item, matcher),
+                    });
+                }
+                None => {}
+                Some(other) => return Err(invalid(&format!("cannot $pull from {path:?}: {other} is not an array"))),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Field under which `$pull` conditions see an array element.
+const ELEMENT: &str = "element";
+
+/// `$pull` conditions: `{"$gt": 3}` tests the element itself, `{"qty": 0}` tests
+/// fields of object elements.
+fn element_filter(matcher: &Value) -> Result<Filter, Error> {
+    let is_condition = matcher.as_object().is_some_and(|m| m.keys().any(|k| k.starts_with('$')));
+    if !is_condition {
+        let prefixed = matcher
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(path, cond)| (format!("{ELEMENT}.{path}"), cond.clone()))
+            .collect::<Map<_, _>>();
+        return Filter::parse(&Value::Object(prefixed));
+    }
+    Filter::parse(&Value::Object(Map::from_iter([(ELEMENT.to_string(), matcher.clone())])))
+}
+
+fn wrap_element(item: &Value) -> Value {
+    Value::Object(Map::from_iter([(ELEMENT.to_string(), item.clone())]))
+}
+
+/// Integer sums stay integers; anything else becomes a float.
+fn add_numbers(a: &Number, b: &Number) -> Option<Number> {
+    match (a.as_i64(), b.as_i64()) {
+        (Some(a), Some(b)) => a.checked_add(b).map(Number::from),
+        _ => Number::from_f64(a.as_f64()? + b.as_f64()?),
+    }
+}
+
+/// The value at a dotted `path`, creating missing objects on the way and a `null`
+/// leaf. Numeric segments index into existing arrays.
+fn field_mut<'a>(doc: &'a mut Value, path: &str) -> Result<&'a mut Value, Error> {
+    let mut current = doc;
+    for segment in path.split('.') {
+        current = match current {
+            Value::Object(fields) => fields.entry(segment).or_insert(Value::Null),
+            Value::Array(items) => {
+                let len = items.len();
+                segment
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| items.get_mut(i))
+                    .ok_or_else(|| invalid(&format!("{path:?}: no element {segment:?} in an array of {len}")))?
+            }
+            Value::Null => {
+                *current = Value::Object(Map::new());
+                current.as_object_mut().expect("just created").entry(segment).or_insert(Value::Null)
+            }
+            other => return Err(invalid(&format!("{path:?}: cannot create a field inside {other}"))),
+        };
+    }
+    Ok(current)
+}
+
+fn existing_field_mut<'a>(doc: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.').try_fold(doc, |value, segment| match value {
+        Value::Object(fields) => fields.get_mut(segment),
+        Value::Array(items) => segment.parse::<usize>().ok().and_then(|i| items.get_mut(i)),
+        _ => None,
+    })
+}
+
+/// RFC 7386: objects merge member by member, `null` members are removed and any
+/// other patch replaces the target.
+fn merge(target: &mut Value, patch: &Value) {
+    let Value::Object(members) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let fields = target.as_object_mut().expect("target is an object");
+    for (name, value) in members {
+        if value.is_null() {
+            fields.remove(name);
+        } else {
+            merge(fields.entry(name).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Splits a JSON Pointer into its parent pointer and unescaped last token.
+fn split_pointer(path: &str) -> Result<(&str, String), Error> {
+    let (parent, last) = path.rsplit_once('/').ok_or_else(|| invalid("the document root has no parent"))?;
+    Ok((parent, last.replace("~1", "/").replace("~0", "~")))
+}
+
+fn add(doc: &mut Document, path: &str, value: Value) -> Result<(), Error> {
+    if path.is_empty() {
+        *doc = value;
+        return Ok(());
+    }
+    let (parent, last) = split_pointer(path)?;
+    match doc.pointer_mut(parent).ok_or_else(|| missing(parent))? {
+        Value::Object(fields) => {
+            fields.insert(last, value);
+        }
+        Value::Array(items) => {
+            let index = match last.as_str() {
+                "-" => items.len(),
+                index => array_index(index, items.len() + 1, path)?,
+            };
+            items.insert(index, value);
+        }
+        _ => return Err(missing(path)),
+    }
+    Ok(())
+}
+
+fn remove(doc: &mut Document, path: &str) -> Result<Value, Error> {
+    let (parent, last) = split_pointer(path)?;
+    match doc.pointer_mut(parent) {
+        Some(Value::Object(fields)) => fields.remove(&last).ok_or_else(|| missing(path)),
+        Some(Value::Array(items)) => {
+            let index = array_index(&last, items.len(), path)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(missing(path)),
+    }
+}
+
+/// RFC 6901 array index: decimal, no leading zeros, below `len`.
+fn array_index(token: &str, len: usize, path: &str) -> Result<usize, Error> {
+    let valid = !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit()) && (token == "0" || !token.starts_with('0'));
+    match token.parse::<usize>() {
+        Ok(index) if valid && index < len => Ok(index),
+        _ => Err(missing(path)),
+    }
+}
+
+fn missing(path: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("Invalid update: no value at {path:?}"))
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("Invalid update: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn apply(update: Value, doc: Value) -> Result<Value, Error> {
+        Update::parse(&update)?.apply(&doc)
+    }
+
+    #[test]
+    fn parse_picks_the_update_format() {
+        assert!(matches!(Update::parse(&json!([])).unwrap(), Update::JsonPatch(_)));
+        assert!(matches!(Update::parse(&json!({"$set": {"a": 1}})).unwrap(), Update::Operators(_)));
+        assert!(matches!(Update::parse(&json!({"a": 1})).unwrap(), Update::MergePatch(_)));
+        assert!(Update::parse(&json!({"$rename": {"a": "b"}})).is_err());
+        assert!(Update::parse(&json!([{"op": "add", "path": "a", "value": 1}])).is_err());
+    }
+
+    #[test]
+    fn json_patch_follows_rfc_6902() {
+        let doc = json!({"a": {"b": 1}, "list": [1, 2], "x~y": 0});
+        let patch = json!([
+            {"op": "test", "path": "/a/b", "value": 1.0},
+            {"op": "add", "path": "/list/1", "value": 9},
+            {"op": "add", "path": "/list/-", "value": 3},
+            {"op": "replace", "path": "/x~0y", "value": "tilde"},
+            {"op": "copy", "from": "/a", "path": "/c"},
+            {"op": "move", "from": "/a/b", "path": "/moved"},
+            {"op": "remove", "path": "/list/0"},
+        ]);
+        assert_eq!(
+            apply(patch, doc).unwrap(),
+            json!({"a": {}, "c": {"b": 1}, "list": [9, 2, 3], "x~y": "tilde", "moved": 1})
+        );
+    }
+
+    #[test]
+    fn json_patch_failures_leave_nothing_half_applied() {
+        let doc = json!({"a": 1});
+        let update = Update::parse(&json!([
+            {"op": "add", "path": "/b", "value": 2},
+            {"op": "test", "path": "/a", "value": 2},
+        ]))
+        .unwrap();
+        assert_eq!(update.apply(&doc).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(doc, json!({"a": 1}));
+        for patch in [
+            json!([{"op": "remove", "path": "/missing"}]),
+            json!([{"op": "replace", "path": "/a/b", "value": 1}]),
+            json!([{"op": "add", "path": "/list/01", "value": 1}]),
+            json!([{"op": "move", "from": "/a", "path": "/a/b"}]),
+        ] {
+            assert!(apply(patch.clone(), json!({"a": {}, "list": [0]})).is_err(), "{patch}");
+        }
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7386() {
+        let doc = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["a", "b"]});
+        let patch = json!({"title": "Hello!", "phoneNumber": "+01-123", "author": {"familyName": null}, "tags": ["c"]});
+        assert_eq!(
+            apply(patch, doc).unwrap(),
+            json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["c"], "phoneNumber": "+01-123"})
+        );
+        assert_eq!(apply(json!({"a": {"b": 1}}), json!({"a": 5})).unwrap(), json!({"a": {"b": 1}}));
+    }
+
+    #[test]
+    fn set_unset_and_inc_follow_dotted_paths() {
+        let doc = json!({"name": "Ada", "stats": {"visits": 1, "score": 1.5}, "list": [1, 2]});
+        let update = json!({
+            "$set": {"address.city": "London", "list.1": 20},
+            "$unset": {"name": "", "missing.field": ""},
+            "$inc": {"stats.visits": 2, "stats.score": 1, "stats.new": 5},
+        });
+        assert_eq!(
+            apply(update, doc).unwrap(),
+            json!({"address": {"city": "London"}, "stats": {"visits": 3, "score": 2.5, "new": 5}, "list": [1, 20]})
+        );
+        assert!(apply(json!({"$inc": {"name": 1}}), json!({"name": "Ada"})).is_err());
+        assert!(apply(json!({"$inc": {"n": 1}}), json!({"n": i64::MAX})).is_err());
+        assert!(apply(json!({"$set": {"name.first": "A"}}), json!({"name": "Ada"})).is_err());
+        assert!(apply(json!({"$set": {"list.5": 1}}), json!({"list": []})).is_err());
+    }
+
+    #[test]
+    fn push_and_pull_modify_arrays() {
+        let doc = json!({"tags": ["a", "b", "a"], "items": [{"qty": 0}, {"qty": 3}], "n": [1, 5, 9]});
+        let update = json!({
+            "$push": {"tags": "c", "new": {"$each": [1, 2]}},
+            "$pull": {"tags": "a", "items": {"qty": 0}, "n": {"$gt": 4}},
+        });
+        assert_eq!(
+            apply(update, doc).unwrap(),
+            json!({"tags": ["b", "c"], "items": [{"qty": 3}], "n": [1], "new": [1, 2]})
+        );
+        assert!(apply(json!({"$push": {"tags": 1}}), json!({"tags": "a"})).is_err());
+    }
+}
@@ -0,0 +1,586 @@
+use crate::checksum::crc32;
+use crate::codec::{Decoder, Encode};
+use crate::collection::Document;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Frame header: body length (u32) followed by the CRC-32 of the body (u32).
+const HEADER_LEN: u64 = 8;
+
+/// Largest frame body the log takes. A header declaring more was not written by
+/// [`Wal::append`]: it is corruption, not a torn tail.
+const MAX_BODY_LEN: u64 = 1 << 30;
+
+const TAG_CREATE_COLLECTION: u8 = 1;
+const TAG_DELETE_COLLECTION: u8 = 2;
+const TAG_PUT: u8 = 3;
+const TAG_DELETE: u8 = 4;
+const TAG_CREATE_INDEX: u8 = 5;
+const TAG_DROP_INDEX: u8 = 6;
+const TAG_CREATE_UNIQUE_INDEX: u8 = 7;
+const TAG_BATCH: u8 = 8;
+const TAG_PUT_EXPIRING: u8 = 9;
+const TAG_CREATE_TTL_INDEX: u8 = 10;
+
+/// A single logged mutation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalRecord {
+    CreateCollection { name: String },
+    DeleteCollection { name: String },
+    /// `expires_at`: Unix time in milliseconds from which the document counts as deleted.
+    Put { collection: String, key: String, value: Document, expires_at: Option<u64> },
+    Delete { collection: String, key: String },
+    CreateIndex { collection: String, name: String, fields: Vec<String>, unique: bool },
+    DropIndex { collection: String, name: String },
+    CreateTtlIndex { collection: String, name: String, field: String, expire_after: u64 },
+    /// Records committed together: replayed all or not at all, under a single LSN.
+    Batch(Vec<WalRecord>),
+}
+
+impl WalRecord {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            WalRecord::CreateCollection { name } => {
+                buf.put_u8(TAG_CREATE_COLLECTION);
+                buf.put_str(name);
+            }
+            WalRecord::DeleteCollection { name } => {
+                buf.put_u8(TAG_DELETE_COLLECTION);
+                buf.put_str(name);
+            }
+            WalRecord::Put { collection, key, value, expires_at } => {
+                buf.put_u8(if expires_at.is_some() { TAG_PUT_EXPIRING } else { TAG_PUT });
+                buf.put_str(collection);
+                buf.put_str(key);
+                if let Some(at) = expires_at {
+                    buf.put_u64(*at);
+                }
+                buf.put_bytes(&serde_json::to_vec(value).expect("Serialization failed."));
+            }
+            WalRecord::Delete { collection, key } => {
+                buf.put_u8(TAG_DELETE);
+                buf.put_str(collection);
+                buf.put_str(key);
+            }
+            WalRecord::CreateIndex { collection, name, fields, unique } => {
+                buf.put_u8(if *unique { TAG_CREATE_UNIQUE_INDEX } else { TAG_CREATE_INDEX });
+                buf.put_str(collection);
+                buf.put_str(name);
+                buf.put_u32(fields.len() as u32);
+                for field in fields {
+                    buf.put_str(field);
+                }
+            }
+            WalRecord::DropIndex { collection, name } => {
+                buf.put_u8(TAG_DROP_INDEX);
+                buf.put_str(collection);
+                buf.put_str(name);
+            }
+            WalRecord::CreateTtlIndex { collection, name, field, expire_after } => {
+                buf.put_u8(TAG_CREATE_TTL_INDEX);
+                buf.put_str(collection);
+                buf.put_str(name);
+                buf.put_str(field);
+                buf.put_u64(*expire_after);
+            }
+            WalRecord::Batch(records) => {
+                buf.put_u8(TAG_BATCH);
+                buf.put_u32(records.len() as u32);
+                for record in records {
+                    record.encode(buf);
+                }
+            }
+        }
+    }
+
+    fn decode(dec: &mut Decoder) -> Result<WalRecord, Error> {
+        match dec.get_u8()? {
+            TAG_CREATE_COLLECTION => Ok(WalRecord::CreateCollection { name: dec.get_string()? }),
+            TAG_DELETE_COLLECTION => Ok(WalRecord::DeleteCollection { name: dec.get_string()? }),
+            tag @ (TAG_PUT | TAG_PUT_EXPIRING) => Ok(WalRecord::Put {
+                collection: dec.get_string()?,
+                key: dec.get_string()?,
+                expires_at: if tag == TAG_PUT_EXPIRING { Some(dec.get_u64()?) } else { None },
+                value: serde_json::from_slice(dec.get_bytes()?)?,
+            }),
+            TAG_DELETE => Ok(WalRecord::Delete {
+                collection: dec.get_string()?,
+                key: dec.get_string()?,
+            }),
+            tag @ (TAG_CREATE_INDEX | TAG_CREATE_UNIQUE_INDEX) => Ok(WalRecord::CreateIndex {
+                collection: dec.get_string()?,
+                name: dec.get_string()?,
+                fields: (0..dec.get_u32()?).map(|_| dec.get_string()).collect::<Result<_, _>>()?,
+                unique: tag == TAG_CREATE_UNIQUE_INDEX,
+            }),
+            TAG_DROP_INDEX => Ok(WalRecord::DropIndex {
+                collection: dec.get_string()?,
+                name: dec.get_string()?,
+            }),
+            TAG_CREATE_TTL_INDEX => Ok(WalRecord::CreateTtlIndex {
+                collection: dec.get_string()?,
+                name: dec.get_string()?,
+                field: dec.get_string()?,
+                expire_after: dec.get_u64()?,
+            }),
+            TAG_BATCH => Ok(WalRecord::Batch(
+                (0..dec.get_u32()?).map(|_| WalRecord::decode(dec)).collect::<Result<_, _>>()?,
+            )),
+            tag => Err(Error::new(ErrorKind::InvalidData, format!("Unknown WAL record tag {}", tag))),
+        }
+    }
+}
+
+/// Append-only, checksummed log of mutations.
+///
+/// Every frame is `[len: u32][crc32: u32][lsn: u64][record]`, where `len` and
+/// the checksum cover everything after the header.
+#[derive(Debug)]
+pub struct Wal {
+    file: File,
+    path: PathBuf,
+    next_lsn: u64,
+    /// Length of the log up to the end of its last complete frame.
+    len: u64,
+    /// Set when a failed append could not be undone: the log may end in part of a
+    /// frame, and nothing more is appended after it.
+    failed: bool,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path` and returns it together with every
+    /// record, in append order. A torn tail left behind by a crash, i.e. an incomplete
+    /// or corrupt last frame, is truncated so that new records are appended after the
+    /// last good one; a corrupt frame followed by others, or one declaring a length no
+    /// record can have, fails with `InvalidData`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<(Wal, Vec<(u64, WalRecord)>), Error> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut records = Vec::new();
+        let mut offset = 0u64;
+        let mut next_lsn = 1;
+
+        while let Some(body) = read_frame(&mut reader, offset, file_len)? {
+            let mut dec = Decoder::new(&body);
+            let lsn = dec.get_u64()?;
+            let record = WalRecord::decode(&mut dec)?;
+            if !dec.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Trailing bytes in WAL record {}", lsn)));
+            }
+            offset += HEADER_LEN + body.len() as u64;
+            next_lsn = lsn + 1;
+            records.push((lsn, record));
+        }
+
+        if offset < file_len {
+            eprintln!(
+                "WAL: discarding {} bytes of torn tail after offset {}",
+                file_len - offset,
+                offset
+            );
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+        Ok((Wal { file, path, next_lsn, len: offset, failed: false }, records))
+    }
+
+    /// Durably appends `record`, returning the log sequence number assigned to it.
+    pub fn append(&mut self, record: &WalRecord) -> Result<u64, Error> {
+        self.append_with(record, |file, frame| {
+            file.write_all(frame)?;
+            file.sync_data()
+        })
+    }
+
+    /// Appends `record` with `write`. Should it fail, whatever part of the frame it
+    /// wrote is cut off again, so that the next record follows the last good one
+    /// instead of a torn frame that would hide it from [`Wal::open`].
+    fn append_with(
+        &mut self,
+        record: &WalRecord,
+        write: impl FnOnce(&mut File, &[u8]) -> Result<(), Error>,
+    ) -> Result<u64, Error> {
+        if self.failed {
+            return Err(Error::other("The WAL refuses writes after an append it could not undo"));
+        }
+        let lsn = self.next_lsn;
+        let frame = frame(lsn, record);
+        let body_len = frame.len() as u64 - HEADER_LEN;
+        if body_len > MAX_BODY_LEN {
+            let message = format!("WAL record of {} bytes exceeds the maximum of {} bytes", body_len, MAX_BODY_LEN);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+        if let Err(e) = write(&mut self.file, &frame) {
+            if self.file.set_len(self.len).and_then(|_| self.file.sync_all()).is_err() {
+                self.failed = true;
+            }
+            return Err(e);
+        }
+        self.len += frame.len() as u64;
+        self.next_lsn += 1;
+        Ok(lsn)
+    }
+
+    pub fn next_lsn(&self) -> u64 {
+        self.next_lsn
+    }
+
+    /// Bytes logged so far, every one of them part of a complete frame.
+    pub fn size(&self) -> Result<u64, Error> {
+        Ok(self.len)
+    }
+
+    /// Checks, without changing it, that the log at `path` is intact frames up to its
+    /// very end, and returns the sequence number of the last one (0: empty).
+    pub fn verify<P: AsRef<Path>>(path: P) -> Result<u64, Error> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut last = 0;
+        read_all(&mut BufReader::new(file), file_len, |lsn, _| {
+            if lsn <= last {
+                return Err(Error::new(ErrorKind::InvalidData, format!("WAL record {} follows {}", lsn, last)));
+            }
+            last = lsn;
+            Ok(())
+        })?;
+        Ok(last)
+    }
+
+    /// Empties the log once everything in it is covered by a checkpoint; sequence
+    /// numbers carry on from where they were. Any torn frame goes with it.
+    pub fn truncate(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.len = 0;
+        self.failed = false;
+        Ok(())
+    }
+
+    /// Drops the records up to `sequence`, which a checkpoint now covers, keeping those
+    /// logged after it. The complete frames among the rest are copied to a new log
+    /// that replaces this one.
+    pub fn discard_through(&mut self, sequence: u64) -> Result<(), Error> {
+        if self.next_lsn <= sequence + 1 {
+            return self.truncate();
+        }
+        let mut kept = Vec::new();
+        read_all(&mut BufReader::new(File::open(&self.path)?), self.len, |lsn, record| {
+            if lsn > sequence {
+                kept.extend_from_slice(&frame(lsn, &record));
+            }
+            Ok(())
+        })?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&kept)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        sync_parent(&self.path)?;
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.len = kept.len() as u64;
+        self.failed = false;
+        Ok(())
+    }
+
+    /// Makes the next record follow `sequence`, the last one a checkpoint covers,
+    /// even if the log itself ends earlier.
+    pub fn resume_after(&mut self, sequence: u64) {
+        self.next_lsn = self.next_lsn.max(sequence + 1);
+    }
+}
+
+/// Makes a rename onto `path` durable by syncing the directory holding it.
+fn sync_parent(path: &Path) -> Result<(), Error> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// `record` framed as it is logged: `[len][crc32][lsn][record]`.
+pub(crate) fn frame(lsn: u64, record: &WalRecord) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.put_u64(lsn);
+    record.encode(&mut body);
+
+    let mut frame = Vec::with_capacity(HEADER_LEN as usize + body.len());
+    frame.put_u32(body.len() as u32);
+    frame.put_u32(crc32(&body));
+    frame.extend_from_slice(&body);
+    frame
+}
+
+/// Passes every frame of `reader`, which holds `len` bytes, to `visit`. Unlike
+/// [`Wal::open`], an incomplete or corrupt frame anywhere is an error.
+pub(crate) fn read_all<R: Read>(
+    reader: &mut R,
+    len: u64,
+    mut visit: impl FnMut(u64, WalRecord) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut offset = 0u64;
+    while let Some(body) = read_frame(reader, offset, len)? {
+        let mut dec = Decoder::new(&body);
+        let lsn = dec.get_u64()?;
+        let record = WalRecord::decode(&mut dec)?;
+        if !dec.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Trailing bytes in WAL record {}", lsn)));
+        }
+        visit(lsn, record)?;
+        offset += HEADER_LEN + body.len() as u64;
+    }
+    if offset < len {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Corrupt frame at offset {}", offset)));
+    }
+    Ok(())
+}
+
+/// Reads the frame starting at `offset`, returning `None` at end of log or for a
+/// torn tail: a frame running past the end of the log, or the last one failing its
+/// checksum. A frame failing its checksum with more after it, or declaring a length
+/// over [`MAX_BODY_LEN`], is corruption, an error.
+fn read_frame<R: Read>(reader: &mut R, offset: u64, file_len: u64) -> Result<Option<Vec<u8>>, Error> {
+    if file_len - offset < HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0u8; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+    let crc = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if len > MAX_BODY_LEN {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Corrupt WAL frame at offset {}: declares {} bytes", offset, len),
+        ));
+    }
+    let remaining = file_len - offset - HEADER_LEN;
+    if remaining < len {
+        return Ok(None);
+    }
+    let mut body = vec![0u8; len as usize];
+    reader.read_exact(&mut body)?;
+    if crc32(&body) != crc {
+        if remaining == len {
+            return Ok(None);
+        }
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Corrupt WAL frame at offset {} with {} bytes logged after it", offset, remaining - len),
+        ));
+    }
+    Ok(Some(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TempDir;
+    use serde_json::json;
+
+    fn put_record(key: &str) -> WalRecord {
+        WalRecord::Put {
+            collection: "coll".to_string(),
+            key: key.to_string(),
+            value: json!({"data": key}),
+            expires_at: None,
+        }
+    }
+
+    fn index_record() -> WalRecord {
+        WalRecord::CreateIndex {
+            collection: "coll".to_string(),
+            name: "city_age".to_string(),
+            fields: vec!["address.city".to_string(), "age".to_string()],
+            unique: true,
+        }
+    }
+
+    fn batch_record() -> WalRecord {
+        WalRecord::Batch(vec![
+            put_record("k2"),
+            WalRecord::Put {
+                collection: "coll".to_string(),
+                key: "session".to_string(),
+                value: json!({}),
+                expires_at: Some(1_700_000_000_000),
+            },
+            WalRecord::CreateTtlIndex {
+                collection: "coll".to_string(),
+                name: "created".to_string(),
+                field: "created".to_string(),
+                expire_after: 3600,
+            },
+            WalRecord::Delete { collection: "coll".to_string(), key: "k3".to_string() },
+        ])
+    }
+
+    #[test]
+    fn append_and_replay_records_in_order() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        {
+            let (mut wal, records) = Wal::open(&path).unwrap();
+            assert!(records.is_empty());
+            wal.append(&WalRecord::CreateCollection { name: "coll".to_string() }).unwrap();
+            wal.append(&put_record("k1")).unwrap();
+            wal.append(&WalRecord::Delete { collection: "coll".to_string(), key: "k1".to_string() }).unwrap();
+            wal.append(&index_record()).unwrap();
+            wal.append(&batch_record()).unwrap();
+        }
+        let (wal, records) = Wal::open(&path).unwrap();
+        let lsns: Vec<u64> = records.iter().map(|(lsn, _)| *lsn).collect();
+        assert_eq!(lsns, vec![1, 2, 3, 4, 5]);
+        assert_eq!(records[1].1, put_record("k1"));
+        assert_eq!(records[3].1, index_record());
+        assert_eq!(records[4].1, batch_record());
+        assert_eq!(wal.next_lsn(), 6);
+    }
+
+    #[test]
+    fn discarding_keeps_the_records_after_a_checkpoint() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        for key in ["k1", "k2", "k3"] {
+            wal.append(&put_record(key)).unwrap();
+        }
+        wal.discard_through(2).unwrap();
+        wal.append(&put_record("k4")).unwrap();
+        assert_eq!(wal.size().unwrap(), std::fs::metadata(&path).unwrap().len());
+        drop(wal);
+
+        let (mut wal, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![(3, put_record("k3")), (4, put_record("k4"))]);
+        wal.discard_through(4).unwrap();
+        assert_eq!((wal.size().unwrap(), wal.next_lsn()), (0, 5));
+    }
+
+    #[test]
+    fn torn_tail_is_truncated() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(&put_record("k1")).unwrap();
+            wal.append(&put_record("k2")).unwrap();
+        }
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 3).unwrap();
+        drop(file);
+        assert_eq!(Wal::verify(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let (mut wal, records) = Wal::open(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1, put_record("k1"));
+        wal.append(&put_record("k3")).unwrap();
+        drop(wal);
+
+        let (wal, records) = Wal::open(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], (2, put_record("k3")));
+        assert_eq!(Wal::verify(&path).unwrap(), 2);
+        assert_eq!(wal.size().unwrap(), std::fs::metadata(&path).unwrap().len());
+    }
+
+    #[test]
+    fn corrupt_tail_checksum_is_truncated() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(&put_record("k1")).unwrap();
+            wal.append(&put_record("k2")).unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let (_, records) = Wal::open(&path).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn failed_append_leaves_no_partial_frame() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(&put_record("k1")).unwrap();
+            let failed = wal.append_with(&put_record("lost"), |file, frame| {
+                file.write_all(&frame[..frame.len() / 2])?;
+                Err(Error::other("disk full"))
+            });
+            assert_eq!(failed.unwrap_err().to_string(), "disk full");
+            assert_eq!(wal.append(&put_record("k2")).unwrap(), 2);
+
+            // An append whose torn frame could not be cut off again.
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&frame(3, &put_record("torn"))[..10]).unwrap();
+            wal.failed = true;
+            assert!(wal.append(&put_record("k3")).is_err());
+            assert!(wal.size().unwrap() < std::fs::metadata(&path).unwrap().len());
+            wal.discard_through(0).unwrap();
+            assert_eq!(wal.size().unwrap(), std::fs::metadata(&path).unwrap().len());
+            assert_eq!(wal.append(&put_record("k3")).unwrap(), 3);
+        }
+        let (_, records) = Wal::open(&path).unwrap();
+        assert_eq!(records, vec![(1, put_record("k1")), (2, put_record("k2")), (3, put_record("k3"))]);
+        assert_eq!(Wal::verify(&path).unwrap(), 3);
+    }
+
+    #[test]
+    fn corrupt_frame_before_the_tail_is_an_error() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(&put_record("k1")).unwrap();
+            wal.append(&put_record("k2")).unwrap();
+            wal.append(&put_record("k3")).unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        let first = frame(1, &put_record("k1")).len();
+        bytes[first + HEADER_LEN as usize + 9] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = Wal::open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains(&format!("offset {}", first)));
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        assert_eq!(Wal::verify(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn impossible_length_is_corruption_not_a_torn_tail() {
+        let dir = TempDir::new("wal");
+        let path = dir.path().join("wal.log");
+        {
+            let (mut wal, _) = Wal::open(&path).unwrap();
+            wal.append(&put_record("k1")).unwrap();
+            wal.append(&put_record("k2")).unwrap();
+            wal.append(&put_record("k3")).unwrap();
+        }
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[0] = 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let err = Wal::open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("offset 0"), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        assert_eq!(Wal::verify(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        std::fs::write(&path, [0xFF; 12]).unwrap();
+        assert_eq!(Wal::open(&path).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 12);
+    }
+}
@@ -0,0 +1,45 @@
+use oxidoc_core::database::SharedDatabase;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Starts a thread that checkpoints the database every `interval` in which it
+/// was written to, keeping the log replayed at startup short. Connections are
+/// served while a checkpoint is written.
+pub fn spawn_checkpointer(db: SharedDatabase, interval: Duration) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("oxidoc-checkpointer".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if db.lock().writes_since_checkpoint() == 0 {
+                continue;
+            }
+            match db.checkpoint() {
+                Ok(checkpoint) => println!("Checkpointer: {}", checkpoint),
+                Err(e) => eprintln!("Checkpointer error: {}", e),
+            }
+        })
+        .expect("Failed to spawn checkpointer thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxidoc_core::database::Database;
+
+    #[test]
+    fn checkpointer_runs_after_writes() {
+        let dir = std::env::temp_dir().join(format!("oxidoc-checkpointer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let db = SharedDatabase::new(Database::open(&dir).unwrap());
+        db.lock().put("c".to_string(), "k".to_string(), serde_json::json!({})).unwrap();
+
+        spawn_checkpointer(db.clone(), Duration::from_millis(10));
+        let checkpointed = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            db.lock().writes_since_checkpoint() == 0
+        });
+        assert!(checkpointed);
+        assert!(db.lock().get("c", "k").unwrap().is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,46 @@
+pub use oxidoc_cli::network::network::DEFAULT_MAX_MESSAGE_SIZE;
+use oxidoc_core::storage::engine::EngineConfig;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 7878;
+pub const DEFAULT_DATA_DIR: &str = "data";
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+pub const DEFAULT_REAP_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_CHECKPOINT_INTERVAL_SECS: u64 = 300;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    /// The only directory the `backup` command writes under.
+    pub backup_dir: PathBuf,
+    /// Connections served at the same time; further clients are told the server is busy.
+    pub max_connections: usize,
+    pub engine: EngineConfig,
+    /// How often expired documents are deleted; zero disables the reaper.
+    pub reap_interval: Duration,
+    /// How often the database is checkpointed if it was written to; zero disables the checkpointer.
+    pub checkpoint_interval: Duration,
+    /// Largest request accepted, in bytes; a client sending a larger one is told so and disconnected.
+    pub max_message_size: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            address: DEFAULT_ADDRESS.to_string(),
+            port: DEFAULT_PORT,
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            backup_dir: PathBuf::from(DEFAULT_BACKUP_DIR),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            engine: EngineConfig::default(),
+            reap_interval: Duration::from_secs(DEFAULT_REAP_INTERVAL_SECS),
+            checkpoint_interval: Duration::from_secs(DEFAULT_CHECKPOINT_INTERVAL_SECS),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
@@ -1,4 +1,8 @@
+pub mod checkpointer;
+pub mod config;
+pub mod reaper;
 pub mod network {
     pub mod listener;
     pub mod handler;
+    pub mod pool;
 }
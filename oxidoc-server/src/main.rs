@@ -1,8 +1,112 @@
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, ValueEnum};
+use oxidoc_core::backup::restore;
+use oxidoc_core::database::{Database, SharedDatabase};
+use oxidoc_core::storage::compaction::CompactionStrategy;
+use oxidoc_core::storage::engine::EngineConfig;
+use oxidoc_core::storage::lsm::LsmOptions;
+use oxidoc_server::config::{
+    ServerConfig, DEFAULT_ADDRESS, DEFAULT_BACKUP_DIR, DEFAULT_CHECKPOINT_INTERVAL_SECS, DEFAULT_DATA_DIR,
+    DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_PORT, DEFAULT_REAP_INTERVAL_SECS,
+};
 use oxidoc_server::network::listener::start_listener;
+use std::path::PathBuf;
+use std::time::Duration;
 
-const PORT: u16 = 7878;
-const ADDRESS: &str = "127.0.0.1";
+#[derive(Parser, Debug)]
+struct ApplicationArgs {
+    #[arg(short, long, default_value = DEFAULT_ADDRESS)]
+    address: String,
+
+    #[arg(short, long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+
+    #[arg(short, long, default_value = DEFAULT_DATA_DIR)]
+    data_dir: PathBuf,
+
+    /// Directory the `backup` command writes under; clients name a directory inside it.
+    #[arg(long, default_value = DEFAULT_BACKUP_DIR)]
+    backup_dir: PathBuf,
+
+    #[arg(short, long, default_value_t = DEFAULT_MAX_CONNECTIONS, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    max_connections: usize,
+
+    #[arg(short, long, value_enum, default_value_t = Engine::Lsm)]
+    engine: Engine,
+
+    #[arg(short, long, value_enum, default_value_t = Compaction::SizeTiered)]
+    compaction: Compaction,
+
+    /// Bloom filter false-positive rate for new SSTables (0 disables filters).
+    #[arg(short, long, default_value_t = 0.01)]
+    bloom_fp_rate: f64,
+
+    /// Seconds between deletions of expired documents (0 disables them).
+    #[arg(long, default_value_t = DEFAULT_REAP_INTERVAL_SECS)]
+    reap_interval: u64,
+
+    /// Seconds between checkpoints, which bound the log replayed at startup (0 disables them).
+    #[arg(long, default_value_t = DEFAULT_CHECKPOINT_INTERVAL_SECS)]
+    checkpoint_interval: u64,
+
+    /// Largest request accepted, in bytes.
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: u64,
+
+    /// Verify the backup in this directory and restore it into the (empty) data directory before starting.
+    #[arg(long)]
+    restore: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Engine {
+    Memory,
+    Lsm,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Compaction {
+    SizeTiered,
+    Leveled,
+}
 
 fn main() -> std::io::Result<()> {
-    start_listener(ADDRESS, PORT)
+    let args = ApplicationArgs::parse();
+    let config = ServerConfig {
+        address: args.address,
+        port: args.port,
+        data_dir: args.data_dir,
+        backup_dir: args.backup_dir,
+        max_connections: args.max_connections,
+        engine: match args.engine {
+            Engine::Memory => EngineConfig::InMemory,
+            Engine::Lsm => EngineConfig::Lsm(LsmOptions {
+                compaction: match args.compaction {
+                    Compaction::SizeTiered => CompactionStrategy::default(),
+                    Compaction::Leveled => CompactionStrategy::Leveled {
+                        l0_tables: 4,
+                        level_bytes: 16 * 1024 * 1024,
+                        multiplier: 10,
+                    },
+                },
+                bloom_false_positive_rate: (args.bloom_fp_rate > 0.0).then_some(args.bloom_fp_rate),
+                ..LsmOptions::default()
+            }),
+        },
+        reap_interval: Duration::from_secs(args.reap_interval),
+        checkpoint_interval: Duration::from_secs(args.checkpoint_interval),
+        max_message_size: args.max_message_size,
+    };
+    if let Some(backup) = &args.restore {
+        let manifest = restore(backup, &config.data_dir)?;
+        println!(
+            "Restored {} at version {} into {}.",
+            backup.display(),
+            manifest.sequence,
+            config.data_dir.display()
+        );
+    }
+    let mut db = Database::open_with(&config.data_dir, config.engine.clone())?;
+    db.set_backup_dir(config.backup_dir.clone());
+    start_listener(&config, SharedDatabase::new(db))
 }
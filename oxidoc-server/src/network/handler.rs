@@ -1,46 +1,131 @@
 use oxidoc_cli::commands::commands::CliCommand;
-use oxidoc_cli::network::network::{read_message, write_message};
-use oxidoc_core::executor::execute_command;
-use std::net::TcpStream;
-use oxidoc_core::response::Response::Failure;
+use oxidoc_cli::network::protocol::{
+    read_envelope_with_limit, supports, write_envelope, Envelope, MessageType, Welcome, PROTOCOL_VERSION,
+};
+use oxidoc_core::database::SharedDatabase;
+use oxidoc_core::executor::{execute_shared, Session};
+use std::io::{self, Error, ErrorKind, Read};
+use std::net::{Shutdown, TcpStream};
+use std::time::Duration;
+use oxidoc_core::error::ErrorCode;
+use oxidoc_core::response::Response;
+
+/// Most bytes discarded after rejecting a message that was too large.
+const DRAIN_LIMIT: u64 = 1024 * 1024;
 
 pub enum HandlerType {
     Server,
     Client,
 }
 
-pub fn handle_stream(stream: TcpStream) -> () {
-    println!("New connection: {}", stream.peer_addr().expect("Unable to get peer address"));
-    let message: &[u8] = b"Welcome to oxidoc!\n";
-    write_message(&stream, message).expect("Failed to send welcome message");
-    let mut db = oxidoc_core::database::Database::initialize();
+/// Serves one connection, reading requests of at most `max_message_size` bytes.
+pub fn handle_stream(stream: TcpStream, db: SharedDatabase, max_message_size: u64) {
+    match stream.peer_addr() {
+        Ok(peer) => println!("New connection: {}", peer),
+        Err(e) => println!("New connection from an unknown peer: {}", e),
+    }
+    let welcome = Welcome::new("Welcome to oxidoc!");
+    if let Err(e) = write_envelope(&stream, &Envelope::new(MessageType::Welcome, PROTOCOL_VERSION, 0, welcome.encode())) {
+        eprintln!("Failed to send welcome message: {}", e);
+        return;
+    }
+    let Some(version) = accept_hello(&stream, max_message_size) else {
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    };
+    // Dropped with the connection, aborting any transaction left open.
+    let mut session = Session::new();
     loop {
-        let buffer = match read_message(&stream) {
+        let envelope = match read_envelope_with_limit(&stream, max_message_size) {
+            Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+                reject_too_large(&stream, version, &e);
+                break;
+            }
+            // The frame was read whole, so the next one can still be: answer and carry on.
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                let malformed = Response::failure(ErrorCode::UnsupportedProtocol, format!("Malformed message: {}", e));
+                send_response(&stream, version, 0, &malformed);
+                continue;
+            }
             Err(e) => {
                 eprintln!("Error reading from stream: {}", e);
                 break;
             }
-            Ok(buffer) => buffer,
+            Ok(envelope) => envelope,
         };
-        match (String::from_utf8_lossy(&buffer.to_vec())).as_ref() {
-            "exit" | "quit" => {
+        let response = match envelope.kind {
+            MessageType::Goodbye => {
                 println!("Client requested to close the connection.");
                 break;
             }
-            _ => {
-                let command = CliCommand::from_bytes(&buffer);
-                println!("Received data: {:?}", command);
-                let response = execute_command(command, &mut db)
-                    .unwrap_or_else(|e| Failure(format!("Error processing command: {}", e)));
-                
-                send_response(&stream, &response);
-            }
+            MessageType::Request if envelope.version == version => match CliCommand::from_bytes(&envelope.payload) {
+                Ok(command) => {
+                    // Never the arguments: they carry the documents themselves.
+                    println!("Request {}: {}", envelope.request_id, command.name());
+                    execute_shared(command, &db, &mut session).unwrap_or_else(|e| Response::from_error(&e))
+                }
+                Err(e) => Response::failure(ErrorCode::ParseError, format!("Invalid command: {}", e)),
+            },
+            MessageType::Request => Response::failure(
+                ErrorCode::UnsupportedProtocol,
+                format!("Request uses protocol version {}, the connection agreed on {}", envelope.version, version),
+            ),
+            kind => Response::failure(ErrorCode::UnsupportedProtocol, format!("Unexpected {:?} message", kind)),
+        };
+        send_response(&stream, version, envelope.request_id, &response);
+    }
+}
+
+/// Reads the client's hello and acknowledges the version it chose, which every
+/// later message uses; `None` when the client chose none this server speaks or
+/// sent something else.
+fn accept_hello(stream: &TcpStream, max_message_size: u64) -> Option<u16> {
+    let (request_id, reason) = match read_envelope_with_limit(stream, max_message_size) {
+        Ok(hello) if hello.kind == MessageType::Hello && supports(hello.version) => {
+            send_response(stream, hello.version, hello.request_id, &Response::Ack);
+            return Some(hello.version);
+        }
+        Ok(hello) => (hello.request_id, format!("Expected a hello with a protocol version up to {}", PROTOCOL_VERSION)),
+        Err(e) if e.kind() == ErrorKind::FileTooLarge => {
+            reject_too_large(stream, PROTOCOL_VERSION, &e);
+            return None;
         }
+        Err(e) if e.kind() == ErrorKind::InvalidData => (0, format!("Malformed hello: {}", e)),
+        Err(e) => {
+            eprintln!("Error reading hello: {}", e);
+            return None;
+        }
+    };
+    send_response(stream, PROTOCOL_VERSION, request_id, &Response::failure(ErrorCode::UnsupportedProtocol, reason));
+    None
+}
+
+/// Tells the client its message was too large, then closes the connection: the
+/// rest of the message is still unread, so no later one could be found. Whatever
+/// the client keeps sending is discarded for a moment, as closing with unread data
+/// would reset the connection and could lose the reply.
+fn reject_too_large(stream: &TcpStream, version: u16, err: &Error) {
+    eprintln!("Closing connection: {}", err);
+    send_response(stream, version, 0, &Response::failure(ErrorCode::MessageTooLarge, err.to_string()));
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(500)));
+    let _ = io::copy(&mut stream.take(DRAIN_LIMIT), &mut io::sink());
+}
+
+/// Turns away a client when every connection slot is taken, with a response in
+/// place of the welcome.
+pub fn reject_busy(stream: TcpStream) {
+    if let Ok(peer) = stream.peer_addr() {
+        println!("Rejecting connection from {}: server busy", peer);
     }
+    let busy = Response::failure(ErrorCode::ServerBusy, "Server busy, please try again later.");
+    send_response(&stream, PROTOCOL_VERSION, 0, &busy);
+    let _ = stream.shutdown(Shutdown::Both);
 }
 
-fn send_response(stream: &TcpStream, response: &oxidoc_core::response::Response) {
-    if let Err(e) = write_message(stream, response.as_bytes().as_ref()) {
+fn send_response(stream: &TcpStream, version: u16, request_id: u64, response: &Response) {
+    let envelope = Envelope::new(MessageType::Response, version, request_id, response.as_bytes());
+    if let Err(e) = write_envelope(stream, &envelope) {
         eprintln!("Failed to send response: {}", e);
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,39 @@
-use crate::network::handler::handle_stream;
-use std::io::{Error, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use crate::checkpointer::spawn_checkpointer;
+use crate::config::ServerConfig;
+use crate::network::handler::{handle_stream, reject_busy};
+use crate::network::pool::ThreadPool;
+use crate::reaper::spawn_reaper;
+use oxidoc_core::database::SharedDatabase;
+use std::io::Error;
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-pub fn start_listener(address: &str, port: u16) -> Result<(), Error> {
-    let full_address: String = format!("{address}:{port}");
+pub fn start_listener(config: &ServerConfig, db: SharedDatabase) -> Result<(), Error> {
+    let full_address: String = format!("{}:{}", config.address, config.port);
     let listener = TcpListener::bind(full_address)?;
     println!("Server listening on {}", listener.local_addr()?);
+    if !config.reap_interval.is_zero() {
+        spawn_reaper(db.clone(), config.reap_interval);
+    }
+    if !config.checkpoint_interval.is_zero() {
+        spawn_checkpointer(db.clone(), config.checkpoint_interval);
+    }
 
+    let pool = ThreadPool::new(config.max_connections);
+    let active = Arc::new(AtomicUsize::new(0));
     for stream in listener.incoming() {
         match stream {
-            Ok(stream) => {
-                handle_stream(stream)
-            }
+            Ok(stream) => match ConnectionSlot::acquire(&active, config.max_connections) {
+                Some(slot) => {
+                    let (db, max_message_size) = (db.clone(), config.max_message_size);
+                    pool.execute(move || {
+                        let _slot = slot;
+                        handle_stream(stream, db, max_message_size)
+                    });
+                }
+                None => reject_busy(stream),
+            },
             Err(e) => {
                 eprintln!("Connection error: {}", e);
             }
@@ -20,14 +42,57 @@ pub fn start_listener(address: &str, port: u16) -> Result<(), Error> {
     Ok(())
 }
 
+/// One of the `max_connections` places in the pool, released on drop.
+struct ConnectionSlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    fn acquire(active: &Arc<AtomicUsize>, max: usize) -> Option<ConnectionSlot> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max).then_some(n + 1))
+            .ok()
+            .map(|_| ConnectionSlot { active: Arc::clone(active) })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use oxidoc_cli::commands::commands::{CliCommand, CreateIndexCommandArgs, GetCommandArgs, InsertCommandArgs};
+    use oxidoc_cli::network::network::write_message;
+    use oxidoc_cli::network::protocol::{read_envelope, write_envelope, Envelope, MessageType, Welcome, PROTOCOL_VERSION};
+    use oxidoc_client::network::client::Connection;
+    use oxidoc_core::collection::Versioned;
+    use oxidoc_core::database::Database;
+    use oxidoc_core::error::{ErrorCategory, ErrorCode};
+    use oxidoc_core::response::Response;
     use std::net::TcpStream;
     use std::thread;
     use std::time::Duration;
 
+    fn start_test_server(max_connections: usize) -> u16 {
+        start_server_with(ServerConfig { max_connections, ..ServerConfig::default() })
+    }
+
+    fn start_server_with(config: ServerConfig) -> u16 {
+        let config = ServerConfig { port: get_free_port(), ..config };
+        let port = config.port;
+        thread::spawn(move || {
+            start_listener(&config, SharedDatabase::new(Database::initialize())).unwrap();
+        });
+        // Wait a moment for the oxidoc-server to start
+        thread::sleep(Duration::from_millis(100));
+        port
+    }
+
     fn get_free_port() -> u16 {
         // Setting port 0 lets the OS assign a free port
         TcpListener::bind("127.0.0.1:0")
@@ -37,19 +102,219 @@ mod tests {
             .port()
     }
 
+    fn connect(port: u16) -> Connection {
+        Connection::open(TcpStream::connect(("127.0.0.1", port)).unwrap()).unwrap()
+    }
+
     #[test]
     fn listener_accepts_connection_and_sends_welcome() {
-        let port = get_free_port();
-        let handle = thread::spawn(move || {
-            start_listener("127.0.0.1", port).unwrap();
+        let port = start_test_server(4);
+        let connection = connect(port);
+        assert!(connection.greeting().contains("Welcome to oxidoc!"));
+        assert_eq!(connection.version(), PROTOCOL_VERSION);
+        connection.close().unwrap();
+    }
+
+    #[test]
+    fn hello_with_an_unsupported_version_is_refused() {
+        let port = start_test_server(4);
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let welcome = read_envelope(&stream).unwrap();
+        assert_eq!(welcome.kind, MessageType::Welcome);
+        assert_eq!(Welcome::decode(&welcome.payload).unwrap(), Welcome::new("Welcome to oxidoc!"));
+
+        write_envelope(&stream, &Envelope::new(MessageType::Hello, PROTOCOL_VERSION + 1, 1, Vec::new())).unwrap();
+        let reply = read_envelope(&stream).unwrap();
+        assert_eq!((reply.kind, reply.request_id), (MessageType::Response, 1));
+        assert!(matches!(Response::from_bytes(&reply.payload).unwrap(), Response::Failure(f) if f.code == ErrorCode::UnsupportedProtocol));
+        assert!(read_envelope(&stream).is_err());
+    }
+
+    #[test]
+    fn responses_echo_the_request_id() {
+        let port = start_test_server(4);
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        read_envelope(&stream).unwrap();
+        write_envelope(&stream, &Envelope::new(MessageType::Hello, PROTOCOL_VERSION, 7, Vec::new())).unwrap();
+        assert_eq!(read_envelope(&stream).unwrap().request_id, 7);
+
+        let begin = Envelope::new(MessageType::Request, PROTOCOL_VERSION, 1234, CliCommand::Begin.as_bytes());
+        write_envelope(&stream, &begin).unwrap();
+        let reply = read_envelope(&stream).unwrap();
+        assert_eq!((reply.kind, reply.version, reply.request_id), (MessageType::Response, PROTOCOL_VERSION, 1234));
+        assert!(matches!(Response::from_bytes(&reply.payload).unwrap(), Response::Success(_)));
+    }
+
+    #[test]
+    fn malformed_messages_are_answered_and_the_connection_kept() {
+        let port = start_test_server(4);
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        read_envelope(&stream).unwrap();
+        write_envelope(&stream, &Envelope::new(MessageType::Hello, PROTOCOL_VERSION, 1, Vec::new())).unwrap();
+        read_envelope(&stream).unwrap();
+        let failure = |reply: Envelope| match Response::from_bytes(&reply.payload).unwrap() {
+            Response::Failure(failure) => (reply.request_id, failure.code),
+            response => panic!("expected a failure, got {}", response),
+        };
+
+        write_message(&stream, b"hi").unwrap();
+        assert_eq!(failure(read_envelope(&stream).unwrap()), (0, ErrorCode::UnsupportedProtocol));
+        write_envelope(&stream, &Envelope::new(MessageType::Request, PROTOCOL_VERSION, 2, b"get c k\n".to_vec())).unwrap();
+        assert_eq!(failure(read_envelope(&stream).unwrap()), (2, ErrorCode::ParseError));
+
+        let begin = Envelope::new(MessageType::Request, PROTOCOL_VERSION, 3, CliCommand::Begin.as_bytes());
+        write_envelope(&stream, &begin).unwrap();
+        assert!(matches!(Response::from_bytes(&read_envelope(&stream).unwrap().payload).unwrap(), Response::Success(_)));
+    }
+
+    #[test]
+    fn too_large_requests_are_refused_before_disconnecting() {
+        let port = start_server_with(ServerConfig { max_message_size: 256, ..ServerConfig::default() });
+        let mut connection = connect(port);
+        let get = |key: String| CliCommand::Get(GetCommandArgs { collection: "c".to_string(), key });
+        assert_eq!(send(&mut connection, get("small".to_string())), Response::Doc(None));
+
+        let Err(error) = connection.call(&get("k".repeat(1000))) else { panic!("expected an error") };
+        let failure = error.failure().expect("a server failure");
+        assert_eq!((failure.code, failure.category), (ErrorCode::MessageTooLarge, ErrorCategory::InvalidArgument));
+        assert!(failure.message.contains("exceeds the maximum of 256 bytes"), "{}", failure.message);
+        assert!(connection.request(&get("small".to_string())).is_err());
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order_of_their_ids() {
+        let port = start_test_server(4);
+        let mut connection = connect(port);
+        let mut commands = Vec::new();
+        for i in 0..100 {
+            commands.push(CliCommand::Insert(InsertCommandArgs {
+                collection: "pipelined".to_string(),
+                key: format!("k{}", i),
+                value: format!("{{\"n\": {}}}", i),
+                if_version: None,
+                ttl: None,
+            }));
+            commands.push(CliCommand::Get(GetCommandArgs { collection: "pipelined".to_string(), key: format!("k{}", i) }));
+        }
+        let responses = connection.pipeline(&commands).unwrap();
+        for (i, pair) in responses.chunks(2).enumerate() {
+            assert!(matches!(pair[0], Response::Success(_)));
+            let expected = Versioned::new(i as u64 + 1, serde_json::json!({"n": i}));
+            assert_eq!(pair[1], Response::Doc(Some(expected)));
+        }
+    }
+
+    fn send(connection: &mut Connection, command: CliCommand) -> Response {
+        connection.request(&command).unwrap()
+    }
+
+    #[test]
+    fn connections_share_the_same_database() {
+        let port = start_test_server(4);
+
+        let mut first = connect(port);
+        let insert = CliCommand::Insert(InsertCommandArgs {
+            collection: "test_coll".to_string(),
+            key: "key1".to_string(),
+            value: r#"{"field":"value1"}"#.to_string(),
+            if_version: None,
+            ttl: None,
         });
-        // Wait a moment for the oxidoc-server to start
-        thread::sleep(Duration::from_millis(100));
-        let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Thread connection failed");
-        let mut buf = [0u8; 64];
-        let n = stream.read(&mut buf).unwrap();
-        let msg = std::str::from_utf8(&buf[..n]).unwrap();
-        assert!(msg.contains("Welcome to oxidoc!"));
-        drop(stream);
+        assert!(matches!(send(&mut first, insert), Response::Success(_)));
+        drop(first);
+
+        let mut second = connect(port);
+        let get = CliCommand::Get(GetCommandArgs {
+            collection: "test_coll".to_string(),
+            key: "key1".to_string(),
+        });
+        assert_eq!(
+            send(&mut second, get),
+            Response::Doc(Some(Versioned::new(1, serde_json::json!({"field": "value1"}))))
+        );
+    }
+
+    #[test]
+    fn unique_violation_is_reported_with_its_code() {
+        let port = start_test_server(4);
+        let mut stream = connect(port);
+        let create_index = CliCommand::CreateIndex(CreateIndexCommandArgs {
+            collection: "users".to_string(),
+            fields: vec!["email".to_string()],
+            name: None,
+            unique: true,
+            ttl: None,
+        });
+        assert!(matches!(send(&mut stream, create_index), Response::Success(_)));
+        let insert = |key: &str| CliCommand::Insert(InsertCommandArgs {
+            collection: "users".to_string(),
+            key: key.to_string(),
+            value: r#"{"email":"a@x"}"#.to_string(),
+            if_version: None,
+            ttl: None,
+        });
+        assert!(matches!(send(&mut stream, insert("u1")), Response::Success(_)));
+        let Response::Failure(failure) = send(&mut stream, insert("u2")) else { panic!("expected a failure") };
+        assert_eq!((failure.code, failure.category), (ErrorCode::UniqueViolation, ErrorCategory::ConstraintViolation));
+        assert_eq!(failure.details, Some(serde_json::json!({"index": "email", "key": "u2", "existing": "u1"})));
+    }
+
+    #[test]
+    fn transactions_are_scoped_to_their_connection() {
+        let port = start_test_server(4);
+        let insert = |key: &str| CliCommand::Insert(InsertCommandArgs {
+            collection: "accounts".to_string(),
+            key: key.to_string(),
+            value: r#"{"balance":1}"#.to_string(),
+            if_version: None,
+            ttl: None,
+        });
+        let get = |key: &str| CliCommand::Get(GetCommandArgs { collection: "accounts".to_string(), key: key.to_string() });
+
+        let (mut writer, mut reader) = (connect(port), connect(port));
+        assert!(matches!(send(&mut writer, CliCommand::Begin), Response::Success(_)));
+        send(&mut writer, insert("a"));
+        assert!(matches!(send(&mut writer, get("a")), Response::Doc(Some(_))));
+        assert_eq!(send(&mut reader, get("a")), Response::Doc(None));
+        assert!(matches!(send(&mut writer, CliCommand::Commit), Response::Success(_)));
+        assert!(matches!(send(&mut reader, get("a")), Response::Doc(Some(_))));
+
+        assert!(matches!(send(&mut writer, CliCommand::Begin), Response::Success(_)));
+        send(&mut writer, insert("b"));
+        drop(writer);
+        assert_eq!(send(&mut reader, get("b")), Response::Doc(None));
+    }
+
+    #[test]
+    fn serves_connections_concurrently() {
+        let port = start_test_server(2);
+        let mut first = connect(port);
+        let mut second = connect(port);
+        let get = CliCommand::Get(GetCommandArgs {
+            collection: "test_coll".to_string(),
+            key: "missing".to_string(),
+        });
+        assert_eq!(send(&mut second, get.clone()), Response::Doc(None));
+        assert_eq!(send(&mut first, get), Response::Doc(None));
+    }
+
+    #[test]
+    fn rejects_connections_over_the_limit_with_busy_message() {
+        let port = start_test_server(1);
+        let first = connect(port);
+
+        let second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let reply = read_envelope(&second).unwrap();
+        assert_eq!(reply.kind, MessageType::Response);
+        let reply = Response::from_bytes(&reply.payload).unwrap();
+        assert!(matches!(reply, Response::Failure(f) if f.code == ErrorCode::ServerBusy && f.message.contains("busy")));
+        assert!(read_envelope(&second).is_err());
+
+        drop(first);
+        let welcomed = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(20));
+            Connection::open(TcpStream::connect(("127.0.0.1", port)).unwrap()).is_ok()
+        });
+        assert!(welcomed);
     }
 }
@@ -0,0 +1,60 @@
+use oxidoc_core::database::SharedDatabase;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Expired documents deleted per logged write; the database is unlocked between batches.
+pub const REAP_BATCH: usize = 1024;
+
+/// Starts a thread that deletes expired documents every `interval`, for as long as
+/// the process runs. Expired documents are hidden from reads in the meantime.
+pub fn spawn_reaper(db: SharedDatabase, interval: Duration) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("oxidoc-reaper".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let mut reaped = 0;
+            loop {
+                let batch = db.lock().reap(REAP_BATCH);
+                match batch {
+                    Ok(deleted) => {
+                        reaped += deleted;
+                        if deleted < REAP_BATCH {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Reaper error: {}", e);
+                        break;
+                    }
+                }
+            }
+            if reaped > 0 {
+                println!("Reaper: deleted {} expired document(s)", reaped);
+            }
+        })
+        .expect("Failed to spawn reaper thread")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oxidoc_core::database::{Database, PutOptions};
+
+    #[test]
+    fn reaper_deletes_expired_documents() {
+        let db = SharedDatabase::new(Database::initialize());
+        let expired = PutOptions { expires_at: Some(0), ..PutOptions::default() };
+        db.lock().put_with("sessions".to_string(), "s1".to_string(), serde_json::json!({}), expired).unwrap();
+        db.lock().put("sessions".to_string(), "s2".to_string(), serde_json::json!({})).unwrap();
+        assert_eq!(db.lock().get("sessions", "s1").unwrap(), None);
+
+        spawn_reaper(db.clone(), Duration::from_millis(10));
+        // The delete the reaper logs is the third write.
+        let reaped = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            db.lock().snapshot().sequence() == 3
+        });
+        assert!(reaped);
+        assert!(db.lock().get("sessions", "s2").unwrap().is_some());
+    }
+}